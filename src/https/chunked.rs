@@ -0,0 +1,309 @@
+use super::parser::{parse_header, take_line};
+use super::response::{HttpResponseError, HttpResult};
+use bytes::{Bytes, BytesMut};
+use log::debug;
+use std::collections::VecDeque;
+use std::str;
+
+type Extension = (String, Option<String>);
+
+// Events produced while decoding a chunked body (RFC 9112 section 7.1).
+#[derive(Debug, PartialEq)]
+pub enum ChunkEvent {
+    Extension { name: String, value: Option<String> },
+    Data(Bytes),
+    Trailer { name: String, value: String },
+    Done,
+    NeedMore,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ChunkState {
+    Size,
+    Data(usize),
+    DataEnd,
+    Trailers,
+    Done,
+}
+
+#[derive(Debug)]
+pub struct ChunkedDecoder {
+    state: ChunkState,
+    extensions: VecDeque<Extension>,
+}
+
+impl Default for ChunkedDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChunkedDecoder {
+    pub fn new() -> Self {
+        Self {
+            state: ChunkState::Size,
+            extensions: VecDeque::new(),
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == ChunkState::Done
+    }
+
+    // Decodes as much as possible from the front of buf, consuming what was used.
+    // Chunks can be split at any point, NeedMore is returned until more bytes arrive.
+    pub fn decode(&mut self, buf: &mut BytesMut) -> HttpResult<ChunkEvent> {
+        loop {
+            // extensions come before the data they belong to
+            if let Some((name, value)) = self.extensions.pop_front() {
+                return Ok(ChunkEvent::Extension { name, value });
+            }
+            match self.state {
+                ChunkState::Size => {
+                    let line = match take_line(buf)? {
+                        Some(l) => l,
+                        None => return Ok(ChunkEvent::NeedMore),
+                    };
+                    let (size, extensions) = parse_chunk_line(&line)?;
+                    debug!("Chunk length: {}", size);
+
+                    self.extensions = extensions.into();
+                    self.state = match size {
+                        0 => ChunkState::Trailers,
+                        n => ChunkState::Data(n),
+                    };
+                }
+                ChunkState::Data(n) => {
+                    if buf.is_empty() {
+                        return Ok(ChunkEvent::NeedMore);
+                    }
+                    let take = n.min(buf.len());
+                    self.state = match n - take {
+                        0 => ChunkState::DataEnd,
+                        left => ChunkState::Data(left),
+                    };
+                    return Ok(ChunkEvent::Data(buf.split_to(take).freeze()));
+                }
+                ChunkState::DataEnd => match take_line(buf)? {
+                    Some(l) if l.is_empty() => self.state = ChunkState::Size,
+                    Some(_) => return Err(HttpResponseError::InvalidChunk),
+                    None => return Ok(ChunkEvent::NeedMore),
+                },
+                ChunkState::Trailers => match take_line(buf)? {
+                    Some(l) if l.is_empty() => self.state = ChunkState::Done,
+                    Some(l) => {
+                        let (name, value) = parse_header(&l)?;
+                        return Ok(ChunkEvent::Trailer { name, value });
+                    }
+                    None => return Ok(ChunkEvent::NeedMore),
+                },
+                ChunkState::Done => return Ok(ChunkEvent::Done),
+            }
+        }
+    }
+}
+
+// chunk-size [ chunk-ext ]
+fn parse_chunk_line(line: &[u8]) -> HttpResult<(usize, Vec<Extension>)> {
+    let line = match str::from_utf8(line) {
+        Ok(o) => o,
+        Err(e) => return Err(HttpResponseError::ParseStrError(e)),
+    };
+    let digits = line.bytes().take_while(|b| b.is_ascii_hexdigit()).count();
+    if digits == 0 {
+        return Err(HttpResponseError::InvalidChunkSize);
+    }
+
+    let mut size: usize = 0;
+    for b in line[..digits].bytes() {
+        let d = (b as char).to_digit(16).unwrap_or(0) as usize;
+        size = match size.checked_mul(16).and_then(|s| s.checked_add(d)) {
+            Some(s) => s,
+            None => return Err(HttpResponseError::InvalidChunkSize),
+        };
+    }
+
+    // anything after the size has to be whitespace or extensions
+    let rest = line[digits..].trim_start_matches([' ', '\t']);
+    if rest.is_empty() {
+        return Ok((size, vec![]));
+    }
+    if !rest.starts_with(';') {
+        return Err(HttpResponseError::InvalidChunkSize);
+    }
+
+    Ok((size, parse_extensions(rest)?))
+}
+
+// *( BWS ";" BWS ext-name [ BWS "=" BWS ext-val ] )
+fn parse_extensions(mut s: &str) -> HttpResult<Vec<Extension>> {
+    let mut exts = vec![];
+
+    loop {
+        s = s.trim_start_matches([' ', '\t']);
+        if s.is_empty() {
+            return Ok(exts);
+        }
+        s = match s.strip_prefix(';') {
+            Some(r) => r.trim_start_matches([' ', '\t']),
+            None => return Err(HttpResponseError::InvalidChunk),
+        };
+
+        let name_len = s.bytes().take_while(|b| is_tchar(*b)).count();
+        if name_len == 0 {
+            return Err(HttpResponseError::InvalidChunk);
+        }
+        let name = s[..name_len].to_string();
+        s = s[name_len..].trim_start_matches([' ', '\t']);
+
+        let value = match s.strip_prefix('=') {
+            None => None,
+            Some(r) => {
+                let r = r.trim_start_matches([' ', '\t']);
+                let (value, rest) = if let Some(quoted) = r.strip_prefix('"') {
+                    parse_quoted(quoted)?
+                } else {
+                    let len = r.bytes().take_while(|b| is_tchar(*b)).count();
+                    if len == 0 {
+                        return Err(HttpResponseError::InvalidChunk);
+                    }
+                    (r[..len].to_string(), &r[len..])
+                };
+                s = rest;
+                Some(value)
+            }
+        };
+        exts.push((name, value));
+    }
+}
+
+// Parses the inside of a quoted-string, returns the unescaped value and what follows it.
+fn parse_quoted(s: &str) -> HttpResult<(String, &str)> {
+    let mut value = String::new();
+    let mut chars = s.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((value, &s[i + 1..])),
+            '\\' => match chars.next() {
+                Some((_, e)) => value.push(e),
+                None => break,
+            },
+            c => value.push(c),
+        }
+    }
+    Err(HttpResponseError::InvalidChunk)
+}
+
+fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(decoder: &mut ChunkedDecoder, buf: &mut BytesMut) -> Vec<ChunkEvent> {
+        let mut events = Vec::new();
+        loop {
+            match decoder.decode(buf).unwrap() {
+                e @ (ChunkEvent::Done | ChunkEvent::NeedMore) => {
+                    events.push(e);
+                    return events;
+                }
+                e => events.push(e),
+            }
+        }
+    }
+
+    fn body(events: &[ChunkEvent]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for e in events {
+            if let ChunkEvent::Data(b) = e {
+                out.extend_from_slice(b);
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn chunks_and_trailers() {
+        let mut buf = BytesMut::from(
+            &b"4\r\nWiki\r\n5\r\npedia\r\nE\r\n in\r\n\r\nchunks.\r\n0\r\nExpires: never\r\nX-Sum: 1\r\n\r\n"[..],
+        );
+        let mut d = ChunkedDecoder::new();
+        let events = decode_all(&mut d, &mut buf);
+        assert_eq!(body(&events), b"Wikipedia in\r\n\r\nchunks.");
+        let trailers: Vec<_> = events
+            .iter()
+            .filter_map(|e| match e {
+                ChunkEvent::Trailer { name, value } => Some((name.as_str(), value.as_str())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(trailers, [("Expires", "never"), ("X-Sum", "1")]);
+        assert_eq!(events.last(), Some(&ChunkEvent::Done));
+        assert!(d.is_done());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn split_at_every_byte() {
+        let raw = b"3;name=value;flag\r\nabc\r\n10\r\n0123456789abcdef\r\n0\r\nT: v\r\n\r\n";
+        let mut d = ChunkedDecoder::new();
+        let mut buf = BytesMut::new();
+        let mut events = Vec::new();
+        for b in raw {
+            buf.extend_from_slice(&[*b]);
+            events.extend(decode_all(&mut d, &mut buf));
+        }
+        assert_eq!(body(&events), b"abc0123456789abcdef");
+        assert!(events.contains(&ChunkEvent::Extension {
+            name: "name".to_string(),
+            value: Some("value".to_string()),
+        }));
+        assert!(events.contains(&ChunkEvent::Extension {
+            name: "flag".to_string(),
+            value: None,
+        }));
+        assert!(events.contains(&ChunkEvent::Trailer {
+            name: "T".to_string(),
+            value: "v".to_string(),
+        }));
+        assert!(d.is_done());
+    }
+
+    #[test]
+    fn bytes_after_the_body_are_left() {
+        let mut buf = BytesMut::from(&b"1\r\na\r\n0\r\n\r\nHTTP/1.1 200 OK\r\n"[..]);
+        let mut d = ChunkedDecoder::new();
+        decode_all(&mut d, &mut buf);
+        assert_eq!(&buf[..], b"HTTP/1.1 200 OK\r\n");
+    }
+
+    #[test]
+    fn invalid_chunks() {
+        let mut d = ChunkedDecoder::new();
+        let mut buf = BytesMut::from(&b"zz\r\n"[..]);
+        assert!(matches!(
+            d.decode(&mut buf),
+            Err(HttpResponseError::InvalidChunkSize)
+        ));
+
+        let mut d = ChunkedDecoder::new();
+        let mut buf = BytesMut::from(&b"ffffffffffffffffffff\r\n"[..]);
+        assert!(matches!(
+            d.decode(&mut buf),
+            Err(HttpResponseError::InvalidChunkSize)
+        ));
+
+        // the data has to be followed by CRLF
+        let mut d = ChunkedDecoder::new();
+        let mut buf = BytesMut::from(&b"2\r\nabcd\r\n"[..]);
+        assert!(matches!(d.decode(&mut buf), Ok(ChunkEvent::Data(_))));
+        assert!(matches!(
+            d.decode(&mut buf),
+            Err(HttpResponseError::InvalidChunk)
+        ));
+    }
+}
@@ -1,12 +1,29 @@
+use super::connect_to::ConnectTo;
+use super::cookie::CookieJar;
+use super::decompress;
+use super::pool::{ConnectionPool, PoolKey};
+use super::redirect;
+use super::redirect::RedirectPolicy;
 use super::request::RequestBuilder;
-use crate::tls::tls_stream::TlsStream;
-use crate::Url;
+use super::resolve::{Resolver, SystemResolver};
+use super::response::{HttpResponseError, Response};
+use super::timeout::Timeouts;
+use super::transport::Transport;
+use super::url::Url;
+use super::websocket::{WebSocket, WsResult};
+use crate::tls::config::TlsConfig;
+use crate::tls::tls_stream::shared_default_config;
+use log::debug;
+use rustls::ClientConfig;
 use std::collections::HashMap;
 use std::io;
-use std::io::{Read, Write};
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Instant;
 
 #[allow(clippy::upper_case_acronyms)]
 #[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Methods {
     GET,
     POST,
@@ -18,66 +35,311 @@ pub enum Methods {
     OPTIONS,
 }
 
+impl Methods {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Methods::GET => "GET",
+            Methods::POST => "POST",
+            Methods::PUT => "PUT",
+            Methods::PATCH => "PATCH",
+            Methods::DELETE => "DELETE",
+            Methods::HEAD => "HEAD",
+            Methods::CONNECT => "CONNECT",
+            Methods::OPTIONS => "OPTIONS",
+        }
+    }
+
+    // Read-only methods, RFC 9110 9.2.1. RFC 8470 only lets these go out as early data.
+    pub fn is_safe(&self) -> bool {
+        matches!(self, Methods::GET | Methods::HEAD | Methods::OPTIONS)
+    }
+
+    // Sending these twice has the same effect as once, RFC 9110 9.2.2.
+    pub fn is_idempotent(&self) -> bool {
+        self.is_safe() || matches!(self, Methods::PUT | Methods::DELETE)
+    }
+}
+
 type HeaderMap<'a> = HashMap<&'a str, &'a str>;
 
 pub struct HttpsClient<'b> {
     headers: HashMap<&'b str, &'b str>,
+    raw_content: bool,
+    max_decompressed: usize,
+    redirect_policy: RedirectPolicy,
+    pool: Arc<ConnectionPool>,
+    cookie_jar: Option<Arc<CookieJar>>,
+    timeouts: Timeouts,
+    tls_config: Arc<ClientConfig>,
+    connect_to: ConnectTo,
+    resolver: Arc<dyn Resolver>,
 }
 
 impl<'b> HttpsClient<'b> {
     pub fn new(agent: &'b str, extra_headers: Option<&HeaderMap<'b>>) -> Self {
+        Self::with_pool(agent, extra_headers, Arc::new(ConnectionPool::new()))
+    }
+
+    // Uses the given pool, so connections can be shared with other clients. Only clients with
+    // equal TLS configs and connect-to targets share them.
+    pub fn with_pool(
+        agent: &'b str,
+        extra_headers: Option<&HeaderMap<'b>>,
+        pool: Arc<ConnectionPool>,
+    ) -> Self {
         let mut headers: HeaderMap<'b> = HashMap::new();
         headers.insert("User-Agent", agent);
+        headers.insert("Accept-Encoding", decompress::accept_encoding());
         if let Some(h) = extra_headers {
             headers.extend(h.iter().map(|(k, v)| (*k, *v)));
         }
 
-        Self { headers }
+        Self {
+            headers,
+            raw_content: false,
+            max_decompressed: decompress::DEFAULT_MAX_SIZE,
+            redirect_policy: RedirectPolicy::default(),
+            pool,
+            cookie_jar: None,
+            timeouts: Timeouts::default(),
+            tls_config: shared_default_config(),
+            connect_to: ConnectTo::default(),
+            resolver: Arc::new(SystemResolver),
+        }
+    }
+
+    pub fn pool(&self) -> &Arc<ConnectionPool> {
+        &self.pool
+    }
+
+    // Cookies are only kept when a jar is set, it can be shared with other clients.
+    pub fn cookie_jar(&mut self, jar: Arc<CookieJar>) -> &mut Self {
+        self.cookie_jar = Some(jar);
+        self
+    }
+
+    pub fn cookies(&self) -> Option<&Arc<CookieJar>> {
+        self.cookie_jar.as_ref()
+    }
+
+    pub fn timeouts(&mut self, timeouts: Timeouts) -> &mut Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    // Pooled connections opened with another config aren't reused, clients given equal
+    // configs share them.
+    pub fn tls_config(&mut self, config: &TlsConfig) -> io::Result<&mut Self> {
+        self.tls_config = config.build_shared()?;
+        Ok(self)
+    }
+
+    // Pooled connections to another address or with another server name aren't reused.
+    pub fn connect_to(&mut self, connect_to: ConnectTo) -> &mut Self {
+        self.connect_to = connect_to;
+        self
+    }
+
+    // How host names are turned into addresses, e.g. a CachingResolver to skip lookups
+    // for hosts connected to recently. The system's resolver is used by default.
+    pub fn resolver(&mut self, resolver: Arc<dyn Resolver>) -> &mut Self {
+        self.resolver = resolver;
+        self
+    }
+
+    // Skips decoding Content-Encoding, responses keep the bytes as sent by the server.
+    pub fn raw_content(&mut self, raw: bool) -> &mut Self {
+        self.raw_content = raw;
+        self
+    }
+
+    // Responses that decode to more than max bytes fail with DecompressedTooLarge,
+    // 64 MiB by default.
+    pub fn max_decompressed_size(&mut self, max: usize) -> &mut Self {
+        self.max_decompressed = max;
+        self
+    }
+
+    pub fn redirect_policy(&mut self, policy: RedirectPolicy) -> &mut Self {
+        self.redirect_policy = policy;
+        self
     }
+
     fn request(
         &self,
         method: Methods,
         url: &str,
         content: Option<Vec<u8>>,
         headers: Option<HeaderMap>,
-    ) -> io::Result<Vec<u8>> {
-        println!("{}", url);
-        let url_parts = Url::new(url).unwrap();
+    ) -> io::Result<Response> {
+        let mut all = self.headers.clone();
+        if let Some(h) = headers {
+            all.extend(h);
+        }
+
+        let deadline = self.timeouts.deadline();
+        let first = self.send(method, url, content.clone(), &all, deadline, false)?;
+        let mut res = redirect::follow(
+            self.redirect_policy,
+            method,
+            url,
+            content,
+            all,
+            first,
+            |m, u, c, h, x| self.send(m, u, c, h, deadline, x),
+        )?;
+
+        if !self.raw_content {
+            res.decompress_limited(self.max_decompressed)?;
+        }
+        Ok(res)
+    }
+
+    // Sends a single request over a pooled connection, headers are used as given.
+    // cross_site leaves out SameSite cookies, see CookieJar::header_for_request().
+    pub(crate) fn send(
+        &self,
+        method: Methods,
+        url: &str,
+        content: Option<Vec<u8>>,
+        headers: &HeaderMap,
+        deadline: Option<Instant>,
+        cross_site: bool,
+    ) -> io::Result<Response> {
+        debug!("Requesting {}", url);
+        let url_parts = Url::new(url)?;
+        let host = url_parts.host_header();
 
         let mut req = RequestBuilder::new();
+        let head = matches!(method, Methods::HEAD);
+        let idempotent = method.is_idempotent();
 
         req.http_method(method)
-            .headers(&self.headers)
+            .headers(headers)
             .route(url_parts.route())
-            .host(url_parts.domain());
+            .host(&host);
 
         if let Some(c) = content {
             req.content(c);
         }
-        if let Some(h) = headers {
-            req.headers(&h);
+        if let Some(ref jar) = self.cookie_jar {
+            req.cookies(jar, &url_parts, cross_site);
         }
 
         let bytes = req.build();
 
-        println!("{:?}", std::str::from_utf8(&bytes).unwrap());
-        let mut stream = TlsStream::new(None, url_parts.domain(), &url_parts.socket_addr())?;
-        let mut buf = vec![];
-        let _ = stream.write(&bytes)?;
-        let _ = stream.read_to_end(&mut buf)?;
-        Ok(buf)
+        let key = PoolKey::from_url(&url_parts).with_connection(
+            &self.tls_config,
+            &self.connect_to.addr_for(&url_parts),
+            self.connect_to.server_name_for(&url_parts),
+            self.connect_to.sni_for(&url_parts),
+        );
+        let mut retried = false;
+        let res = loop {
+            let mut conn = self.pool.checkout(&key, deadline, || {
+                let cfg = Some(&self.tls_config);
+                let resolver = self.resolver.as_ref();
+                Transport::connect(
+                    &url_parts,
+                    cfg,
+                    &self.connect_to,
+                    resolver,
+                    &self.timeouts,
+                    deadline,
+                )
+            })?;
+            conn.parser.set_head_request(head);
+            conn.stream().set_read_idle_timeout(self.timeouts.read_idle);
+            conn.stream().set_deadline(deadline);
+
+            // early data can be replayed, so only safe methods on a new connection send it
+            let early = method.is_safe() && !conn.is_reused();
+            let res = match write_request(conn.stream(), &bytes, early) {
+                Ok(()) => conn.read_response(),
+                Err(e) => Err(HttpResponseError::Io(e)),
+            };
+            match res {
+                Ok(mut r) => {
+                    r.tls = conn.stream().tls_info();
+                    let keep = conn.parser.is_keep_alive();
+                    conn.keep_alive(keep);
+                    conn.stream().set_deadline(None);
+                    break r;
+                }
+                // the server may have closed a reused connection right before we used it
+                Err(e) if conn.is_reused() && idempotent && !retried && !is_timeout(&e) => {
+                    debug!("Reused connection failed ({}), retrying", e);
+                    retried = true;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        if let Some(ref jar) = self.cookie_jar {
+            jar.store(&url_parts, &res);
+        }
+        Ok(res)
+    }
+
+    // Opens a WebSocket with this client's headers, TLS config, connect-to, resolver and timeouts.
+    // Cookies aren't sent, the connection isn't pooled.
+    pub fn websocket(&self, url: &str, extra_headers: Option<HeaderMap>) -> WsResult<WebSocket> {
+        let mut all = self.headers.clone();
+        if let Some(h) = extra_headers {
+            all.extend(h);
+        }
+        let cfg = Some(&self.tls_config);
+        let resolver = self.resolver.as_ref();
+        WebSocket::open(
+            url,
+            Some(&all),
+            cfg,
+            &self.connect_to,
+            resolver,
+            &self.timeouts,
+        )
     }
 
-    pub fn get(&mut self, url: &str, extra_headers: Option<HeaderMap>) -> io::Result<Vec<u8>> {
+    pub fn get(&self, url: &str, extra_headers: Option<HeaderMap>) -> io::Result<Response> {
         self.request(Methods::GET, url, None, extra_headers)
     }
 
     pub fn post(
-        &mut self,
+        &self,
         url: &str,
         content: Option<Vec<u8>>,
         extra_headers: Option<HeaderMap>,
-    ) -> io::Result<Vec<u8>> {
+    ) -> io::Result<Response> {
         self.request(Methods::POST, url, content, extra_headers)
     }
 }
+
+fn write_request(io: &mut Transport, bytes: &[u8], early: bool) -> io::Result<()> {
+    if early && io.write_early_data(bytes)? {
+        return Ok(());
+    }
+    io.write_all(bytes)
+}
+
+fn is_timeout(e: &HttpResponseError) -> bool {
+    matches!(*e, HttpResponseError::Io(ref e) if e.kind() == io::ErrorKind::TimedOut)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn method_classes() {
+        use Methods::*;
+        for m in [GET, HEAD, OPTIONS] {
+            assert!(m.is_safe() && m.is_idempotent(), "{:?}", m);
+        }
+        for m in [PUT, DELETE] {
+            assert!(!m.is_safe() && m.is_idempotent(), "{:?}", m);
+        }
+        for m in [POST, PATCH, CONNECT] {
+            assert!(!m.is_safe() && !m.is_idempotent(), "{:?}", m);
+        }
+    }
+}
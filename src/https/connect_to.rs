@@ -0,0 +1,106 @@
+use super::url::Url;
+use crate::tls::pinning::host_key;
+use std::collections::{HashMap, HashSet};
+
+// Where to connect and what name to send instead of what the url says, like curl's
+// --connect-to and --resolve. The Host header always comes from the url.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectTo {
+    addrs: HashMap<(String, u16), String>,
+    server_names: HashMap<String, String>,
+    no_sni: HashSet<String>,
+}
+
+impl ConnectTo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Requests for host:port connect to addr instead, "ip:port" or "host:port".
+    // SNI and certificate checks still use host.
+    pub fn addr(mut self, host: &str, port: u16, addr: &str) -> Self {
+        self.addrs.insert((host_key(host), port), addr.to_string());
+        self
+    }
+
+    // Sends name in SNI for host and checks the certificate against it, on every port.
+    // Pins are looked up by name too. An IP address sends no SNI at all.
+    pub fn server_name(mut self, host: &str, name: &str) -> Self {
+        self.server_names.insert(host_key(host), name.to_string());
+        self
+    }
+
+    // Sends no server name in the handshake for host, on every port. The certificate is
+    // still checked against the server name. Some servers then answer with their default
+    // certificate, or not at all.
+    pub fn no_sni(mut self, host: &str) -> Self {
+        self.no_sni.insert(host_key(host));
+        self
+    }
+
+    // Address to open the connection to, url.socket_addr() unless mapped.
+    pub(crate) fn addr_for(&self, url: &Url) -> String {
+        if self.addrs.is_empty() {
+            return url.socket_addr();
+        }
+        let mapped = url
+            .port()
+            .and_then(|p| self.addrs.get(&(host_key(url.domain()), p)));
+        match mapped {
+            Some(a) => a.clone(),
+            None => url.socket_addr(),
+        }
+    }
+
+    // Name for SNI and certificate checks, the url's host unless overridden.
+    pub(crate) fn server_name_for<'u>(&'u self, url: &'u Url) -> &'u str {
+        if self.server_names.is_empty() {
+            return url.domain();
+        }
+        match self.server_names.get(&host_key(url.domain())) {
+            Some(n) => n,
+            None => url.domain(),
+        }
+    }
+
+    // Whether the server name goes out in SNI, IP addresses never do.
+    pub(crate) fn sni_for(&self, url: &Url) -> bool {
+        self.no_sni.is_empty() || !self.no_sni.contains(&host_key(url.domain()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses() {
+        let connect_to = ConnectTo::new()
+            .addr("Example.com", 443, "10.0.0.1:8443")
+            .addr("[::1]", 80, "127.0.0.1:8080");
+        let addr = |u: &str| connect_to.addr_for(&Url::new(u).unwrap());
+        assert_eq!(addr("https://example.COM/a"), "10.0.0.1:8443");
+        assert_eq!(addr("http://[::1]/"), "127.0.0.1:8080");
+        // other ports and hosts are left alone
+        assert_eq!(addr("https://example.com:8443/"), "example.com:8443");
+        assert_eq!(addr("https://other.example/"), "other.example:443");
+    }
+
+    #[test]
+    fn server_names() {
+        let connect_to = ConnectTo::new()
+            .server_name("example.com", "front.example")
+            .no_sni("IP.example");
+        let url = Url::new("https://example.com:8443/").unwrap();
+        assert_eq!(connect_to.server_name_for(&url), "front.example");
+        assert!(connect_to.sni_for(&url));
+
+        let url = Url::new("https://ip.example/").unwrap();
+        assert_eq!(connect_to.server_name_for(&url), "ip.example");
+        assert!(!connect_to.sni_for(&url));
+
+        let none = ConnectTo::default();
+        assert_eq!(none.server_name_for(&url), "ip.example");
+        assert!(none.sni_for(&url));
+    }
+}
@@ -0,0 +1,729 @@
+use super::client::Methods;
+use super::response::Response;
+use super::url::Url;
+use log::debug;
+use publicsuffix::{List, Psl};
+use std::cmp::Reverse;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+// Upper bound for Max-Age, RFC 6265bis section 5.6.2.
+const MAX_AGE_LIMIT: u64 = 400 * 24 * 3600;
+
+// Prefix the Netscape cookie file format uses for HttpOnly cookies.
+const HTTP_ONLY_PREFIX: &str = "#HttpOnly_";
+
+// https://publicsuffix.org/list/public_suffix_list.dat
+const PUBLIC_SUFFIX_LIST: &str = include_str!("public_suffix_list.dat");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl SameSite {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "strict" => Some(SameSite::Strict),
+            "lax" => Some(SameSite::Lax),
+            "none" => Some(SameSite::None),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    // lowercase, without a leading dot
+    pub domain: String,
+    // only sent to the exact host that set it, no Domain attribute was given
+    pub host_only: bool,
+    pub path: String,
+    // None for session cookies
+    pub expires: Option<SystemTime>,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    // Parses a Set-Cookie value received for url (RFC 6265 section 5.2 and 5.3).
+    // None if the cookie has to be ignored.
+    pub fn parse(set_cookie: &str, url: &Url) -> Option<Self> {
+        let (pair, attributes) = match set_cookie.split_once(';') {
+            Some((p, a)) => (p, a),
+            None => (set_cookie, ""),
+        };
+        let (name, value) = pair.split_once('=')?;
+        let (name, value) = (name.trim(), value.trim());
+        if name.is_empty() {
+            return None;
+        }
+
+        let host = url.domain().to_ascii_lowercase();
+        let mut domain = None;
+        let mut path = None;
+        let mut expires = None;
+        let mut max_age = None;
+        let mut secure = false;
+        let mut http_only = false;
+        let mut same_site = None;
+
+        // later attributes win over earlier ones with the same name
+        for attr in attributes.split(';') {
+            let (k, v) = match attr.split_once('=') {
+                Some((k, v)) => (k.trim(), v.trim()),
+                None => (attr.trim(), ""),
+            };
+            match k.to_ascii_lowercase().as_str() {
+                "expires" => {
+                    if let Some(t) = parse_date(v) {
+                        expires = Some(t);
+                    }
+                }
+                "max-age" => {
+                    if let Some(t) = parse_max_age(v) {
+                        max_age = Some(t);
+                    }
+                }
+                "domain" if !v.is_empty() => {
+                    domain = Some(v.trim_start_matches('.').to_ascii_lowercase());
+                }
+                "path" => {
+                    path = match v.starts_with('/') {
+                        true => Some(v.to_string()),
+                        false => None,
+                    };
+                }
+                "secure" => secure = true,
+                "httponly" => http_only = true,
+                "samesite" => same_site = SameSite::parse(v),
+                _ => {}
+            }
+        }
+
+        // any accepted Domain attribute makes the cookie go to subdomains as well
+        let host_only = match domain {
+            // a public suffix set by the host itself is kept host-only, set by others it's
+            // refused, RFC 6265 section 5.3 step 5
+            Some(ref d) if is_public_suffix(d) && *d == host => true,
+            Some(ref d) => {
+                if !domain_match(&host, d) || is_public_suffix(d) {
+                    debug!("Ignoring cookie {} for domain {} from {}", name, d, host);
+                    return None;
+                }
+                false
+            }
+            None => true,
+        };
+        // a secure cookie can't be set over plain http, SameSite=None requires Secure
+        if secure && !is_secure(url) {
+            return None;
+        }
+        if same_site == Some(SameSite::None) && !secure {
+            return None;
+        }
+        let path = path.unwrap_or_else(|| default_path(url.path()));
+
+        // cookie prefixes, RFC 6265bis section 4.1.3
+        if name.starts_with("__Secure-") && !secure {
+            return None;
+        }
+        if name.starts_with("__Host-") && (!secure || domain.is_some() || path != "/") {
+            return None;
+        }
+
+        Some(Self {
+            name: name.to_string(),
+            value: value.to_string(),
+            domain: domain.filter(|_| !host_only).unwrap_or(host),
+            host_only,
+            path,
+            // Max-Age has precedence over Expires
+            expires: max_age.or(expires),
+            secure,
+            http_only,
+            same_site,
+        })
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires.is_some_and(|e| e <= now)
+    }
+
+    // Whether the cookie goes along with a request to url.
+    pub fn matches(&self, url: &Url) -> bool {
+        let host = url.domain().to_ascii_lowercase();
+        let domain_ok = match self.host_only {
+            true => host == self.domain,
+            false => domain_match(&host, &self.domain),
+        };
+        domain_ok && path_match(url.path(), &self.path) && (!self.secure || is_secure(url))
+    }
+
+    // Whether the cookie goes along with a cross-site request, RFC 6265bis section 5.8.3.
+    // Lax cookies only go with safe methods, like a link followed to another site.
+    // Cookies without SameSite are sent like None.
+    fn allows_cross_site(&self, method: Methods) -> bool {
+        match self.same_site {
+            Some(SameSite::Strict) => false,
+            Some(SameSite::Lax) => method.is_safe(),
+            Some(SameSite::None) | None => true,
+        }
+    }
+
+    fn same_key(&self, other: &Cookie) -> bool {
+        self.name == other.name && self.domain == other.domain && self.path == other.path
+    }
+
+    // One line of a Netscape style cookie file, with the SameSite value as an extra column.
+    fn to_line(&self) -> String {
+        let expires = self.expires.map_or(0, |e| {
+            e.duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs())
+                .max(1)
+        });
+        let mut line = format!(
+            "{}{}{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            if self.http_only { HTTP_ONLY_PREFIX } else { "" },
+            if self.host_only { "" } else { "." },
+            self.domain,
+            bool_str(!self.host_only),
+            self.path,
+            bool_str(self.secure),
+            expires,
+            self.name,
+            self.value
+        );
+        if let Some(s) = self.same_site {
+            line.push('\t');
+            line.push_str(s.as_str());
+        }
+        line
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        let (http_only, line) = match line.strip_prefix(HTTP_ONLY_PREFIX) {
+            Some(l) => (true, l),
+            None => (false, line),
+        };
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 7 {
+            return None;
+        }
+        let expires = match fields[4].parse::<u64>().ok()? {
+            0 => None,
+            secs => Some(UNIX_EPOCH + Duration::from_secs(secs)),
+        };
+        Some(Self {
+            name: fields[5].to_string(),
+            value: fields[6].to_string(),
+            domain: fields[0].trim_start_matches('.').to_ascii_lowercase(),
+            host_only: !fields[1].eq_ignore_ascii_case("TRUE"),
+            path: fields[2].to_string(),
+            expires,
+            secure: fields[3].eq_ignore_ascii_case("TRUE"),
+            http_only,
+            same_site: fields.get(7).and_then(|s| SameSite::parse(s)),
+        })
+    }
+}
+
+// Cookie storage shared by every request of a client, can be shared between clients too.
+#[derive(Debug, Default)]
+pub struct CookieJar {
+    cookies: Mutex<Vec<Cookie>>,
+}
+
+impl CookieJar {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Reads a jar written by save(), expired cookies are left out.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let now = SystemTime::now();
+        let cookies = fs::read_to_string(path)?
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .filter(|l| l.starts_with(HTTP_ONLY_PREFIX) || !l.starts_with('#'))
+            .filter_map(Cookie::from_line)
+            .filter(|c| !c.is_expired(now))
+            .collect();
+        Ok(Self {
+            cookies: Mutex::new(cookies),
+        })
+    }
+
+    // Writes the cookies in the Netscape cookie file format, session cookies included.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut out = String::from("# Netscape HTTP Cookie File\n");
+        for c in self.lock().iter() {
+            out.push_str(&c.to_line());
+            out.push('\n');
+        }
+        fs::write(path, out)
+    }
+
+    // Stores every Set-Cookie of a response to a request for url.
+    pub fn store(&self, url: &Url, res: &Response) {
+        for set_cookie in res.set_cookies() {
+            self.set_cookie(url, set_cookie);
+        }
+    }
+
+    pub fn set_cookie(&self, url: &Url, set_cookie: &str) {
+        if let Some(c) = Cookie::parse(set_cookie, url) {
+            self.insert(c);
+        }
+    }
+
+    // Replaces a cookie with the same name, domain and path.
+    // An already expired cookie only removes the old one.
+    pub fn insert(&self, cookie: Cookie) {
+        let mut cookies = self.lock();
+        let expired = cookie.is_expired(SystemTime::now());
+        match cookies.iter().position(|c| c.same_key(&cookie)) {
+            Some(i) if expired => {
+                cookies.remove(i);
+            }
+            Some(i) => cookies[i] = cookie,
+            None if expired => {}
+            None => cookies.push(cookie),
+        }
+    }
+
+    // Value of the Cookie header for a same-site request to url, None if no cookie matches.
+    pub fn header_for(&self, url: &Url) -> Option<String> {
+        self.header_for_request(url, Methods::GET, false)
+    }
+
+    // Like header_for(), SameSite cookies are left out of cross-site requests, e.g. after a
+    // redirect to or from another site. See same_site().
+    pub fn header_for_request(
+        &self,
+        url: &Url,
+        method: Methods,
+        cross_site: bool,
+    ) -> Option<String> {
+        let now = SystemTime::now();
+        let mut cookies = self.lock();
+        cookies.retain(|c| !c.is_expired(now));
+
+        // longer paths first, otherwise in the order they were created
+        let mut matching: Vec<&Cookie> = cookies
+            .iter()
+            .filter(|c| c.matches(url) && (!cross_site || c.allows_cross_site(method)))
+            .collect();
+        if matching.is_empty() {
+            return None;
+        }
+        matching.sort_by_key(|c| Reverse(c.path.len()));
+        let pairs: Vec<String> = matching
+            .iter()
+            .map(|c| format!("{}={}", c.name, c.value))
+            .collect();
+        Some(pairs.join("; "))
+    }
+
+    pub fn cookies(&self) -> Vec<Cookie> {
+        self.lock().clone()
+    }
+
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Cookie>> {
+        match self.cookies.lock() {
+            Ok(c) => c,
+            Err(p) => p.into_inner(),
+        }
+    }
+}
+
+// Same scheme and registrable domain, like example.com for www.example.com, RFC 6265bis
+// section 5.2. IP addresses and public suffixes are only the same site as themselves.
+pub fn same_site(a: &Url, b: &Url) -> bool {
+    let (a_host, b_host) = (
+        a.domain().to_ascii_lowercase(),
+        b.domain().to_ascii_lowercase(),
+    );
+    is_secure(a) == is_secure(b) && registrable_domain(&a_host) == registrable_domain(&b_host)
+}
+
+fn registrable_domain(host: &str) -> &str {
+    if host.parse::<IpAddr>().is_ok() || host.starts_with('[') {
+        return host;
+    }
+    match public_suffixes().domain(host.as_bytes()) {
+        // the list only holds ASCII, so is the part of host it returns
+        Some(d) => &host[host.len() - d.as_bytes().len()..],
+        None => host,
+    }
+}
+
+fn is_public_suffix(domain: &str) -> bool {
+    if domain.parse::<IpAddr>().is_ok() {
+        return false;
+    }
+    // names not on the list have their last label as the suffix
+    public_suffixes()
+        .suffix(domain.as_bytes())
+        .is_some_and(|s| s.as_bytes() == domain.as_bytes())
+}
+
+fn public_suffixes() -> &'static List {
+    static LIST: OnceLock<List> = OnceLock::new();
+    // an empty list still treats the last label as a public suffix
+    LIST.get_or_init(|| PUBLIC_SUFFIX_LIST.parse().unwrap_or_default())
+}
+
+fn is_secure(url: &Url) -> bool {
+    matches!(url.scheme(), "https" | "wss")
+}
+
+fn bool_str(b: bool) -> &'static str {
+    match b {
+        true => "TRUE",
+        false => "FALSE",
+    }
+}
+
+fn domain_match(host: &str, domain: &str) -> bool {
+    if host == domain {
+        return true;
+    }
+    host.parse::<IpAddr>().is_err()
+        && host.len() > domain.len()
+        && host.ends_with(domain)
+        && host.as_bytes()[host.len() - domain.len() - 1] == b'.'
+}
+
+fn path_match(request_path: &str, cookie_path: &str) -> bool {
+    if request_path == cookie_path {
+        return true;
+    }
+    request_path.starts_with(cookie_path)
+        && (cookie_path.ends_with('/') || request_path.as_bytes()[cookie_path.len()] == b'/')
+}
+
+// Directory of the request path, RFC 6265 section 5.1.4.
+fn default_path(route: &str) -> String {
+    if !route.starts_with('/') {
+        return "/".to_string();
+    }
+    match route.rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(i) => route[..i].to_string(),
+    }
+}
+
+fn parse_max_age(v: &str) -> Option<SystemTime> {
+    let (negative, digits) = match v.strip_prefix('-') {
+        Some(d) => (true, d),
+        None => (false, v),
+    };
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let secs = digits.parse::<u64>().unwrap_or(u64::MAX);
+    // zero or negative expires the cookie right away
+    if negative || secs == 0 {
+        return Some(UNIX_EPOCH);
+    }
+    Some(SystemTime::now() + Duration::from_secs(secs.min(MAX_AGE_LIMIT)))
+}
+
+// The lenient cookie date algorithm of RFC 6265 section 5.1.1.
+fn parse_date(v: &str) -> Option<SystemTime> {
+    let mut time = None;
+    let mut day = None;
+    let mut month = None;
+    let mut year = None;
+
+    for token in v.split(is_date_delimiter).filter(|t| !t.is_empty()) {
+        if time.is_none() {
+            if let Some(t) = parse_time(token) {
+                time = Some(t);
+                continue;
+            }
+        }
+        if day.is_none() {
+            if let Some(d) = leading_digits(token, 1, 2) {
+                day = Some(d);
+                continue;
+            }
+        }
+        if month.is_none() {
+            let prefix = token.get(..3).map(|p| p.to_ascii_lowercase());
+            if let Some(m) = prefix.and_then(|p| MONTHS.iter().position(|m| *m == p)) {
+                month = Some(m as u32 + 1);
+                continue;
+            }
+        }
+        if year.is_none() {
+            if let Some(y) = leading_digits(token, 2, 4) {
+                year = Some(y);
+                continue;
+            }
+        }
+    }
+
+    let (hour, minute, second) = time?;
+    let (day, month, mut year) = (day?, month?, year?);
+    if (70..=99).contains(&year) {
+        year += 1900;
+    } else if year <= 69 {
+        year += 2000;
+    }
+    if !(1..=31).contains(&day) || year < 1601 || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+
+    let days = days_from_civil(year as i64, month, day);
+    let secs = days * 86_400 + hour as i64 * 3600 + minute as i64 * 60 + second as i64;
+    match secs >= 0 {
+        true => UNIX_EPOCH.checked_add(Duration::from_secs(secs as u64)),
+        false => UNIX_EPOCH.checked_sub(Duration::from_secs(secs.unsigned_abs())),
+    }
+}
+
+fn is_date_delimiter(c: char) -> bool {
+    matches!(c, '\x09' | '\x20'..='\x2f' | '\x3b'..='\x40' | '\x5b'..='\x60' | '\x7b'..='\x7e')
+}
+
+fn parse_time(token: &str) -> Option<(u32, u32, u32)> {
+    let mut parts = token.splitn(3, ':');
+    let (h, m, s) = (parts.next()?, parts.next()?, parts.next()?);
+    let full = |p: &str| p.len() <= 2 && leading_digits(p, 1, 2).is_some();
+    if !full(h) || !full(m) {
+        return None;
+    }
+    Some((
+        leading_digits(h, 1, 2)?,
+        leading_digits(m, 1, 2)?,
+        leading_digits(s, 1, 2)?,
+    ))
+}
+
+// Number made of min to max digits at the start of the token, anything but a digit may follow.
+fn leading_digits(token: &str, min: usize, max: usize) -> Option<u32> {
+    let n = token.bytes().take_while(|b| b.is_ascii_digit()).count();
+    if n < min || n > max {
+        return None;
+    }
+    token[..n].parse().ok()
+}
+
+// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let m = month as i64;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(u: &str) -> Url<'_> {
+        Url::new(u).unwrap()
+    }
+
+    #[test]
+    fn domain_matching() {
+        assert!(domain_match("example.com", "example.com"));
+        assert!(domain_match("www.example.com", "example.com"));
+        assert!(domain_match("a.b.example.com", "example.com"));
+        assert!(!domain_match("badexample.com", "example.com"));
+        assert!(!domain_match("example.com", "www.example.com"));
+        // IP addresses only match themselves
+        assert!(domain_match("192.168.0.1", "192.168.0.1"));
+        assert!(!domain_match("10.168.0.1", "168.0.1"));
+    }
+
+    #[test]
+    fn path_matching() {
+        assert!(path_match("/", "/"));
+        assert!(path_match("/docs", "/docs"));
+        assert!(path_match("/docs/", "/docs"));
+        assert!(path_match("/docs/web", "/docs"));
+        assert!(path_match("/docs/web", "/docs/"));
+        assert!(!path_match("/docsets", "/docs"));
+        assert!(!path_match("/", "/docs"));
+    }
+
+    #[test]
+    fn default_paths() {
+        assert_eq!(default_path("/"), "/");
+        assert_eq!(default_path("/a"), "/");
+        assert_eq!(default_path("/a/b"), "/a");
+        assert_eq!(default_path("/a/b/"), "/a/b");
+    }
+
+    #[test]
+    fn host_only_without_domain() {
+        let c = Cookie::parse("id=1", &url("https://example.com/")).unwrap();
+        assert!(c.host_only);
+        assert!(c.matches(&url("https://example.com/x")));
+        assert!(!c.matches(&url("https://www.example.com/")));
+    }
+
+    #[test]
+    fn domain_attribute() {
+        // even when it names the host itself, subdomains get the cookie too
+        let c = Cookie::parse("id=1; Domain=.Example.com", &url("https://example.com/")).unwrap();
+        assert!(!c.host_only);
+        assert_eq!(c.domain, "example.com");
+        assert!(c.matches(&url("https://www.example.com/")));
+
+        let c =
+            Cookie::parse("id=1; Domain=example.com", &url("https://www.example.com/")).unwrap();
+        assert!(c.matches(&url("https://other.example.com/")));
+        assert!(!c.matches(&url("https://example.org/")));
+
+        // other sites, subdomains of the host and top level domains are refused
+        let from = url("https://www.example.com/");
+        assert!(Cookie::parse("id=1; Domain=example.org", &from).is_none());
+        assert!(Cookie::parse("id=1; Domain=a.www.example.com", &from).is_none());
+        assert!(Cookie::parse("id=1; Domain=com", &from).is_none());
+        let c = Cookie::parse("id=1; Domain=localhost", &url("http://localhost/")).unwrap();
+        assert!(c.host_only);
+    }
+
+    #[test]
+    fn public_suffix_domains() {
+        let from = url("https://www.example.co.uk/");
+        assert!(Cookie::parse("id=1; Domain=co.uk", &from).is_none());
+        assert!(Cookie::parse("id=1; Domain=uk", &from).is_none());
+        let c = Cookie::parse("id=1; Domain=example.co.uk", &from).unwrap();
+        assert!(c.matches(&url("https://shop.example.co.uk/")));
+        // private suffixes too, one user's site can't set cookies for the others
+        let from = url("https://alice.github.io/");
+        assert!(Cookie::parse("id=1; Domain=github.io", &from).is_none());
+        // set by the suffix itself it's kept to that host
+        let c = Cookie::parse("id=1; Domain=github.io", &url("https://github.io/")).unwrap();
+        assert!(c.host_only);
+        assert!(!c.matches(&url("https://alice.github.io/")));
+    }
+
+    #[test]
+    fn sites() {
+        let site = |a: &str, b: &str| same_site(&url(a), &url(b));
+        assert!(site(
+            "https://www.example.com/",
+            "https://api.EXAMPLE.com:8443/"
+        ));
+        assert!(site("https://a.example.co.uk/", "https://example.co.uk/"));
+        assert!(!site("https://a.co.uk/", "https://b.co.uk/"));
+        assert!(!site("https://alice.github.io/", "https://bob.github.io/"));
+        assert!(!site("https://example.com/", "http://example.com/"));
+        assert!(site("http://127.0.0.1:1/", "http://127.0.0.1:2/"));
+        assert!(!site("http://10.0.0.1/", "http://20.0.0.1/"));
+    }
+
+    #[test]
+    fn same_site_cookies() {
+        let jar = CookieJar::new();
+        let from = url("https://example.com/");
+        jar.set_cookie(&from, "s=1; SameSite=Strict");
+        jar.set_cookie(&from, "l=2; SameSite=Lax");
+        jar.set_cookie(&from, "n=3; SameSite=None; Secure");
+        jar.set_cookie(&from, "d=4");
+
+        let header = |m, cross| jar.header_for_request(&from, m, cross);
+        assert_eq!(
+            header(Methods::POST, false).as_deref(),
+            Some("s=1; l=2; n=3; d=4")
+        );
+        assert_eq!(header(Methods::GET, true).as_deref(), Some("l=2; n=3; d=4"));
+        assert_eq!(header(Methods::POST, true).as_deref(), Some("n=3; d=4"));
+    }
+
+    #[test]
+    fn path_attribute() {
+        let from = url("https://example.com/docs/page");
+        let c = Cookie::parse("id=1", &from).unwrap();
+        assert_eq!(c.path, "/docs");
+        let c = Cookie::parse("id=1; Path=/api", &from).unwrap();
+        assert!(c.matches(&url("https://example.com/api/v1")));
+        assert!(!c.matches(&url("https://example.com/apix")));
+        // not starting with a slash means the default path
+        let c = Cookie::parse("id=1; Path=api", &from).unwrap();
+        assert_eq!(c.path, "/docs");
+    }
+
+    #[test]
+    fn secure_and_prefixes() {
+        let https = url("https://example.com/");
+        let http = url("http://example.com/");
+        assert!(Cookie::parse("id=1; Secure", &http).is_none());
+        let c = Cookie::parse("id=1; Secure", &https).unwrap();
+        assert!(!c.matches(&http));
+        assert!(Cookie::parse("id=1; SameSite=None", &https).is_none());
+
+        assert!(Cookie::parse("__Secure-id=1", &https).is_none());
+        assert!(Cookie::parse("__Secure-id=1; Secure", &https).is_some());
+        assert!(Cookie::parse("__Host-id=1; Secure; Path=/", &https).is_some());
+        assert!(Cookie::parse("__Host-id=1; Secure", &url("https://example.com/a/b")).is_none());
+        assert!(Cookie::parse("__Host-id=1; Secure; Path=/; Domain=example.com", &https).is_none());
+    }
+
+    #[test]
+    fn jar_header() {
+        let jar = CookieJar::new();
+        let from = url("https://www.example.com/docs/page");
+        jar.set_cookie(&from, "a=1; Path=/");
+        jar.set_cookie(&from, "b=2");
+        jar.set_cookie(&from, "c=3; Domain=example.com; Path=/");
+        assert_eq!(
+            jar.header_for(&url("https://www.example.com/docs/x"))
+                .as_deref(),
+            Some("b=2; a=1; c=3")
+        );
+        assert_eq!(
+            jar.header_for(&url("https://api.example.com/")).as_deref(),
+            Some("c=3")
+        );
+        // Max-Age=0 removes it again
+        jar.set_cookie(&from, "a=; Path=/; Max-Age=0");
+        assert_eq!(
+            jar.header_for(&url("https://www.example.com/")).as_deref(),
+            Some("c=3")
+        );
+    }
+
+    #[test]
+    fn dates() {
+        let t = parse_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        assert_eq!(t, UNIX_EPOCH + Duration::from_secs(784_111_777));
+        assert_eq!(parse_date("Sunday, 06-Nov-94 08:49:37 GMT"), Some(t));
+        assert_eq!(parse_date("Sun Nov  6 08:49:37 1994"), Some(t));
+        assert!(parse_date("Sun, 32 Nov 1994 08:49:37 GMT").is_none());
+    }
+}
@@ -0,0 +1,222 @@
+use super::response::{HttpResponseError, HttpResult};
+use bytes::Bytes;
+#[cfg(any(
+    feature = "gzip",
+    feature = "deflate",
+    feature = "brotli",
+    feature = "zstd"
+))]
+use std::io::Read;
+use std::sync::OnceLock;
+
+// Content codings this build can decode, depends on the enabled features.
+const SUPPORTED: &[&str] = &[
+    #[cfg(feature = "zstd")]
+    "zstd",
+    #[cfg(feature = "brotli")]
+    "br",
+    #[cfg(feature = "gzip")]
+    "gzip",
+    #[cfg(feature = "deflate")]
+    "deflate",
+];
+
+// Largest content decoding may produce unless told otherwise, so a small
+// compressed response can't take up all memory.
+pub const DEFAULT_MAX_SIZE: usize = 64 << 20;
+
+// Value for the Accept-Encoding request header.
+pub fn accept_encoding() -> &'static str {
+    static VALUE: OnceLock<String> = OnceLock::new();
+    VALUE.get_or_init(|| match SUPPORTED.is_empty() {
+        true => "identity".to_string(),
+        false => SUPPORTED.join(", "),
+    })
+}
+
+// Undoes every coding listed in a Content-Encoding value.
+// Codings are applied in the listed order, so they get removed from last to first.
+// Fails with DecompressedTooLarge when a step produces more than max bytes.
+pub fn decode(content: Bytes, encodings: &str, max: usize) -> HttpResult<Bytes> {
+    let mut content = content;
+    for enc in encodings
+        .rsplit(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+    {
+        content = decode_one(content, &enc.to_ascii_lowercase(), max)?;
+    }
+    Ok(content)
+}
+
+// max goes unused in builds without any decoder
+#[allow(unused_variables)]
+fn decode_one(content: Bytes, encoding: &str, max: usize) -> HttpResult<Bytes> {
+    let decoded: HttpResult<Vec<u8>> = match encoding {
+        "identity" => return Ok(content),
+        #[cfg(feature = "gzip")]
+        "gzip" | "x-gzip" => read_all(flate2::read::MultiGzDecoder::new(&content[..]), max),
+        // should be zlib wrapped, but some servers send a raw deflate stream
+        #[cfg(feature = "deflate")]
+        "deflate" => {
+            read_all(flate2::read::ZlibDecoder::new(&content[..]), max).or_else(|e| match e {
+                HttpResponseError::Decompress(..) => {
+                    read_all(flate2::read::DeflateDecoder::new(&content[..]), max)
+                }
+                e => Err(e),
+            })
+        }
+        #[cfg(feature = "brotli")]
+        "br" => read_all(brotli::Decompressor::new(&content[..], 4096), max),
+        #[cfg(feature = "zstd")]
+        "zstd" => zstd::stream::read::Decoder::new(&content[..])
+            .map_err(HttpResponseError::Decompress)
+            .and_then(|d| read_all(d, max)),
+        other => Err(HttpResponseError::UnsupportedEncoding(other.to_string())),
+    };
+    decoded.map(Bytes::from)
+}
+
+// Reads one byte past max, to tell content of exactly max bytes from larger content.
+#[cfg(any(
+    feature = "gzip",
+    feature = "deflate",
+    feature = "brotli",
+    feature = "zstd"
+))]
+fn read_all<R: Read>(r: R, max: usize) -> HttpResult<Vec<u8>> {
+    let mut out = vec![];
+    let limit = (max as u64).saturating_add(1);
+    if let Err(e) = r.take(limit).read_to_end(&mut out) {
+        return Err(HttpResponseError::Decompress(e));
+    }
+    match out.len() > max {
+        true => Err(HttpResponseError::DecompressedTooLarge(max)),
+        false => Ok(out),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(any(feature = "gzip", feature = "deflate", feature = "brotli"))]
+    use std::io::Write;
+
+    const TEXT: &[u8] = b"hello hello hello hello hello hello";
+
+    #[cfg(feature = "gzip")]
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut enc = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        enc.write_all(data).unwrap();
+        enc.finish().unwrap()
+    }
+
+    #[test]
+    fn accept_encoding_lists_supported() {
+        let value = accept_encoding();
+        assert_eq!(value == "identity", SUPPORTED.is_empty());
+        for enc in SUPPORTED {
+            assert!(value.contains(enc));
+        }
+    }
+
+    #[test]
+    fn identity_and_unsupported() {
+        let content = Bytes::from_static(TEXT);
+        assert_eq!(decode(content.clone(), "identity", 1).unwrap(), TEXT);
+        assert_eq!(decode(content.clone(), " , ", 1).unwrap(), TEXT);
+        assert!(matches!(
+            decode(content, "compress", usize::MAX),
+            Err(HttpResponseError::UnsupportedEncoding(e)) if e == "compress"
+        ));
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn gzip_members() {
+        let mut two = gzip(b"hello ");
+        two.extend(gzip(b"world"));
+        assert_eq!(
+            decode(two.into(), "GZIP", usize::MAX).unwrap(),
+            "hello world"
+        );
+        assert!(matches!(
+            decode(Bytes::from_static(b"not gzip"), "gzip", usize::MAX),
+            Err(HttpResponseError::Decompress(..))
+        ));
+    }
+
+    #[cfg(feature = "deflate")]
+    #[test]
+    fn deflate_zlib_and_raw() {
+        let level = flate2::Compression::default();
+        let mut zlib = flate2::write::ZlibEncoder::new(vec![], level);
+        zlib.write_all(TEXT).unwrap();
+        let mut raw = flate2::write::DeflateEncoder::new(vec![], level);
+        raw.write_all(TEXT).unwrap();
+        for content in [zlib.finish().unwrap(), raw.finish().unwrap()] {
+            assert_eq!(decode(content.into(), "deflate", usize::MAX).unwrap(), TEXT);
+        }
+    }
+
+    #[cfg(feature = "brotli")]
+    #[test]
+    fn brotli() {
+        let mut enc = brotli::CompressorWriter::new(vec![], 4096, 5, 22);
+        enc.write_all(TEXT).unwrap();
+        let content = enc.into_inner();
+        assert_eq!(decode(content.into(), "br", usize::MAX).unwrap(), TEXT);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd() {
+        let content = zstd::encode_all(TEXT, 3).unwrap();
+        assert_eq!(decode(content.into(), "zstd", usize::MAX).unwrap(), TEXT);
+    }
+
+    #[cfg(all(feature = "gzip", feature = "zstd"))]
+    #[test]
+    fn codings_are_removed_last_to_first() {
+        let content = zstd::encode_all(&gzip(TEXT)[..], 3).unwrap();
+        assert_eq!(
+            decode(content.into(), "gzip, zstd", usize::MAX).unwrap(),
+            TEXT
+        );
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn size_limit() {
+        let bomb = gzip(&vec![0; 1 << 20]);
+        assert!(bomb.len() < 2048);
+        let bomb = Bytes::from(bomb);
+        assert!(matches!(
+            decode(bomb.clone(), "gzip", 1 << 16),
+            Err(HttpResponseError::DecompressedTooLarge(max)) if max == 1 << 16
+        ));
+        assert_eq!(decode(bomb, "gzip", 1 << 20).unwrap().len(), 1 << 20);
+    }
+
+    #[cfg(all(feature = "brotli", feature = "zstd"))]
+    #[test]
+    fn size_limit_of_every_decoder() {
+        let zeros = vec![0; 4096];
+        let mut br = brotli::CompressorWriter::new(vec![], 4096, 5, 22);
+        br.write_all(&zeros).unwrap();
+        let cases = [
+            ("br", br.into_inner()),
+            ("zstd", zstd::encode_all(&zeros[..], 3).unwrap()),
+        ];
+        for (enc, content) in cases {
+            assert!(
+                matches!(
+                    decode(content.into(), enc, 4095),
+                    Err(HttpResponseError::DecompressedTooLarge(4095))
+                ),
+                "{}",
+                enc
+            );
+        }
+    }
+}
@@ -0,0 +1,1039 @@
+use super::frame::*;
+use super::hpack::{Decoder, Encoder, DEFAULT_MAX_LIST_SIZE};
+use super::{H2Error, H2Result};
+use super::{CANCEL, FLOW_CONTROL_ERROR, NO_ERROR, PROTOCOL_ERROR, REFUSED_STREAM, STREAM_CLOSED};
+use crate::https::response::{insert_header, HeaderMap, Response};
+use bytes::{Bytes, BytesMut};
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const DEFAULT_WINDOW: i64 = 65_535;
+// Our receive windows, bigger than the default to not stall downloads.
+const LOCAL_WINDOW: i64 = 1 << 20;
+
+// Connection specific headers, not allowed in HTTP/2.
+const HOP_HEADERS: [&str; 6] = [
+    "connection",
+    "host",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+#[derive(Debug, Default)]
+struct Stream {
+    send_window: i64,
+    recv_window: i64,
+    status_code: Option<u16>,
+    headers: HeaderMap,
+    trailers: HeaderMap,
+    content: BytesMut,
+    // received END_STREAM
+    remote_closed: bool,
+    reset: Option<u32>,
+    // we reset it because its headers went over our limit
+    headers_too_large: bool,
+}
+
+impl Stream {
+    fn is_active(&self) -> bool {
+        !self.remote_closed && self.reset.is_none()
+    }
+}
+
+// Header block that is still waiting for CONTINUATION frames.
+#[derive(Debug)]
+struct PendingHeaders {
+    stream_id: u32,
+    block: BytesMut,
+    end_stream: bool,
+}
+
+// HTTP/2 client connection, RFC 9113.
+// Requests are multiplexed over the same transport, responses can be collected
+// in any order since frames for other streams are buffered while reading.
+pub struct H2Connection<S: Read + Write> {
+    io: S,
+    read_buf: BytesMut,
+    encoder: Encoder,
+    decoder: Decoder,
+    streams: HashMap<u32, Stream>,
+    next_stream_id: u32,
+    pending: Option<PendingHeaders>,
+    send_window: i64,
+    recv_window: i64,
+    // peer settings
+    initial_window: i64,
+    max_frame_size: u32,
+    max_concurrent: usize,
+    settings_received: bool,
+    goaway: Option<(u32, u32)>,
+}
+
+impl<S: Read + Write> H2Connection<S> {
+    // Sends the connection preface, the server's preface is handled with the first frames read.
+    pub fn handshake(io: S) -> H2Result<Self> {
+        let mut conn = Self {
+            io,
+            read_buf: BytesMut::with_capacity(DEFAULT_MAX_FRAME_SIZE as usize),
+            encoder: Encoder::new(),
+            decoder: Decoder::new(),
+            streams: HashMap::new(),
+            next_stream_id: 1,
+            pending: None,
+            send_window: DEFAULT_WINDOW,
+            recv_window: LOCAL_WINDOW,
+            initial_window: DEFAULT_WINDOW,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_concurrent: 100,
+            settings_received: false,
+            goaway: None,
+        };
+
+        let mut buf = BytesMut::from(PREFACE);
+        Frame::Settings {
+            ack: false,
+            settings: vec![
+                (SETTINGS_ENABLE_PUSH, 0),
+                (SETTINGS_INITIAL_WINDOW_SIZE, LOCAL_WINDOW as u32),
+                (SETTINGS_MAX_HEADER_LIST_SIZE, DEFAULT_MAX_LIST_SIZE as u32),
+            ],
+        }
+        .encode(&mut buf);
+        Frame::WindowUpdate {
+            stream_id: 0,
+            increment: (LOCAL_WINDOW - DEFAULT_WINDOW) as u32,
+        }
+        .encode(&mut buf);
+        conn.io.write_all(&buf)?;
+        conn.io.flush()?;
+
+        debug!("Sent HTTP/2 preface");
+        Ok(conn)
+    }
+
+    // Opens a new stream and sends the request on it, the response is read with read_response().
+    // Pseudo headers are built from the arguments, header names are lowercased.
+    pub fn send_request(
+        &mut self,
+        method: &str,
+        scheme: &str,
+        authority: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: Option<&[u8]>,
+    ) -> H2Result<u32> {
+        loop {
+            if let Some((_, code)) = self.goaway {
+                return Err(H2Error::GoAway(code));
+            }
+            let active = self.active_streams();
+            if active < self.max_concurrent {
+                break;
+            }
+            // with no stream to wait for, SETTINGS_MAX_CONCURRENT_STREAMS of 0 would block forever
+            if active == 0 {
+                return Err(H2Error::Protocol("peer allows no concurrent streams"));
+            }
+            debug!("Waiting for a stream to finish");
+            self.read_and_process()?;
+        }
+
+        let stream_id = self.next_stream_id;
+        if stream_id > MAX_WINDOW_SIZE {
+            return Err(H2Error::Protocol("stream ids exhausted"));
+        }
+        self.next_stream_id += 2;
+
+        let lower: Vec<(String, &str)> = headers
+            .iter()
+            .map(|(k, v)| (k.to_ascii_lowercase(), *v))
+            .filter(|(k, v)| !HOP_HEADERS.contains(&k.as_str()) && (k != "te" || *v == "trailers"))
+            .collect();
+        let mut fields = vec![
+            (":method", method),
+            (":scheme", scheme),
+            (":authority", authority),
+            (":path", path),
+        ];
+        fields.extend(lower.iter().map(|(k, v)| (k.as_str(), *v)));
+
+        let mut block = BytesMut::new();
+        self.encoder.encode(fields, &mut block);
+
+        let end_stream = body.is_none_or(|b| b.is_empty());
+        self.streams.insert(
+            stream_id,
+            Stream {
+                send_window: self.initial_window,
+                recv_window: LOCAL_WINDOW,
+                ..Default::default()
+            },
+        );
+
+        // header block split over HEADERS and CONTINUATION frames
+        let mut out = BytesMut::new();
+        let mut block = block.freeze();
+        let chunk = block.split_to(block.len().min(self.max_frame_size as usize));
+        Frame::Headers {
+            stream_id,
+            block: chunk,
+            end_stream,
+            end_headers: block.is_empty(),
+        }
+        .encode(&mut out);
+        while !block.is_empty() {
+            let chunk = block.split_to(block.len().min(self.max_frame_size as usize));
+            Frame::Continuation {
+                stream_id,
+                block: chunk,
+                end_headers: block.is_empty(),
+            }
+            .encode(&mut out);
+        }
+        self.io.write_all(&out)?;
+        self.io.flush()?;
+        debug!("Opened stream {}", stream_id);
+
+        if let Some(b) = body.filter(|b| !b.is_empty()) {
+            self.send_data(stream_id, Bytes::copy_from_slice(b))?;
+        }
+        Ok(stream_id)
+    }
+
+    // Blocks until the stream's response is complete.
+    pub fn read_response(&mut self, stream_id: u32) -> H2Result<Response> {
+        loop {
+            let stream = match self.streams.get(&stream_id) {
+                Some(s) => s,
+                None => return Err(H2Error::Protocol("unknown stream")),
+            };
+            if stream.headers_too_large {
+                self.streams.remove(&stream_id);
+                return Err(H2Error::HeaderListTooLarge);
+            }
+            if let Some(code) = stream.reset {
+                self.streams.remove(&stream_id);
+                return Err(H2Error::StreamReset(code));
+            }
+            if stream.remote_closed {
+                break;
+            }
+            self.read_and_process()?;
+        }
+
+        let stream = self.streams.remove(&stream_id).unwrap_or_default();
+        let status_code = match stream.status_code {
+            Some(c) => c,
+            None => return Err(H2Error::Protocol("response without :status")),
+        };
+        Ok(Response {
+            status_code,
+            reason: String::new(),
+            headers: stream.headers,
+            trailers: stream.trailers,
+            content: stream.content.freeze(),
+            redirects: vec![],
+            tls: None,
+        })
+    }
+
+    // Tells the peer we are done, streams already open may still finish.
+    pub fn close(&mut self) -> H2Result<()> {
+        self.write_frame(Frame::GoAway {
+            last_stream_id: 0,
+            error_code: NO_ERROR,
+            debug: Bytes::new(),
+        })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.goaway.is_some()
+    }
+
+    // The underlying transport, writing to it directly breaks the connection.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.io
+    }
+
+    fn active_streams(&self) -> usize {
+        self.streams.values().filter(|s| s.is_active()).count()
+    }
+
+    // Sends the body, waiting for WINDOW_UPDATEs when a window runs out.
+    fn send_data(&mut self, stream_id: u32, mut data: Bytes) -> H2Result<()> {
+        while !data.is_empty() {
+            let stream_window = match self.streams.get(&stream_id) {
+                Some(s) if s.reset.is_none() => s.send_window,
+                Some(_) | None => return Ok(()),
+            };
+            let window = self.send_window.min(stream_window).max(0) as usize;
+            let len = data.len().min(window).min(self.max_frame_size as usize);
+            if len == 0 {
+                debug!("Stream {} is blocked by flow control", stream_id);
+                self.read_and_process()?;
+                continue;
+            }
+
+            let chunk = data.split_to(len);
+            self.send_window -= len as i64;
+            if let Some(s) = self.streams.get_mut(&stream_id) {
+                s.send_window -= len as i64;
+            }
+            self.write_frame(Frame::Data {
+                stream_id,
+                data: chunk,
+                end_stream: data.is_empty(),
+                pad: None,
+            })?;
+        }
+        Ok(())
+    }
+
+    fn write_frame(&mut self, frame: Frame) -> H2Result<()> {
+        let mut buf = BytesMut::new();
+        frame.encode(&mut buf);
+        self.io.write_all(&buf)?;
+        self.io.flush()?;
+        Ok(())
+    }
+
+    fn read_frame(&mut self) -> H2Result<Frame> {
+        let mut tmp: [u8; 16384] = [0; 16384];
+        loop {
+            // we never raise SETTINGS_MAX_FRAME_SIZE, so the default is the limit
+            if let Some(frame) = Frame::decode(&mut self.read_buf, DEFAULT_MAX_FRAME_SIZE)? {
+                return Ok(frame);
+            }
+            match self.io.read(&mut tmp) {
+                Ok(0) => return Err(H2Error::Io(ErrorKind::UnexpectedEof.into())),
+                Ok(n) => self.read_buf.extend_from_slice(&tmp[..n]),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(H2Error::Io(e)),
+            }
+        }
+    }
+
+    // Reads a single frame and applies it, connection errors send a GOAWAY.
+    fn read_and_process(&mut self) -> H2Result<()> {
+        let res = self.read_frame().and_then(|f| self.process(f));
+        if let Err(ref e) = res {
+            match e {
+                H2Error::Io(..)
+                | H2Error::GoAway(..)
+                | H2Error::StreamReset(..)
+                | H2Error::HeaderListTooLarge => {}
+                e => {
+                    warn!("HTTP/2 connection error: {}", e);
+                    let _ = self.write_frame(Frame::GoAway {
+                        last_stream_id: 0,
+                        error_code: e.code(),
+                        debug: Bytes::new(),
+                    });
+                    self.goaway = Some((0, e.code()));
+                }
+            }
+        }
+        res
+    }
+
+    fn process(&mut self, frame: Frame) -> H2Result<()> {
+        // nothing may interleave with a header block
+        if let Some(ref p) = self.pending {
+            match frame {
+                Frame::Continuation { stream_id, .. } if stream_id == p.stream_id => {}
+                _ => return Err(H2Error::Protocol("expected CONTINUATION")),
+            }
+        }
+        if !self.settings_received && !matches!(frame, Frame::Settings { ack: false, .. }) {
+            return Err(H2Error::Protocol("server preface must be SETTINGS"));
+        }
+
+        match frame {
+            Frame::Settings { ack: true, .. } => debug!("SETTINGS acknowledged"),
+            Frame::Settings {
+                ack: false,
+                settings,
+            } => {
+                self.settings_received = true;
+                self.apply_settings(&settings)?;
+                self.write_frame(Frame::Settings {
+                    ack: true,
+                    settings: vec![],
+                })?;
+            }
+            Frame::Ping { ack: false, data } => {
+                self.write_frame(Frame::Ping { ack: true, data })?;
+            }
+            Frame::Ping { ack: true, .. } => {}
+            Frame::GoAway {
+                last_stream_id,
+                error_code,
+                debug,
+            } => {
+                debug!(
+                    "GOAWAY, last stream: {}, code: {:#x}, {:?}",
+                    last_stream_id, error_code, debug
+                );
+                self.goaway = Some((last_stream_id, error_code));
+                // streams above the last one were never processed
+                for (id, s) in self.streams.iter_mut() {
+                    if *id > last_stream_id && !s.remote_closed {
+                        s.reset = Some(REFUSED_STREAM);
+                    }
+                }
+                if error_code != NO_ERROR {
+                    for s in self.streams.values_mut().filter(|s| s.is_active()) {
+                        s.reset = Some(error_code);
+                    }
+                }
+            }
+            Frame::WindowUpdate {
+                stream_id,
+                increment,
+            } => {
+                if stream_id != 0 && self.is_idle(stream_id) {
+                    return Err(H2Error::Protocol("WINDOW_UPDATE on an idle stream"));
+                }
+                if increment == 0 {
+                    if stream_id == 0 {
+                        return Err(H2Error::Protocol("WINDOW_UPDATE of 0"));
+                    }
+                    // a closed stream may still get one, it's ignored like any other update
+                    if self.streams.contains_key(&stream_id) {
+                        return self.reset_stream(stream_id, PROTOCOL_ERROR);
+                    }
+                    return Ok(());
+                }
+                if stream_id == 0 {
+                    self.send_window += increment as i64;
+                    if self.send_window > MAX_WINDOW_SIZE as i64 {
+                        return Err(H2Error::FlowControl);
+                    }
+                } else if let Some(s) = self.streams.get_mut(&stream_id) {
+                    s.send_window += increment as i64;
+                    if s.send_window > MAX_WINDOW_SIZE as i64 {
+                        return self.reset_stream(stream_id, FLOW_CONTROL_ERROR);
+                    }
+                }
+            }
+            Frame::RstStream {
+                stream_id,
+                error_code,
+            } => {
+                if self.is_idle(stream_id) {
+                    return Err(H2Error::Protocol("RST_STREAM on an idle stream"));
+                }
+                if let Some(s) = self.streams.get_mut(&stream_id) {
+                    debug!("Stream {} reset, code: {:#x}", stream_id, error_code);
+                    s.reset = Some(error_code);
+                }
+            }
+            Frame::Headers {
+                stream_id,
+                block,
+                end_stream,
+                end_headers,
+            } => {
+                let pending = PendingHeaders {
+                    stream_id,
+                    block: BytesMut::from(&block[..]),
+                    end_stream,
+                };
+                if !end_headers {
+                    self.pending = Some(pending);
+                    return Ok(());
+                }
+                self.process_headers(pending)?;
+            }
+            Frame::Continuation {
+                block, end_headers, ..
+            } => {
+                let mut pending = match self.pending.take() {
+                    Some(p) => p,
+                    None => return Err(H2Error::Protocol("unexpected CONTINUATION")),
+                };
+                pending.block.extend_from_slice(&block);
+                // a block this large would decode to more than we allow, and one that
+                // isn't decoded leaves the HPACK state behind, RFC 9113 section 4.3
+                if pending.block.len() > self.decoder.max_list_size() {
+                    return Err(H2Error::Compression("header block too large"));
+                }
+                if !end_headers {
+                    self.pending = Some(pending);
+                    return Ok(());
+                }
+                self.process_headers(pending)?;
+            }
+            ref f @ Frame::Data {
+                stream_id,
+                ref data,
+                end_stream,
+                ..
+            } => {
+                // counted against the connection window whatever the stream's state
+                let len = f.flow_len() as i64;
+                self.recv_window -= len;
+                if self.recv_window < 0 {
+                    return Err(H2Error::FlowControl);
+                }
+                // everything is buffered right away, so the windows are refilled at half
+                if self.recv_window < LOCAL_WINDOW / 2 {
+                    let increment = (LOCAL_WINDOW - self.recv_window) as u32;
+                    self.recv_window = LOCAL_WINDOW;
+                    self.write_frame(Frame::WindowUpdate {
+                        stream_id: 0,
+                        increment,
+                    })?;
+                }
+
+                let idle = self.is_idle(stream_id);
+                let mut update = None;
+                match self.streams.get_mut(&stream_id) {
+                    Some(s) if s.is_active() && s.status_code.is_some() => {
+                        s.recv_window -= len;
+                        if s.recv_window < 0 {
+                            return self.reset_stream(stream_id, FLOW_CONTROL_ERROR);
+                        }
+                        s.content.extend_from_slice(data);
+                        s.remote_closed = end_stream;
+                        if !end_stream && s.recv_window < LOCAL_WINDOW / 2 {
+                            update = Some((LOCAL_WINDOW - s.recv_window) as u32);
+                            s.recv_window = LOCAL_WINDOW;
+                        }
+                    }
+                    Some(s) if s.is_active() => {
+                        return Err(H2Error::Protocol("DATA before HEADERS"));
+                    }
+                    _ if idle => {
+                        return Err(H2Error::Protocol("DATA on an idle stream"));
+                    }
+                    _ => return self.reset_stream(stream_id, STREAM_CLOSED),
+                }
+                if let Some(increment) = update {
+                    self.write_frame(Frame::WindowUpdate {
+                        stream_id,
+                        increment,
+                    })?;
+                }
+            }
+            Frame::PushPromise { .. } => {
+                return Err(H2Error::Protocol("PUSH_PROMISE with push disabled"));
+            }
+            Frame::Priority { .. } | Frame::Unknown { .. } => {}
+        }
+        Ok(())
+    }
+
+    fn apply_settings(&mut self, settings: &[(u16, u32)]) -> H2Result<()> {
+        for (id, value) in settings {
+            match *id {
+                SETTINGS_HEADER_TABLE_SIZE => self.encoder.set_max_size(*value as usize),
+                SETTINGS_ENABLE_PUSH if *value > 1 => {
+                    return Err(H2Error::Protocol("invalid ENABLE_PUSH"));
+                }
+                SETTINGS_MAX_CONCURRENT_STREAMS => self.max_concurrent = *value as usize,
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    if *value > MAX_WINDOW_SIZE {
+                        return Err(H2Error::FlowControl);
+                    }
+                    // the difference applies to every open stream
+                    let delta = *value as i64 - self.initial_window;
+                    self.initial_window = *value as i64;
+                    for s in self.streams.values_mut() {
+                        s.send_window += delta;
+                    }
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(DEFAULT_MAX_FRAME_SIZE..=MAX_MAX_FRAME_SIZE).contains(value) {
+                        return Err(H2Error::Protocol("invalid MAX_FRAME_SIZE"));
+                    }
+                    self.max_frame_size = *value;
+                }
+                _ => {}
+            }
+        }
+        debug!("Applied peer settings: {:?}", settings);
+        Ok(())
+    }
+
+    fn process_headers(&mut self, pending: PendingHeaders) -> H2Result<()> {
+        // always decoded, the HPACK state is shared by all streams
+        let fields = match self.decoder.decode(&pending.block) {
+            Err(H2Error::HeaderListTooLarge) => None,
+            res => Some(res?),
+        };
+
+        if self.is_idle(pending.stream_id) {
+            return Err(H2Error::Protocol("HEADERS on an idle stream"));
+        }
+        let stream = match self.streams.get_mut(&pending.stream_id) {
+            Some(s) if s.is_active() => s,
+            _ => return self.reset_stream(pending.stream_id, STREAM_CLOSED),
+        };
+        let fields = match fields {
+            Some(f) => f,
+            None => {
+                debug!("Headers of stream {} are too large", pending.stream_id);
+                stream.headers_too_large = true;
+                return self.reset_stream(pending.stream_id, CANCEL);
+            }
+        };
+
+        if stream.status_code.is_some() {
+            // trailers have to end the stream
+            if !pending.end_stream {
+                return self.reset_stream(pending.stream_id, PROTOCOL_ERROR);
+            }
+            for (k, v) in fields.into_iter().filter(|(k, _)| !k.starts_with(':')) {
+                insert_header(&mut stream.trailers, k, v);
+            }
+            stream.remote_closed = true;
+            return Ok(());
+        }
+
+        let status = fields
+            .iter()
+            .find(|(k, _)| k == ":status")
+            .and_then(|(_, v)| v.parse::<u16>().ok());
+        let status = match status {
+            Some(s) => s,
+            None => return self.reset_stream(pending.stream_id, PROTOCOL_ERROR),
+        };
+        // interim responses are dropped
+        if (100..200).contains(&status) {
+            return Ok(());
+        }
+
+        stream.status_code = Some(status);
+        for (k, v) in fields.into_iter().filter(|(k, _)| !k.starts_with(':')) {
+            insert_header(&mut stream.headers, k, v);
+        }
+        stream.remote_closed = pending.end_stream;
+        Ok(())
+    }
+
+    // Streams we haven't opened yet, RFC 9113 section 5.1. Push is off,
+    // so the server can't open any and even ids are never in use.
+    fn is_idle(&self, stream_id: u32) -> bool {
+        stream_id.is_multiple_of(2) || stream_id >= self.next_stream_id
+    }
+
+    fn reset_stream(&mut self, stream_id: u32, code: u32) -> H2Result<()> {
+        debug!("Resetting stream {}, code: {:#x}", stream_id, code);
+        if let Some(s) = self.streams.get_mut(&stream_id) {
+            s.reset = Some(code);
+        }
+        self.write_frame(Frame::RstStream {
+            stream_id,
+            error_code: code,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::https::h2::COMPRESSION_ERROR;
+    use std::io::{self, Cursor};
+
+    // Server side of a connection, everything it sends is known up front.
+    struct Peer {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Peer {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Peer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn settings(settings: Vec<(u16, u32)>) -> Frame {
+        Frame::Settings {
+            ack: false,
+            settings,
+        }
+    }
+
+    fn headers(encoder: &mut Encoder, stream_id: u32, fields: &[(&str, &str)], end: bool) -> Frame {
+        let mut block = BytesMut::new();
+        encoder.encode(fields.iter().copied(), &mut block);
+        Frame::Headers {
+            stream_id,
+            block: block.freeze(),
+            end_stream: end,
+            end_headers: true,
+        }
+    }
+
+    fn data(stream_id: u32, data: &[u8], end_stream: bool) -> Frame {
+        Frame::Data {
+            stream_id,
+            data: Bytes::copy_from_slice(data),
+            end_stream,
+            pad: None,
+        }
+    }
+
+    fn connect(frames: Vec<Frame>) -> H2Connection<Peer> {
+        let mut input = BytesMut::new();
+        for f in frames {
+            f.encode(&mut input);
+        }
+        let peer = Peer {
+            input: Cursor::new(input.to_vec()),
+            output: vec![],
+        };
+        H2Connection::handshake(peer).unwrap()
+    }
+
+    fn get(conn: &mut H2Connection<Peer>) -> u32 {
+        conn.send_request("GET", "https", "example.com", "/", &[], None)
+            .unwrap()
+    }
+
+    // Frames the client sent, after the preface.
+    fn sent(conn: &mut H2Connection<Peer>) -> Vec<Frame> {
+        let out = &conn.get_mut().output;
+        assert!(out.starts_with(PREFACE));
+        let mut buf = BytesMut::from(&out[PREFACE.len()..]);
+        let mut frames = vec![];
+        while let Some(f) = Frame::decode(&mut buf, MAX_MAX_FRAME_SIZE).unwrap() {
+            frames.push(f);
+        }
+        frames
+    }
+
+    fn goaway_code(conn: &mut H2Connection<Peer>) -> Option<u32> {
+        sent(conn).into_iter().find_map(|f| match f {
+            Frame::GoAway { error_code, .. } => Some(error_code),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn request_and_response() {
+        let mut enc = Encoder::new();
+        let mut conn = connect(vec![
+            settings(vec![]),
+            headers(
+                &mut enc,
+                1,
+                &[(":status", "200"), ("content-type", "text/plain")],
+                false,
+            ),
+            data(1, b"hello ", false),
+            data(1, b"world", true),
+        ]);
+        let headers = [
+            ("User-Agent", "test"),
+            ("Connection", "close"),
+            ("TE", "gzip"),
+        ];
+        let id = conn
+            .send_request("GET", "https", "example.com", "/a?b", &headers, None)
+            .unwrap();
+        assert_eq!(id, 1);
+        let res = conn.read_response(id).unwrap();
+        assert_eq!(res.status_code, 200);
+        assert_eq!(res.header("Content-Type"), Some("text/plain"));
+        assert_eq!(&res.content[..], b"hello world");
+
+        let frames = sent(&mut conn);
+        assert!(matches!(frames[0], Frame::Settings { ack: false, .. }));
+        let block = match frames[2] {
+            Frame::Headers {
+                stream_id: 1,
+                ref block,
+                end_stream: true,
+                end_headers: true,
+            } => block,
+            ref f => panic!("unexpected {:?}", f),
+        };
+        let fields = Decoder::new().decode(block).unwrap();
+        let fields: Vec<(&str, &str)> = fields
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        // connection specific headers are left out
+        assert_eq!(
+            fields,
+            [
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":authority", "example.com"),
+                (":path", "/a?b"),
+                ("user-agent", "test"),
+            ]
+        );
+        assert!(frames.contains(&Frame::Settings {
+            ack: true,
+            settings: vec![],
+        }));
+    }
+
+    #[test]
+    fn continuation_interim_and_trailers() {
+        let mut enc = Encoder::new();
+        let mut block = BytesMut::new();
+        enc.encode([(":status", "200"), ("x-long", "a")], &mut block);
+        let mut block = block.freeze();
+        let first = block.split_to(2);
+        let mut conn = connect(vec![
+            settings(vec![]),
+            headers(&mut enc, 1, &[(":status", "103"), ("link", "</a>")], false),
+            Frame::Headers {
+                stream_id: 1,
+                block: first,
+                end_stream: false,
+                end_headers: false,
+            },
+            Frame::Continuation {
+                stream_id: 1,
+                block,
+                end_headers: true,
+            },
+            data(1, b"body", false),
+            headers(&mut enc, 1, &[("x-checksum", "1234")], true),
+        ]);
+        let id = get(&mut conn);
+        let res = conn.read_response(id).unwrap();
+        assert_eq!(res.status_code, 200);
+        assert_eq!(res.header("x-long"), Some("a"));
+        assert_eq!(res.header("link"), None);
+        assert_eq!(
+            res.trailers.get("x-checksum").map(|s| s.as_str()),
+            Some("1234")
+        );
+        assert_eq!(&res.content[..], b"body");
+    }
+
+    #[test]
+    fn body_waits_for_window_updates() {
+        let mut enc = Encoder::new();
+        let mut conn = connect(vec![
+            settings(vec![]),
+            Frame::WindowUpdate {
+                stream_id: 0,
+                increment: 10_000,
+            },
+            Frame::WindowUpdate {
+                stream_id: 1,
+                increment: 10_000,
+            },
+            headers(&mut enc, 1, &[(":status", "204")], true),
+        ]);
+        let body = vec![b'x'; 70_000];
+        let id = conn
+            .send_request("POST", "https", "example.com", "/", &[], Some(&body))
+            .unwrap();
+        assert_eq!(conn.read_response(id).unwrap().status_code, 204);
+
+        let sizes: Vec<(usize, bool)> = sent(&mut conn)
+            .iter()
+            .filter_map(|f| match f {
+                Frame::Data {
+                    data, end_stream, ..
+                } => Some((data.len(), *end_stream)),
+                _ => None,
+            })
+            .collect();
+        assert!(sizes
+            .iter()
+            .all(|(n, _)| *n <= DEFAULT_MAX_FRAME_SIZE as usize));
+        assert_eq!(sizes.iter().map(|(n, _)| n).sum::<usize>(), 70_000);
+        assert_eq!(sizes.iter().filter(|(_, end)| *end).count(), 1);
+        assert!(sizes.last().unwrap().1);
+    }
+
+    #[test]
+    fn frames_on_idle_streams_close_the_connection() {
+        let cases = [
+            data(3, b"x", false),
+            headers(&mut Encoder::new(), 2, &[(":status", "200")], true),
+            Frame::RstStream {
+                stream_id: 5,
+                error_code: 0,
+            },
+            Frame::WindowUpdate {
+                stream_id: 7,
+                increment: 1,
+            },
+        ];
+        for frame in cases {
+            let mut conn = connect(vec![settings(vec![]), frame]);
+            let id = get(&mut conn);
+            let e = conn.read_response(id).err().unwrap();
+            assert!(matches!(e, H2Error::Protocol(..)), "{}", e);
+            assert_eq!(goaway_code(&mut conn), Some(PROTOCOL_ERROR));
+            assert!(conn.is_closed());
+        }
+    }
+
+    #[test]
+    fn data_on_closed_streams_counts_against_the_connection() {
+        let mut enc = Encoder::new();
+        let mut frames = vec![
+            settings(vec![]),
+            headers(&mut enc, 1, &[(":status", "200")], true),
+        ];
+        let chunk = vec![0; DEFAULT_MAX_FRAME_SIZE as usize];
+        let count = (LOCAL_WINDOW / 2) as usize / chunk.len() + 1;
+        frames.extend((0..count).map(|_| data(1, &chunk, false)));
+        frames.push(headers(&mut enc, 3, &[(":status", "200")], true));
+        let mut conn = connect(frames);
+
+        let id = get(&mut conn);
+        conn.read_response(id).unwrap();
+        let id = get(&mut conn);
+        conn.read_response(id).unwrap();
+
+        let frames = sent(&mut conn);
+        let resets = frames
+            .iter()
+            .filter(|f| {
+                **f == Frame::RstStream {
+                    stream_id: 1,
+                    error_code: STREAM_CLOSED,
+                }
+            })
+            .count();
+        assert_eq!(resets, count);
+        let refilled: u32 = frames
+            .iter()
+            .filter_map(|f| match *f {
+                Frame::WindowUpdate {
+                    stream_id: 0,
+                    increment,
+                } => Some(increment),
+                _ => None,
+            })
+            .sum();
+        // the one sent with the preface, and the refill
+        assert_eq!(
+            refilled as i64,
+            LOCAL_WINDOW - DEFAULT_WINDOW + (count * chunk.len()) as i64
+        );
+        assert!(!conn.is_closed());
+    }
+
+    #[test]
+    fn oversized_header_list_fails_only_its_stream() {
+        let mut enc = Encoder::new();
+        let value = "v".repeat(4000);
+        let big: Vec<(&str, &str)> = std::iter::once((":status", "200"))
+            .chain((0..70).map(|_| ("x-big", value.as_str())))
+            .collect();
+        let mut conn = connect(vec![
+            settings(vec![]),
+            headers(&mut enc, 1, &big, true),
+            headers(&mut enc, 3, &[(":status", "200"), ("x-big", &value)], true),
+        ]);
+        let id = get(&mut conn);
+        assert!(matches!(
+            conn.read_response(id),
+            Err(H2Error::HeaderListTooLarge)
+        ));
+        let id = get(&mut conn);
+        let res = conn.read_response(id).unwrap();
+        assert_eq!(res.header("x-big"), Some(value.as_str()));
+
+        let frames = sent(&mut conn);
+        assert!(frames.contains(&Frame::RstStream {
+            stream_id: 1,
+            error_code: CANCEL,
+        }));
+        assert!(frames.contains(&settings(vec![
+            (SETTINGS_ENABLE_PUSH, 0),
+            (SETTINGS_INITIAL_WINDOW_SIZE, LOCAL_WINDOW as u32),
+            (SETTINGS_MAX_HEADER_LIST_SIZE, DEFAULT_MAX_LIST_SIZE as u32),
+        ])));
+    }
+
+    #[test]
+    fn oversized_header_block_closes_the_connection() {
+        let chunk = Bytes::from(vec![0; DEFAULT_MAX_FRAME_SIZE as usize]);
+        let mut frames = vec![
+            settings(vec![]),
+            Frame::Headers {
+                stream_id: 1,
+                block: chunk.clone(),
+                end_stream: true,
+                end_headers: false,
+            },
+        ];
+        let count = DEFAULT_MAX_LIST_SIZE / chunk.len() + 1;
+        frames.extend((0..count).map(|_| Frame::Continuation {
+            stream_id: 1,
+            block: chunk.clone(),
+            end_headers: false,
+        }));
+        let mut conn = connect(frames);
+        let id = get(&mut conn);
+        assert!(matches!(
+            conn.read_response(id),
+            Err(H2Error::Compression(..))
+        ));
+        assert_eq!(goaway_code(&mut conn), Some(COMPRESSION_ERROR));
+    }
+
+    #[test]
+    fn settings_and_goaway() {
+        let mut enc = Encoder::new();
+        let mut conn = connect(vec![
+            settings(vec![(SETTINGS_MAX_CONCURRENT_STREAMS, 0)]),
+            headers(&mut enc, 1, &[(":status", "200")], true),
+        ]);
+        let id = get(&mut conn);
+        conn.read_response(id).unwrap();
+        // nothing to wait for, so it fails instead of blocking
+        let e = conn.send_request("GET", "https", "example.com", "/", &[], None);
+        assert!(matches!(e, Err(H2Error::Protocol(..))));
+
+        let mut conn = connect(vec![
+            settings(vec![]),
+            Frame::GoAway {
+                last_stream_id: 1,
+                error_code: NO_ERROR,
+                debug: Bytes::new(),
+            },
+            headers(&mut enc, 1, &[(":status", "200")], true),
+        ]);
+        let first = get(&mut conn);
+        let second = get(&mut conn);
+        assert!(matches!(
+            conn.read_response(second),
+            Err(H2Error::StreamReset(REFUSED_STREAM))
+        ));
+        assert!(conn.is_closed());
+        // streams up to the last one still complete
+        assert_eq!(conn.read_response(first).unwrap().status_code, 200);
+        assert!(matches!(
+            conn.send_request("GET", "https", "example.com", "/", &[], None),
+            Err(H2Error::GoAway(NO_ERROR))
+        ));
+
+        let mut conn = connect(vec![settings(vec![(
+            SETTINGS_INITIAL_WINDOW_SIZE,
+            MAX_WINDOW_SIZE + 1,
+        )])]);
+        let id = get(&mut conn);
+        assert!(matches!(conn.read_response(id), Err(H2Error::FlowControl)));
+        assert_eq!(goaway_code(&mut conn), Some(FLOW_CONTROL_ERROR));
+    }
+}
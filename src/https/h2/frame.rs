@@ -0,0 +1,550 @@
+use super::{H2Error, H2Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+pub const FRAME_HEADER_LEN: usize = 9;
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 16_384;
+pub const MAX_MAX_FRAME_SIZE: u32 = 16_777_215;
+pub const MAX_WINDOW_SIZE: u32 = (1 << 31) - 1;
+
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+// Setting identifiers, RFC 9113 section 6.5.2.
+pub const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x1;
+pub const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+pub const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+pub const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+pub const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+pub const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+#[derive(Debug, PartialEq)]
+pub enum Frame {
+    Data {
+        stream_id: u32,
+        data: Bytes,
+        end_stream: bool,
+        pad: Option<u8>,
+    },
+    Headers {
+        stream_id: u32,
+        block: Bytes,
+        end_stream: bool,
+        end_headers: bool,
+    },
+    Priority {
+        stream_id: u32,
+    },
+    RstStream {
+        stream_id: u32,
+        error_code: u32,
+    },
+    Settings {
+        ack: bool,
+        settings: Vec<(u16, u32)>,
+    },
+    PushPromise {
+        stream_id: u32,
+        promised_id: u32,
+        block: Bytes,
+        end_headers: bool,
+    },
+    Ping {
+        ack: bool,
+        data: [u8; 8],
+    },
+    GoAway {
+        last_stream_id: u32,
+        error_code: u32,
+        debug: Bytes,
+    },
+    WindowUpdate {
+        stream_id: u32,
+        increment: u32,
+    },
+    Continuation {
+        stream_id: u32,
+        block: Bytes,
+        end_headers: bool,
+    },
+    Unknown {
+        kind: u8,
+        stream_id: u32,
+    },
+}
+
+impl Frame {
+    pub fn stream_id(&self) -> u32 {
+        match *self {
+            Frame::Data { stream_id, .. }
+            | Frame::Headers { stream_id, .. }
+            | Frame::Priority { stream_id }
+            | Frame::RstStream { stream_id, .. }
+            | Frame::PushPromise { stream_id, .. }
+            | Frame::WindowUpdate { stream_id, .. }
+            | Frame::Continuation { stream_id, .. }
+            | Frame::Unknown { stream_id, .. } => stream_id,
+            Frame::Settings { .. } | Frame::Ping { .. } | Frame::GoAway { .. } => 0,
+        }
+    }
+
+    // Bytes counted against flow control, padding included.
+    pub fn flow_len(&self) -> usize {
+        match self {
+            Frame::Data { data, pad, .. } => data.len() + pad.map_or(0, |p| p as usize + 1),
+            _ => 0,
+        }
+    }
+
+    // Decodes one frame from the front of buf, None if it isn't complete yet.
+    pub fn decode(buf: &mut BytesMut, max_frame_size: u32) -> H2Result<Option<Frame>> {
+        if buf.len() < FRAME_HEADER_LEN {
+            return Ok(None);
+        }
+        let len = (buf[0] as usize) << 16 | (buf[1] as usize) << 8 | buf[2] as usize;
+        if len > max_frame_size as usize {
+            return Err(H2Error::FrameSize);
+        }
+        if buf.len() < FRAME_HEADER_LEN + len {
+            return Ok(None);
+        }
+
+        let kind = buf[3];
+        let flags = buf[4];
+        let stream_id = u32::from_be_bytes([buf[5], buf[6], buf[7], buf[8]]) & MAX_WINDOW_SIZE;
+        buf.advance(FRAME_HEADER_LEN);
+        let mut payload = buf.split_to(len).freeze();
+
+        let frame = match kind {
+            DATA => {
+                require_stream(stream_id)?;
+                let pad = strip_padding(&mut payload, flags)?;
+                Frame::Data {
+                    stream_id,
+                    data: payload,
+                    end_stream: flags & END_STREAM != 0,
+                    pad,
+                }
+            }
+            HEADERS => {
+                require_stream(stream_id)?;
+                strip_padding(&mut payload, flags)?;
+                if flags & PRIORITY_FLAG != 0 {
+                    if payload.len() < 5 {
+                        return Err(H2Error::FrameSize);
+                    }
+                    payload.advance(5);
+                }
+                Frame::Headers {
+                    stream_id,
+                    block: payload,
+                    end_stream: flags & END_STREAM != 0,
+                    end_headers: flags & END_HEADERS != 0,
+                }
+            }
+            PRIORITY => {
+                require_stream(stream_id)?;
+                if len != 5 {
+                    return Err(H2Error::FrameSize);
+                }
+                Frame::Priority { stream_id }
+            }
+            RST_STREAM => {
+                require_stream(stream_id)?;
+                if len != 4 {
+                    return Err(H2Error::FrameSize);
+                }
+                Frame::RstStream {
+                    stream_id,
+                    error_code: payload.get_u32(),
+                }
+            }
+            SETTINGS => {
+                if stream_id != 0 {
+                    return Err(H2Error::Protocol("SETTINGS on a stream"));
+                }
+                let ack = flags & ACK != 0;
+                if !len.is_multiple_of(6) || (ack && len != 0) {
+                    return Err(H2Error::FrameSize);
+                }
+                let mut settings = Vec::with_capacity(len / 6);
+                while payload.has_remaining() {
+                    settings.push((payload.get_u16(), payload.get_u32()));
+                }
+                Frame::Settings { ack, settings }
+            }
+            PUSH_PROMISE => {
+                require_stream(stream_id)?;
+                strip_padding(&mut payload, flags)?;
+                if payload.len() < 4 {
+                    return Err(H2Error::FrameSize);
+                }
+                Frame::PushPromise {
+                    stream_id,
+                    promised_id: payload.get_u32() & MAX_WINDOW_SIZE,
+                    block: payload,
+                    end_headers: flags & END_HEADERS != 0,
+                }
+            }
+            PING => {
+                if stream_id != 0 {
+                    return Err(H2Error::Protocol("PING on a stream"));
+                }
+                if len != 8 {
+                    return Err(H2Error::FrameSize);
+                }
+                let mut data = [0; 8];
+                payload.copy_to_slice(&mut data);
+                Frame::Ping {
+                    ack: flags & ACK != 0,
+                    data,
+                }
+            }
+            GOAWAY => {
+                if stream_id != 0 {
+                    return Err(H2Error::Protocol("GOAWAY on a stream"));
+                }
+                if len < 8 {
+                    return Err(H2Error::FrameSize);
+                }
+                Frame::GoAway {
+                    last_stream_id: payload.get_u32() & MAX_WINDOW_SIZE,
+                    error_code: payload.get_u32(),
+                    debug: payload,
+                }
+            }
+            WINDOW_UPDATE => {
+                if len != 4 {
+                    return Err(H2Error::FrameSize);
+                }
+                Frame::WindowUpdate {
+                    stream_id,
+                    increment: payload.get_u32() & MAX_WINDOW_SIZE,
+                }
+            }
+            CONTINUATION => {
+                require_stream(stream_id)?;
+                Frame::Continuation {
+                    stream_id,
+                    block: payload,
+                    end_headers: flags & END_HEADERS != 0,
+                }
+            }
+            kind => Frame::Unknown { kind, stream_id },
+        };
+
+        Ok(Some(frame))
+    }
+
+    pub fn encode(&self, dst: &mut BytesMut) {
+        match self {
+            Frame::Data {
+                stream_id,
+                data,
+                end_stream,
+                pad,
+            } => {
+                let mut flags = if *end_stream { END_STREAM } else { 0 };
+                if pad.is_some() {
+                    flags |= PADDED;
+                }
+                write_header(dst, self.flow_len(), DATA, flags, *stream_id);
+                if let Some(p) = pad {
+                    dst.put_u8(*p);
+                }
+                dst.extend_from_slice(data);
+                if let Some(p) = pad {
+                    dst.put_bytes(0, *p as usize);
+                }
+            }
+            Frame::Headers {
+                stream_id,
+                block,
+                end_stream,
+                end_headers,
+            } => {
+                let mut flags = if *end_stream { END_STREAM } else { 0 };
+                if *end_headers {
+                    flags |= END_HEADERS;
+                }
+                write_header(dst, block.len(), HEADERS, flags, *stream_id);
+                dst.extend_from_slice(block);
+            }
+            Frame::Priority { stream_id } => {
+                write_header(dst, 5, PRIORITY, 0, *stream_id);
+                // no dependency, default weight
+                dst.put_u32(0);
+                dst.put_u8(15);
+            }
+            Frame::RstStream {
+                stream_id,
+                error_code,
+            } => {
+                write_header(dst, 4, RST_STREAM, 0, *stream_id);
+                dst.put_u32(*error_code);
+            }
+            Frame::Settings { ack, settings } => {
+                let flags = if *ack { ACK } else { 0 };
+                write_header(dst, settings.len() * 6, SETTINGS, flags, 0);
+                for (id, value) in settings {
+                    dst.put_u16(*id);
+                    dst.put_u32(*value);
+                }
+            }
+            Frame::PushPromise {
+                stream_id,
+                promised_id,
+                block,
+                end_headers,
+            } => {
+                let flags = if *end_headers { END_HEADERS } else { 0 };
+                write_header(dst, block.len() + 4, PUSH_PROMISE, flags, *stream_id);
+                dst.put_u32(*promised_id);
+                dst.extend_from_slice(block);
+            }
+            Frame::Ping { ack, data } => {
+                let flags = if *ack { ACK } else { 0 };
+                write_header(dst, 8, PING, flags, 0);
+                dst.extend_from_slice(data);
+            }
+            Frame::GoAway {
+                last_stream_id,
+                error_code,
+                debug,
+            } => {
+                write_header(dst, debug.len() + 8, GOAWAY, 0, 0);
+                dst.put_u32(*last_stream_id);
+                dst.put_u32(*error_code);
+                dst.extend_from_slice(debug);
+            }
+            Frame::WindowUpdate {
+                stream_id,
+                increment,
+            } => {
+                write_header(dst, 4, WINDOW_UPDATE, 0, *stream_id);
+                dst.put_u32(*increment);
+            }
+            Frame::Continuation {
+                stream_id,
+                block,
+                end_headers,
+            } => {
+                let flags = if *end_headers { END_HEADERS } else { 0 };
+                write_header(dst, block.len(), CONTINUATION, flags, *stream_id);
+                dst.extend_from_slice(block);
+            }
+            Frame::Unknown { kind, stream_id } => {
+                write_header(dst, 0, *kind, 0, *stream_id);
+            }
+        }
+    }
+}
+
+fn write_header(dst: &mut BytesMut, len: usize, kind: u8, flags: u8, stream_id: u32) {
+    dst.reserve(FRAME_HEADER_LEN + len);
+    dst.put_uint(len as u64, 3);
+    dst.put_u8(kind);
+    dst.put_u8(flags);
+    dst.put_u32(stream_id & MAX_WINDOW_SIZE);
+}
+
+fn require_stream(stream_id: u32) -> H2Result<()> {
+    match stream_id {
+        0 => Err(H2Error::Protocol("frame requires a stream")),
+        _ => Ok(()),
+    }
+}
+
+// Removes the pad length octet and the padding itself.
+fn strip_padding(payload: &mut Bytes, flags: u8) -> H2Result<Option<u8>> {
+    if flags & PADDED == 0 {
+        return Ok(None);
+    }
+    if payload.is_empty() {
+        return Err(H2Error::FrameSize);
+    }
+    let pad = payload.get_u8();
+    if pad as usize > payload.len() {
+        return Err(H2Error::Protocol("padding longer than the payload"));
+    }
+    payload.truncate(payload.len() - pad as usize);
+    Ok(Some(pad))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(frame: Frame) {
+        let mut buf = BytesMut::new();
+        frame.encode(&mut buf);
+        // incomplete until the last byte is there
+        let mut partial = BytesMut::from(&buf[..buf.len() - 1]);
+        if buf.len() > FRAME_HEADER_LEN {
+            assert_eq!(
+                Frame::decode(&mut partial, MAX_MAX_FRAME_SIZE).unwrap(),
+                None
+            );
+        }
+        buf.extend_from_slice(b"next");
+        let decoded = Frame::decode(&mut buf, MAX_MAX_FRAME_SIZE).unwrap();
+        assert_eq!(decoded, Some(frame));
+        assert_eq!(&buf[..], b"next");
+    }
+
+    fn decode(bytes: &[u8]) -> H2Result<Option<Frame>> {
+        Frame::decode(&mut BytesMut::from(bytes), DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let block = Bytes::from_static(b"\x82\x86");
+        let frames = [
+            Frame::Data {
+                stream_id: 1,
+                data: Bytes::from_static(b"hello"),
+                end_stream: true,
+                pad: None,
+            },
+            Frame::Data {
+                stream_id: 3,
+                data: Bytes::from_static(b"padded"),
+                end_stream: false,
+                pad: Some(4),
+            },
+            Frame::Headers {
+                stream_id: 5,
+                block: block.clone(),
+                end_stream: false,
+                end_headers: true,
+            },
+            Frame::Priority { stream_id: 7 },
+            Frame::RstStream {
+                stream_id: 9,
+                error_code: 0x8,
+            },
+            Frame::Settings {
+                ack: false,
+                settings: vec![
+                    (SETTINGS_ENABLE_PUSH, 0),
+                    (SETTINGS_MAX_FRAME_SIZE, 1 << 20),
+                ],
+            },
+            Frame::Settings {
+                ack: true,
+                settings: vec![],
+            },
+            Frame::PushPromise {
+                stream_id: 1,
+                promised_id: 2,
+                block: block.clone(),
+                end_headers: true,
+            },
+            Frame::Ping {
+                ack: true,
+                data: *b"12345678",
+            },
+            Frame::GoAway {
+                last_stream_id: 11,
+                error_code: 0x1,
+                debug: Bytes::from_static(b"bye"),
+            },
+            Frame::WindowUpdate {
+                stream_id: 0,
+                increment: MAX_WINDOW_SIZE,
+            },
+            Frame::Continuation {
+                stream_id: 5,
+                block,
+                end_headers: false,
+            },
+            Frame::Unknown {
+                kind: 0xfa,
+                stream_id: 13,
+            },
+        ];
+        for frame in frames {
+            round_trip(frame);
+        }
+    }
+
+    #[test]
+    fn flow_len_counts_padding() {
+        let frame = Frame::Data {
+            stream_id: 1,
+            data: Bytes::from_static(b"abc"),
+            end_stream: false,
+            pad: Some(4),
+        };
+        assert_eq!(frame.flow_len(), 8);
+        assert_eq!(Frame::Priority { stream_id: 1 }.flow_len(), 0);
+    }
+
+    #[test]
+    fn headers_with_priority_and_padding() {
+        // PADDED | PRIORITY | END_HEADERS, pad 2, 5 bytes of priority, block, padding
+        let bytes = b"\x00\x00\x0a\x01\x2c\x00\x00\x00\x01\x02\x00\x00\x00\x00\x0f\x82\x86\x00\x00";
+        let frame = decode(bytes).unwrap().unwrap();
+        assert_eq!(
+            frame,
+            Frame::Headers {
+                stream_id: 1,
+                block: Bytes::from_static(b"\x82\x86"),
+                end_stream: false,
+                end_headers: true,
+            }
+        );
+    }
+
+    #[test]
+    fn reserved_bit_is_ignored() {
+        let frame = decode(b"\x00\x00\x04\x08\x00\x80\x00\x00\x01\x80\x00\x00\x01").unwrap();
+        assert_eq!(
+            frame,
+            Some(Frame::WindowUpdate {
+                stream_id: 1,
+                increment: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn invalid_frames() {
+        let too_long = [&[0x00, 0x40, 0x01, 0x00, 0x00, 0, 0, 0, 1][..], &[0; 16385]].concat();
+        assert!(matches!(decode(&too_long), Err(H2Error::FrameSize)));
+        let cases: [&[u8]; 9] = [
+            // DATA without a stream
+            b"\x00\x00\x01\x00\x00\x00\x00\x00\x00a",
+            // padding longer than the payload
+            b"\x00\x00\x02\x00\x08\x00\x00\x00\x01\x05a",
+            // RST_STREAM of 3 bytes
+            b"\x00\x00\x03\x03\x00\x00\x00\x00\x01\x00\x00\x00",
+            // SETTINGS on a stream, of 5 bytes, ack with a payload
+            b"\x00\x00\x00\x04\x00\x00\x00\x00\x01",
+            b"\x00\x00\x05\x04\x00\x00\x00\x00\x00\x00\x01\x00\x00\x00",
+            b"\x00\x00\x06\x04\x01\x00\x00\x00\x00\x00\x01\x00\x00\x00\x00",
+            // PING of 7 bytes
+            b"\x00\x00\x07\x06\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00",
+            // GOAWAY on a stream
+            b"\x00\x00\x08\x07\x00\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x00",
+            // CONTINUATION without a stream
+            b"\x00\x00\x00\x09\x04\x00\x00\x00\x00",
+        ];
+        for bytes in cases {
+            assert!(decode(bytes).is_err(), "{:x?}", bytes);
+        }
+    }
+}
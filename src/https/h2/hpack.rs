@@ -0,0 +1,596 @@
+use super::huffman;
+use super::{H2Error, H2Result};
+use bytes::{BufMut, BytesMut};
+use std::collections::VecDeque;
+
+pub const DEFAULT_TABLE_SIZE: usize = 4096;
+
+// RFC 7541 Appendix A, index 1 is the first entry.
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// Headers that shouldn't end up in any compression context.
+const SENSITIVE: [&str; 3] = ["authorization", "cookie", "proxy-authorization"];
+
+// Each entry costs its name and value plus 32 octets of overhead.
+fn entry_size(name: &str, value: &str) -> usize {
+    name.len() + value.len() + 32
+}
+
+#[derive(Debug)]
+struct DynamicTable {
+    entries: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+}
+
+impl DynamicTable {
+    fn new(max_size: usize) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            size: 0,
+            max_size,
+        }
+    }
+
+    fn get(&self, index: usize) -> H2Result<(&str, &str)> {
+        if index == 0 {
+            return Err(H2Error::Compression("index 0"));
+        }
+        if index <= STATIC_TABLE.len() {
+            return Ok(STATIC_TABLE[index - 1]);
+        }
+        match self.entries.get(index - STATIC_TABLE.len() - 1) {
+            Some((n, v)) => Ok((n, v)),
+            None => Err(H2Error::Compression("index out of range")),
+        }
+    }
+
+    fn insert(&mut self, name: String, value: String) {
+        let size = entry_size(&name, &value);
+        self.size += size;
+        self.entries.push_front((name, value));
+        self.evict();
+    }
+
+    fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.max_size {
+            match self.entries.pop_back() {
+                Some((n, v)) => self.size -= entry_size(&n, &v),
+                None => break,
+            }
+        }
+    }
+
+    // Index of an exact match, or of an entry with the same name.
+    fn find(&self, name: &str, value: &str) -> (Option<usize>, Option<usize>) {
+        let mut name_match = None;
+        for (i, (n, v)) in STATIC_TABLE.iter().enumerate() {
+            if *n == name {
+                if *v == value {
+                    return (Some(i + 1), Some(i + 1));
+                }
+                name_match.get_or_insert(i + 1);
+            }
+        }
+        for (i, (n, v)) in self.entries.iter().enumerate() {
+            if n == name {
+                let index = i + STATIC_TABLE.len() + 1;
+                if v == value {
+                    return (Some(index), Some(index));
+                }
+                name_match.get_or_insert(index);
+            }
+        }
+        (None, name_match)
+    }
+}
+
+// What we advertise as SETTINGS_MAX_HEADER_LIST_SIZE.
+pub const DEFAULT_MAX_LIST_SIZE: usize = 256 << 10;
+
+#[derive(Debug)]
+pub struct Decoder {
+    table: DynamicTable,
+    // upper bound we advertised with SETTINGS_HEADER_TABLE_SIZE
+    max_allowed: usize,
+    // decoded size of a block, counted like SETTINGS_MAX_HEADER_LIST_SIZE
+    max_list_size: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self {
+            table: DynamicTable::new(DEFAULT_TABLE_SIZE),
+            max_allowed: DEFAULT_TABLE_SIZE,
+            max_list_size: DEFAULT_MAX_LIST_SIZE,
+        }
+    }
+
+    pub fn max_list_size(&self) -> usize {
+        self.max_list_size
+    }
+
+    pub fn set_max_list_size(&mut self, size: usize) {
+        self.max_list_size = size;
+    }
+
+    // Fails with HeaderListTooLarge when the fields add up to more than max_list_size,
+    // name and value length plus 32 each. The whole block is still decoded, so the
+    // dynamic table stays in sync with the peer's.
+    pub fn decode(&mut self, mut block: &[u8]) -> H2Result<Vec<(String, String)>> {
+        let mut headers = vec![];
+        let mut size = 0;
+        let mut first = true;
+
+        while let Some(&b) = block.first() {
+            if b & 0x80 != 0 {
+                // indexed field
+                let index = decode_int(&mut block, 7)?;
+                let (n, v) = self.table.get(index)?;
+                size += n.len() + v.len() + 32;
+                if size <= self.max_list_size {
+                    headers.push((n.to_string(), v.to_string()));
+                }
+            } else if b & 0x40 != 0 {
+                // literal with incremental indexing
+                let (n, v) = self.decode_literal(&mut block, 6)?;
+                size += n.len() + v.len() + 32;
+                self.table.insert(n.clone(), v.clone());
+                if size <= self.max_list_size {
+                    headers.push((n, v));
+                }
+            } else if b & 0x20 != 0 {
+                // size updates are only allowed at the start of a block
+                if !first {
+                    return Err(H2Error::Compression("late table size update"));
+                }
+                let size = decode_int(&mut block, 5)?;
+                if size > self.max_allowed {
+                    return Err(H2Error::Compression("table size over the limit"));
+                }
+                self.table.set_max_size(size);
+                continue;
+            } else {
+                // literal without indexing or never indexed
+                let (n, v) = self.decode_literal(&mut block, 4)?;
+                size += n.len() + v.len() + 32;
+                if size <= self.max_list_size {
+                    headers.push((n, v));
+                }
+            }
+            first = false;
+        }
+
+        match size > self.max_list_size {
+            true => Err(H2Error::HeaderListTooLarge),
+            false => Ok(headers),
+        }
+    }
+
+    fn decode_literal(&self, block: &mut &[u8], prefix: u8) -> H2Result<(String, String)> {
+        let name = match decode_int(block, prefix)? {
+            0 => decode_string(block)?,
+            index => self.table.get(index)?.0.to_string(),
+        };
+        let value = decode_string(block)?;
+        Ok((name, value))
+    }
+}
+
+#[derive(Debug)]
+pub struct Encoder {
+    table: DynamicTable,
+    // smallest size set since the last block, has to be signalled first
+    pending_update: Option<usize>,
+}
+
+impl Default for Encoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self {
+            table: DynamicTable::new(DEFAULT_TABLE_SIZE),
+            pending_update: None,
+        }
+    }
+
+    // Applies the peer's SETTINGS_HEADER_TABLE_SIZE, capped to the default.
+    pub fn set_max_size(&mut self, size: usize) {
+        let size = size.min(DEFAULT_TABLE_SIZE);
+        if size == self.table.max_size {
+            return;
+        }
+        self.pending_update = Some(self.pending_update.map_or(size, |p| p.min(size)));
+        self.table.set_max_size(size);
+    }
+
+    pub fn encode<'h, I>(&mut self, headers: I, dst: &mut BytesMut)
+    where
+        I: IntoIterator<Item = (&'h str, &'h str)>,
+    {
+        if let Some(size) = self.pending_update.take() {
+            if size != self.table.max_size {
+                encode_int(dst, size, 5, 0x20);
+            }
+            encode_int(dst, self.table.max_size, 5, 0x20);
+        }
+
+        for (name, value) in headers {
+            let (exact, name_index) = self.table.find(name, value);
+            if let Some(index) = exact {
+                encode_int(dst, index, 7, 0x80);
+                continue;
+            }
+
+            if SENSITIVE.contains(&name) {
+                // never indexed
+                encode_int(dst, name_index.unwrap_or(0), 4, 0x10);
+            } else {
+                encode_int(dst, name_index.unwrap_or(0), 6, 0x40);
+                self.table.insert(name.to_string(), value.to_string());
+            }
+            if name_index.is_none() {
+                encode_string(dst, name.as_bytes());
+            }
+            encode_string(dst, value.as_bytes());
+        }
+    }
+}
+
+// Integer representation with an N-bit prefix, RFC 7541 section 5.1.
+fn decode_int(block: &mut &[u8], prefix: u8) -> H2Result<usize> {
+    let max = (1usize << prefix) - 1;
+    let Some((&first, rest)) = block.split_first() else {
+        return Err(H2Error::Compression("truncated integer"));
+    };
+    *block = rest;
+
+    let mut value = first as usize & max;
+    if value < max {
+        return Ok(value);
+    }
+
+    let mut shift = 0;
+    loop {
+        let Some((&b, rest)) = block.split_first() else {
+            return Err(H2Error::Compression("truncated integer"));
+        };
+        *block = rest;
+        if shift > 28 {
+            return Err(H2Error::Compression("integer overflow"));
+        }
+        value += ((b & 0x7f) as usize) << shift;
+        shift += 7;
+        if b & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn encode_int(dst: &mut BytesMut, value: usize, prefix: u8, flags: u8) {
+    let max = (1usize << prefix) - 1;
+    if value < max {
+        dst.put_u8(flags | value as u8);
+        return;
+    }
+    dst.put_u8(flags | max as u8);
+    let mut rest = value - max;
+    while rest >= 0x80 {
+        dst.put_u8((rest & 0x7f) as u8 | 0x80);
+        rest >>= 7;
+    }
+    dst.put_u8(rest as u8);
+}
+
+fn decode_string(block: &mut &[u8]) -> H2Result<String> {
+    let huffman = block.first().is_some_and(|b| b & 0x80 != 0);
+    let len = decode_int(block, 7)?;
+    if block.len() < len {
+        return Err(H2Error::Compression("truncated string"));
+    }
+    let (raw, rest) = block.split_at(len);
+    *block = rest;
+
+    let bytes = match huffman {
+        true => huffman::decode(raw)?,
+        false => raw.to_vec(),
+    };
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+// Huffman coded whenever that is shorter.
+fn encode_string(dst: &mut BytesMut, s: &[u8]) {
+    let len = huffman::encoded_len(s);
+    if len < s.len() {
+        let mut buf = Vec::with_capacity(len);
+        huffman::encode(s, &mut buf);
+        encode_int(dst, buf.len(), 7, 0x80);
+        dst.extend_from_slice(&buf);
+    } else {
+        encode_int(dst, s.len(), 7, 0);
+        dst.extend_from_slice(s);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn owned(headers: &[(&str, &str)]) -> Vec<(String, String)> {
+        headers
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+
+    const REQUEST_1: [(&str, &str); 4] = [
+        (":method", "GET"),
+        (":scheme", "http"),
+        (":path", "/"),
+        (":authority", "www.example.com"),
+    ];
+    const REQUEST_2: [(&str, &str); 5] = [
+        (":method", "GET"),
+        (":scheme", "http"),
+        (":path", "/"),
+        (":authority", "www.example.com"),
+        ("cache-control", "no-cache"),
+    ];
+    const REQUEST_3: [(&str, &str); 5] = [
+        (":method", "GET"),
+        (":scheme", "https"),
+        (":path", "/index.html"),
+        (":authority", "www.example.com"),
+        ("custom-key", "custom-value"),
+    ];
+
+    // RFC 7541 C.1
+    #[test]
+    fn integers() {
+        let mut dst = BytesMut::new();
+        encode_int(&mut dst, 10, 5, 0);
+        encode_int(&mut dst, 1337, 5, 0);
+        encode_int(&mut dst, 42, 8, 0);
+        assert_eq!(&dst[..], [0x0a, 0x1f, 0x9a, 0x0a, 0x2a]);
+
+        let mut block = &dst[..];
+        assert_eq!(decode_int(&mut block, 5).unwrap(), 10);
+        assert_eq!(decode_int(&mut block, 5).unwrap(), 1337);
+        assert_eq!(decode_int(&mut block, 8).unwrap(), 42);
+        assert!(block.is_empty());
+        assert!(decode_int(&mut &[0x1f, 0x9a][..], 5).is_err());
+    }
+
+    // RFC 7541 C.3, requests without Huffman coding
+    #[test]
+    fn decode_requests() {
+        let mut d = Decoder::new();
+        let block = hex("8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d");
+        assert_eq!(d.decode(&block).unwrap(), owned(&REQUEST_1));
+        let block = hex("8286 84be 5808 6e6f 2d63 6163 6865");
+        assert_eq!(d.decode(&block).unwrap(), owned(&REQUEST_2));
+        let block = hex("8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65");
+        assert_eq!(d.decode(&block).unwrap(), owned(&REQUEST_3));
+        assert_eq!(d.table.size, 164);
+    }
+
+    // RFC 7541 C.4, requests with Huffman coding, both ways
+    #[test]
+    fn encode_requests() {
+        let blocks = [
+            hex("8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff"),
+            hex("8286 84be 5886 a8eb 1064 9cbf"),
+            hex("8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf"),
+        ];
+        let requests: [&[(&str, &str)]; 3] = [&REQUEST_1, &REQUEST_2, &REQUEST_3];
+
+        let mut e = Encoder::new();
+        let mut d = Decoder::new();
+        for (headers, expected) in requests.iter().zip(blocks) {
+            let mut dst = BytesMut::new();
+            e.encode(headers.iter().copied(), &mut dst);
+            assert_eq!(&dst[..], &expected[..]);
+            assert_eq!(d.decode(&dst).unwrap(), owned(headers));
+        }
+        assert_eq!(e.table.size, 164);
+        assert_eq!(d.table.size, 164);
+    }
+
+    // RFC 7541 C.6, responses with Huffman coding and a 256 octet table, entries get evicted
+    #[test]
+    fn decode_responses_with_eviction() {
+        let mut d = Decoder::new();
+        d.table.set_max_size(256);
+
+        let block = hex(
+            "4882 6402 5885 aec3 771a 4b61 96d0 7abe 9410 54d4 44a8 2005 9504 0b81 66e0 82a6
+             2d1b ff6e 919d 29ad 1718 63c7 8f0b 97c8 e9ae 82ae 43d3",
+        );
+        let first = [
+            (":status", "302"),
+            ("cache-control", "private"),
+            ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+            ("location", "https://www.example.com"),
+        ];
+        assert_eq!(d.decode(&block).unwrap(), owned(&first));
+        assert_eq!(d.table.size, 222);
+
+        let block = hex("4883 640e ffc1 c0bf");
+        let second = [
+            (":status", "307"),
+            ("cache-control", "private"),
+            ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+            ("location", "https://www.example.com"),
+        ];
+        assert_eq!(d.decode(&block).unwrap(), owned(&second));
+        assert_eq!(d.table.size, 222);
+
+        let block = hex(
+            "88c1 6196 d07a be94 1054 d444 a820 0595 040b 8166 e084 a62d 1bff c05a 839b d9ab
+             77ad 94e7 821d d7f2 e6c7 b335 dfdf cd5b 3960 d5af 2708 7f36 72c1 ab27 0fb5 291f
+             9587 3160 65c0 03ed 4ee5 b106 3d50 07",
+        );
+        let third = [
+            (":status", "200"),
+            ("cache-control", "private"),
+            ("date", "Mon, 21 Oct 2013 20:13:22 GMT"),
+            ("location", "https://www.example.com"),
+            ("content-encoding", "gzip"),
+            (
+                "set-cookie",
+                "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1",
+            ),
+        ];
+        assert_eq!(d.decode(&block).unwrap(), owned(&third));
+        assert_eq!(d.table.size, 215);
+    }
+
+    #[test]
+    fn sensitive_headers_are_never_indexed() {
+        let mut e = Encoder::new();
+        let mut dst = BytesMut::new();
+        e.encode([("cookie", "a=b")], &mut dst);
+        assert_eq!(dst[0] & 0xf0, 0x10);
+        assert!(e.table.entries.is_empty());
+        assert_eq!(
+            Decoder::new().decode(&dst).unwrap(),
+            owned(&[("cookie", "a=b")])
+        );
+    }
+
+    #[test]
+    fn table_size_updates() {
+        let mut e = Encoder::new();
+        let mut d = Decoder::new();
+        e.set_max_size(0);
+        e.set_max_size(100);
+        let mut dst = BytesMut::new();
+        e.encode([("x-a", "1")], &mut dst);
+        // the smallest size comes first, then the current one
+        assert_eq!(&dst[..2], [0x20, 0x3f]);
+        assert_eq!(d.decode(&dst).unwrap(), owned(&[("x-a", "1")]));
+        assert_eq!(d.table.max_size, 100);
+
+        // 4097 is over what we allow, and updates have to come before the first field
+        assert!(Decoder::new().decode(&hex("3fe2 1f")).is_err());
+        assert!(Decoder::new().decode(&hex("8220")).is_err());
+    }
+
+    #[test]
+    fn invalid_indexes() {
+        assert!(Decoder::new().decode(&[0x80]).is_err());
+        assert!(Decoder::new().decode(&[0xbe]).is_err());
+    }
+
+    #[test]
+    fn header_list_size_limit() {
+        let mut enc = Encoder::new();
+        let mut dec = Decoder::new();
+        // 3 + 5 + 32 for each field
+        dec.set_max_list_size(80);
+
+        let mut block = BytesMut::new();
+        enc.encode([("a-b", "value"), ("c-d", "value")], &mut block);
+        assert_eq!(dec.decode(&block).unwrap().len(), 2);
+
+        let mut block = BytesMut::new();
+        enc.encode(
+            [("a-b", "value"), ("c-d", "value"), ("e-f", "value")],
+            &mut block,
+        );
+        assert!(matches!(
+            dec.decode(&block),
+            Err(H2Error::HeaderListTooLarge)
+        ));
+        // the failed block was still added to the table, so the next one decodes
+        let mut block = BytesMut::new();
+        enc.encode([("e-f", "value")], &mut block);
+        assert_eq!(block.len(), 1);
+        assert_eq!(dec.decode(&block).unwrap(), owned(&[("e-f", "value")]));
+    }
+}
@@ -0,0 +1,88 @@
+pub mod connection;
+pub mod frame;
+pub mod hpack;
+pub mod huffman;
+
+use std::error;
+use std::fmt;
+use std::io;
+
+// Error codes, RFC 9113 section 7.
+pub const NO_ERROR: u32 = 0x0;
+pub const PROTOCOL_ERROR: u32 = 0x1;
+pub const INTERNAL_ERROR: u32 = 0x2;
+pub const FLOW_CONTROL_ERROR: u32 = 0x3;
+pub const STREAM_CLOSED: u32 = 0x5;
+pub const FRAME_SIZE_ERROR: u32 = 0x6;
+pub const REFUSED_STREAM: u32 = 0x7;
+pub const CANCEL: u32 = 0x8;
+pub const COMPRESSION_ERROR: u32 = 0x9;
+
+#[derive(Debug)]
+pub enum H2Error {
+    Protocol(&'static str),
+    FrameSize,
+    FlowControl,
+    Compression(&'static str),
+    // response headers over our SETTINGS_MAX_HEADER_LIST_SIZE, only fails their stream
+    HeaderListTooLarge,
+    StreamReset(u32),
+    GoAway(u32),
+    Io(io::Error),
+}
+
+impl H2Error {
+    // Code sent to the peer when this error tears down the connection.
+    pub fn code(&self) -> u32 {
+        match *self {
+            H2Error::Protocol(..) => PROTOCOL_ERROR,
+            H2Error::FrameSize => FRAME_SIZE_ERROR,
+            H2Error::FlowControl => FLOW_CONTROL_ERROR,
+            H2Error::Compression(..) => COMPRESSION_ERROR,
+            H2Error::HeaderListTooLarge => CANCEL,
+            H2Error::StreamReset(c) | H2Error::GoAway(c) => c,
+            H2Error::Io(..) => INTERNAL_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for H2Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            H2Error::Protocol(msg) => write!(f, "http2 protocol error: {}", msg),
+            H2Error::FrameSize => write!(f, "http2 frame too large"),
+            H2Error::FlowControl => write!(f, "http2 flow control window exceeded"),
+            H2Error::Compression(msg) => write!(f, "hpack error: {}", msg),
+            H2Error::HeaderListTooLarge => write!(f, "http2 response headers too large"),
+            H2Error::StreamReset(code) => write!(f, "stream reset by peer, code: {:#x}", code),
+            H2Error::GoAway(code) => write!(f, "connection closed by peer, code: {:#x}", code),
+            H2Error::Io(ref e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl error::Error for H2Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            H2Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for H2Error {
+    fn from(e: io::Error) -> Self {
+        H2Error::Io(e)
+    }
+}
+
+impl From<H2Error> for io::Error {
+    fn from(e: H2Error) -> Self {
+        match e {
+            H2Error::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+pub type H2Result<T> = Result<T, H2Error>;
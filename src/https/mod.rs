@@ -1,5 +1,19 @@
+pub mod chunked;
 pub mod client;
+pub mod connect_to;
+pub mod cookie;
+pub mod decompress;
+pub mod h2;
+pub mod parser;
+pub mod percent;
 pub mod persistent_client;
+pub mod pool;
+pub mod query;
+pub mod redirect;
 pub mod request;
+pub mod resolve;
 pub mod response;
+pub mod timeout;
+pub mod transport;
 pub mod url;
+pub mod websocket;
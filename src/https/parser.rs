@@ -0,0 +1,635 @@
+use super::chunked::{ChunkEvent, ChunkedDecoder};
+use super::response::{HttpResponseError, HttpResult};
+use bytes::{Bytes, BytesMut};
+use log::debug;
+use std::str;
+
+// Upper bound for a single status/header line, anything bigger is rejected.
+const MAX_LINE_LEN: usize = 64 * 1024;
+// Upper bounds for the status line and headers together, and for the number of headers.
+const MAX_HEAD_LEN: usize = 256 * 1024;
+const MAX_HEADERS: usize = 500;
+
+// Events emitted by the parser, in the order they appear in the response.
+#[derive(Debug, PartialEq)]
+pub enum ParseEvent {
+    Status {
+        version: u8,
+        code: u16,
+        reason: String,
+    },
+    Header {
+        name: String,
+        value: String,
+    },
+    HeadersComplete,
+    ChunkExtension {
+        name: String,
+        value: Option<String>,
+    },
+    Body(Bytes),
+    Trailer {
+        name: String,
+        value: String,
+    },
+    Done,
+    NeedMore,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    StatusLine,
+    Headers,
+    Length(usize),
+    Close,
+    Chunked,
+    Done,
+}
+
+// Push based HTTP/1.x response parser.
+// Bytes are handed to it with feed() as they arrive,
+// next_event() then yields whatever can be parsed from them.
+#[derive(Debug)]
+pub struct ResponseParser {
+    buf: BytesMut,
+    state: State,
+    eof: bool,
+    head_request: bool,
+    interim: bool,
+    // of the head being parsed
+    head_len: usize,
+    header_count: usize,
+    status_code: u16,
+    content_length: Option<usize>,
+    chunked: bool,
+    has_transfer_encoding: bool,
+    keep_alive: bool,
+    close_delimited: bool,
+    chunked_decoder: ChunkedDecoder,
+}
+
+impl Default for ResponseParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ResponseParser {
+    pub fn new() -> Self {
+        Self {
+            buf: BytesMut::with_capacity(4096),
+            state: State::StatusLine,
+            eof: false,
+            head_request: false,
+            interim: false,
+            head_len: 0,
+            header_count: 0,
+            status_code: 0,
+            content_length: None,
+            chunked: false,
+            has_transfer_encoding: false,
+            keep_alive: false,
+            close_delimited: false,
+            chunked_decoder: ChunkedDecoder::new(),
+        }
+    }
+
+    // Responses to HEAD never have a body, no matter what the headers say.
+    pub fn set_head_request(&mut self, head: bool) -> &mut Self {
+        self.head_request = head;
+        self
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    // Marks the end of the stream, needed for bodies delimited by connection close.
+    pub fn finish(&mut self) {
+        self.eof = true;
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    pub fn is_eof(&self) -> bool {
+        self.eof
+    }
+
+    // Prepares for the next response on the same connection,
+    // bytes already buffered past the previous response are kept.
+    pub fn reset(&mut self) {
+        self.state = State::StatusLine;
+        self.interim = false;
+        self.status_code = 0;
+        self.content_length = None;
+        self.chunked = false;
+        self.has_transfer_encoding = false;
+        self.keep_alive = false;
+        self.close_delimited = false;
+        self.chunked_decoder = ChunkedDecoder::new();
+    }
+
+    // Whether the connection can carry another response after this one.
+    pub fn is_keep_alive(&self) -> bool {
+        self.keep_alive && self.is_done() && !self.close_delimited && !self.eof
+    }
+
+    // Bytes received after the end of the current response.
+    pub fn take_remaining(&mut self) -> Bytes {
+        self.buf.split().freeze()
+    }
+
+    pub fn next_event(&mut self) -> HttpResult<ParseEvent> {
+        loop {
+            match self.state {
+                State::StatusLine => {
+                    let line = match take_line(&mut self.buf)? {
+                        Some(l) => l,
+                        None => return self.need_more(),
+                    };
+                    // tolerate empty lines before the status line
+                    if line.is_empty() {
+                        continue;
+                    }
+                    let (version, code, reason) = parse_status_line(&line)?;
+                    self.head_len = line.len();
+                    self.header_count = 0;
+                    self.status_code = code;
+                    self.content_length = None;
+                    self.chunked = false;
+                    self.has_transfer_encoding = false;
+                    // persistent by default since HTTP/1.1
+                    self.keep_alive = version == 1;
+                    self.state = State::Headers;
+
+                    // interim responses are skipped, 101 ends the message though
+                    if (100..200).contains(&code) && code != 101 {
+                        debug!("Skipping interim response {}", code);
+                        self.interim = true;
+                        continue;
+                    }
+                    self.interim = false;
+                    return Ok(ParseEvent::Status {
+                        version,
+                        code,
+                        reason,
+                    });
+                }
+                State::Headers => {
+                    let line = match take_header_line(&mut self.buf)? {
+                        Some(l) => l,
+                        None => return self.need_more(),
+                    };
+                    self.count_header(line.len())?;
+                    if line.is_empty() {
+                        if self.interim {
+                            self.state = State::StatusLine;
+                            continue;
+                        }
+                        self.state = self.body_state();
+                        self.close_delimited = self.state == State::Close;
+                        return Ok(ParseEvent::HeadersComplete);
+                    }
+                    let (name, value) = parse_header(&line)?;
+                    if self.interim {
+                        continue;
+                    }
+                    self.track_framing(&name, &value)?;
+                    return Ok(ParseEvent::Header { name, value });
+                }
+                State::Length(0) => self.state = State::Done,
+                State::Length(n) => {
+                    if self.buf.is_empty() {
+                        return self.need_more();
+                    }
+                    let take = n.min(self.buf.len());
+                    self.state = State::Length(n - take);
+                    return Ok(ParseEvent::Body(self.buf.split_to(take).freeze()));
+                }
+                State::Close => {
+                    if !self.buf.is_empty() {
+                        return Ok(ParseEvent::Body(self.buf.split().freeze()));
+                    }
+                    if self.eof {
+                        self.state = State::Done;
+                        continue;
+                    }
+                    return Ok(ParseEvent::NeedMore);
+                }
+                State::Chunked => match self.chunked_decoder.decode(&mut self.buf)? {
+                    ChunkEvent::Extension { name, value } => {
+                        return Ok(ParseEvent::ChunkExtension { name, value })
+                    }
+                    ChunkEvent::Data(b) => return Ok(ParseEvent::Body(b)),
+                    ChunkEvent::Trailer { name, value } => {
+                        return Ok(ParseEvent::Trailer { name, value })
+                    }
+                    ChunkEvent::Done => self.state = State::Done,
+                    ChunkEvent::NeedMore => return self.need_more(),
+                },
+                State::Done => return Ok(ParseEvent::Done),
+            }
+        }
+    }
+
+    // Counts a header line, the empty one ending the head included.
+    fn count_header(&mut self, len: usize) -> HttpResult<()> {
+        self.head_len += len + 2;
+        if len > 0 {
+            self.header_count += 1;
+        }
+        match self.head_len > MAX_HEAD_LEN || self.header_count > MAX_HEADERS {
+            true => Err(HttpResponseError::HeadersTooLarge),
+            false => Ok(()),
+        }
+    }
+
+    fn need_more(&self) -> HttpResult<ParseEvent> {
+        if !self.eof {
+            return Ok(ParseEvent::NeedMore);
+        }
+        match self.state {
+            State::StatusLine if self.buf.is_empty() => Err(HttpResponseError::Empty),
+            _ => Err(HttpResponseError::Incomplete),
+        }
+    }
+
+    fn body_state(&self) -> State {
+        let code = self.status_code;
+        if self.head_request || code == 204 || code == 304 || (100..200).contains(&code) {
+            return State::Done;
+        }
+        if self.chunked {
+            return State::Chunked;
+        }
+        if self.has_transfer_encoding {
+            return State::Close;
+        }
+        match self.content_length {
+            Some(0) => State::Done,
+            Some(n) => State::Length(n),
+            None => State::Close,
+        }
+    }
+
+    fn track_framing(&mut self, name: &str, value: &str) -> HttpResult<()> {
+        if name.eq_ignore_ascii_case("Content-Length") {
+            let len = match value.parse::<usize>() {
+                Ok(n) => n,
+                Err(e) => return Err(HttpResponseError::ParseError(e)),
+            };
+            match self.content_length {
+                Some(prev) if prev != len => return Err(HttpResponseError::InvalidHeader),
+                _ => self.content_length = Some(len),
+            }
+        } else if name.eq_ignore_ascii_case("Connection") {
+            for token in value.split(',').map(str::trim) {
+                if token.eq_ignore_ascii_case("close") {
+                    self.keep_alive = false;
+                } else if token.eq_ignore_ascii_case("keep-alive") {
+                    self.keep_alive = true;
+                }
+            }
+        } else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+            self.has_transfer_encoding = true;
+            // chunked has to be the final coding
+            self.chunked = value
+                .rsplit(',')
+                .next()
+                .is_some_and(|c| c.trim().eq_ignore_ascii_case("chunked"));
+        }
+        Ok(())
+    }
+}
+
+// Takes a single line without the line ending, None if it isn't complete yet.
+// Only the line itself counts towards MAX_LINE_LEN, not what is buffered after it.
+pub(super) fn take_line(buf: &mut BytesMut) -> HttpResult<Option<BytesMut>> {
+    match buf.iter().position(|b| *b == b'\n') {
+        Some(pos) if pos > MAX_LINE_LEN => Err(HttpResponseError::HeadersTooLarge),
+        Some(pos) => {
+            let mut line = buf.split_to(pos + 1);
+            line.truncate(pos);
+            if line.last() == Some(&b'\r') {
+                line.truncate(pos - 1);
+            }
+            Ok(Some(line))
+        }
+        None if buf.len() > MAX_LINE_LEN => Err(HttpResponseError::HeadersTooLarge),
+        None => Ok(None),
+    }
+}
+
+// Like take_line(), but joins obsolete folded lines into one.
+fn take_header_line(buf: &mut BytesMut) -> HttpResult<Option<BytesMut>> {
+    let mut end = 0;
+    loop {
+        let pos = match buf[end..].iter().position(|b| *b == b'\n') {
+            Some(p) if p > MAX_LINE_LEN => return Err(HttpResponseError::HeadersTooLarge),
+            Some(p) => end + p,
+            None if buf.len() - end > MAX_LINE_LEN => {
+                return Err(HttpResponseError::HeadersTooLarge)
+            }
+            None => return Ok(None),
+        };
+        // the empty line ending the headers can't be folded
+        if pos == 0 || (pos == 1 && buf[0] == b'\r') {
+            return take_line(buf);
+        }
+        match buf.get(pos + 1) {
+            Some(b' ') | Some(b'\t') => end = pos + 1,
+            Some(_) => break,
+            None => return Ok(None),
+        }
+    }
+
+    let Some(mut line) = take_line(buf)? else {
+        return Ok(None);
+    };
+    while matches!(buf.first(), Some(b' ') | Some(b'\t')) {
+        if let Some(cont) = take_line(buf)? {
+            line.extend_from_slice(b" ");
+            line.extend_from_slice(cont.trim_ascii());
+        }
+    }
+    Ok(Some(line))
+}
+
+fn parse_status_line(line: &[u8]) -> HttpResult<(u8, u16, String)> {
+    let line = match str::from_utf8(line) {
+        Ok(o) => o,
+        Err(e) => return Err(HttpResponseError::ParseStrError(e)),
+    };
+    let mut parts = line.splitn(3, ' ');
+
+    let version = match parts.next() {
+        Some("HTTP/1.1") => 1,
+        Some("HTTP/1.0") => 0,
+        _ => return Err(HttpResponseError::InvalidStatusLine),
+    };
+    let code = match parts.next() {
+        Some(c) if c.len() == 3 && c.bytes().all(|b| b.is_ascii_digit()) => {
+            match c.parse::<u16>() {
+                Ok(n) => n,
+                Err(e) => return Err(HttpResponseError::ParseError(e)),
+            }
+        }
+        _ => return Err(HttpResponseError::InvalidStatusLine),
+    };
+    let reason = parts.next().unwrap_or("").to_string();
+
+    Ok((version, code, reason))
+}
+
+pub(super) fn parse_header(line: &[u8]) -> HttpResult<(String, String)> {
+    let line = match str::from_utf8(line) {
+        Ok(o) => o,
+        Err(e) => return Err(HttpResponseError::ParseStrError(e)),
+    };
+    let (name, value) = match line.split_once(':') {
+        Some(kv) => kv,
+        None => return Err(HttpResponseError::InvalidHeader),
+    };
+    // no whitespace allowed in or around the field name
+    if name.is_empty() || name.bytes().any(|b| b.is_ascii_whitespace()) {
+        return Err(HttpResponseError::InvalidHeader);
+    }
+
+    Ok((name.to_string(), value.trim().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every event up to Done or NeedMore, body pieces joined into one.
+    fn parse(p: &mut ResponseParser) -> (Vec<ParseEvent>, Vec<u8>) {
+        let mut events = Vec::new();
+        let mut body = Vec::new();
+        loop {
+            match p.next_event().unwrap() {
+                ParseEvent::Body(b) => body.extend_from_slice(&b),
+                e @ (ParseEvent::Done | ParseEvent::NeedMore) => {
+                    events.push(e);
+                    return (events, body);
+                }
+                e => events.push(e),
+            }
+        }
+    }
+
+    fn header(name: &str, value: &str) -> ParseEvent {
+        ParseEvent::Header {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn content_length() {
+        let mut p = ResponseParser::new();
+        p.feed(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello");
+        let (events, body) = parse(&mut p);
+        assert_eq!(
+            events,
+            [
+                ParseEvent::Status {
+                    version: 1,
+                    code: 200,
+                    reason: "OK".to_string(),
+                },
+                header("Content-Length", "5"),
+                ParseEvent::HeadersComplete,
+                ParseEvent::Done,
+            ]
+        );
+        assert_eq!(body, b"hello");
+        assert!(p.is_keep_alive());
+    }
+
+    #[test]
+    fn chunked_with_trailers() {
+        let mut p = ResponseParser::new();
+        p.feed(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip, chunked\r\n\r\n");
+        p.feed(b"3\r\nabc\r\n0\r\nX-Checksum: 42\r\n\r\n");
+        let (events, body) = parse(&mut p);
+        assert_eq!(body, b"abc");
+        assert!(events.contains(&ParseEvent::Trailer {
+            name: "X-Checksum".to_string(),
+            value: "42".to_string(),
+        }));
+        assert_eq!(events.last(), Some(&ParseEvent::Done));
+    }
+
+    #[test]
+    fn fed_one_byte_at_a_time() {
+        let raw = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nWiki\r\n0\r\n\r\n";
+        let mut p = ResponseParser::new();
+        let mut body = Vec::new();
+        for b in raw {
+            p.feed(&[*b]);
+            body.extend(parse(&mut p).1);
+        }
+        assert_eq!(body, b"Wiki");
+        assert!(p.is_done());
+    }
+
+    #[test]
+    fn pipelined_responses() {
+        let mut p = ResponseParser::new();
+        p.feed(b"HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\naHTTP/1.1 204 No Content\r\n\r\n");
+        assert_eq!(parse(&mut p).1, b"a");
+        p.reset();
+        let (events, body) = parse(&mut p);
+        assert!(matches!(events[0], ParseEvent::Status { code: 204, .. }));
+        assert!(body.is_empty());
+        assert!(p.take_remaining().is_empty());
+    }
+
+    #[test]
+    fn interim_responses_are_skipped() {
+        let mut p = ResponseParser::new();
+        p.feed(b"HTTP/1.1 100 Continue\r\nX-Interim: 1\r\n\r\n");
+        p.feed(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        let (events, _) = parse(&mut p);
+        assert!(matches!(events[0], ParseEvent::Status { code: 200, .. }));
+        assert!(!events.contains(&header("X-Interim", "1")));
+    }
+
+    #[test]
+    fn head_response_has_no_body() {
+        let mut p = ResponseParser::new();
+        p.set_head_request(true);
+        p.feed(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n");
+        let (events, body) = parse(&mut p);
+        assert_eq!(events.last(), Some(&ParseEvent::Done));
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn close_delimited() {
+        let mut p = ResponseParser::new();
+        p.feed(b"HTTP/1.0 200 OK\r\n\r\nuntil");
+        let (events, body) = parse(&mut p);
+        assert_eq!(events.last(), Some(&ParseEvent::NeedMore));
+        p.feed(b" close");
+        p.finish();
+        let (events, rest) = parse(&mut p);
+        assert_eq!(events.last(), Some(&ParseEvent::Done));
+        assert_eq!([body, rest].concat(), b"until close");
+        assert!(!p.is_keep_alive());
+    }
+
+    #[test]
+    fn folded_headers_are_joined() {
+        let mut p = ResponseParser::new();
+        p.feed(
+            b"HTTP/1.1 200 OK\r\nX-Long: one\r\n  two\r\n\ttwo more\r\nContent-Length: 0\r\n\r\n",
+        );
+        let (events, _) = parse(&mut p);
+        assert!(events.contains(&header("X-Long", "one two two more")));
+    }
+
+    #[test]
+    fn conflicting_content_lengths() {
+        let mut p = ResponseParser::new();
+        p.feed(b"HTTP/1.1 200 OK\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n");
+        let mut res = p.next_event();
+        while matches!(
+            res,
+            Ok(ParseEvent::Status { .. } | ParseEvent::Header { .. })
+        ) {
+            res = p.next_event();
+        }
+        assert!(matches!(res, Err(HttpResponseError::InvalidHeader)));
+    }
+
+    #[test]
+    fn truncated_response() {
+        let mut p = ResponseParser::new();
+        p.feed(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort");
+        parse(&mut p);
+        p.finish();
+        assert!(matches!(p.next_event(), Err(HttpResponseError::Incomplete)));
+    }
+
+    #[test]
+    fn line_length_is_per_line() {
+        // lots of buffered body after a short head isn't a long line
+        let mut p = ResponseParser::new();
+        let body = vec![b'x'; MAX_LINE_LEN * 2];
+        p.feed(format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len()).as_bytes());
+        p.feed(&body);
+        assert_eq!(parse(&mut p).1.len(), body.len());
+
+        let mut p = ResponseParser::new();
+        p.feed(b"HTTP/1.1 200 OK\r\nX-Big: ");
+        p.feed(&vec![b'a'; MAX_LINE_LEN + 1]);
+        p.feed(b"\r\n\r\n");
+        let mut res = p.next_event();
+        while matches!(res, Ok(ParseEvent::Status { .. })) {
+            res = p.next_event();
+        }
+        assert!(matches!(res, Err(HttpResponseError::HeadersTooLarge)));
+    }
+
+    // Result of the first event that isn't the status line or a header.
+    fn after_headers(p: &mut ResponseParser) -> HttpResult<ParseEvent> {
+        loop {
+            match p.next_event() {
+                Ok(ParseEvent::Status { .. }) | Ok(ParseEvent::Header { .. }) => {}
+                other => return other,
+            }
+        }
+    }
+
+    #[test]
+    fn head_limits() {
+        // many lines under the line limit still add up
+        let line = format!("X-Big: {}\r\n", "a".repeat(MAX_LINE_LEN - 16));
+        let mut p = ResponseParser::new();
+        p.feed(b"HTTP/1.1 200 OK\r\n");
+        for _ in 0..MAX_HEAD_LEN / line.len() + 1 {
+            p.feed(line.as_bytes());
+        }
+        p.feed(b"\r\n");
+        assert!(matches!(
+            after_headers(&mut p),
+            Err(HttpResponseError::HeadersTooLarge)
+        ));
+
+        let head = |count: usize| {
+            let mut p = ResponseParser::new();
+            p.feed(b"HTTP/1.1 200 OK\r\n");
+            p.feed("X: 1\r\n".repeat(count).as_bytes());
+            p.feed(b"Content-Length: 0\r\n\r\n");
+            after_headers(&mut p)
+        };
+        assert_eq!(head(MAX_HEADERS - 1).unwrap(), ParseEvent::HeadersComplete);
+        assert!(matches!(
+            head(MAX_HEADERS),
+            Err(HttpResponseError::HeadersTooLarge)
+        ));
+
+        // the limits are per response
+        let mut p = ResponseParser::new();
+        for _ in 0..3 {
+            p.feed(b"HTTP/1.1 100 Continue\r\n");
+            p.feed("X: 1\r\n".repeat(MAX_HEADERS / 2).as_bytes());
+            p.feed(b"\r\n");
+        }
+        p.feed(b"HTTP/1.1 204 No Content\r\n\r\n");
+        assert_eq!(after_headers(&mut p).unwrap(), ParseEvent::HeadersComplete);
+    }
+
+    #[test]
+    fn take_line_endings() {
+        let mut buf = BytesMut::from(&b"crlf\r\nlf\npartial"[..]);
+        assert_eq!(&take_line(&mut buf).unwrap().unwrap()[..], b"crlf");
+        assert_eq!(&take_line(&mut buf).unwrap().unwrap()[..], b"lf");
+        assert!(take_line(&mut buf).unwrap().is_none());
+        assert_eq!(&buf[..], b"partial");
+    }
+}
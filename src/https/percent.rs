@@ -0,0 +1,113 @@
+use std::borrow::Cow;
+
+const HEX: &[u8; 16] = b"0123456789ABCDEF";
+
+// Encodes everything except the unreserved characters of RFC 3986,
+// safe for any component of a url.
+pub fn encode(s: &str) -> Cow<'_, str> {
+    encode_with(
+        s,
+        |b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~'),
+        false,
+    )
+}
+
+// application/x-www-form-urlencoded, spaces turn into "+".
+pub fn encode_form(s: &str) -> Cow<'_, str> {
+    encode_with(
+        s,
+        |b| b.is_ascii_alphanumeric() || matches!(b, b'*' | b'-' | b'.' | b'_'),
+        true,
+    )
+}
+
+// Decodes %XX sequences, invalid ones are kept as they are.
+// Bytes that don't make valid UTF-8 are replaced.
+pub fn decode(s: &str) -> Cow<'_, str> {
+    if !s.contains('%') {
+        return Cow::Borrowed(s);
+    }
+    let b = s.as_bytes();
+    let mut out = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        match (b[i], hex_value(b.get(i + 1)), hex_value(b.get(i + 2))) {
+            (b'%', Some(h), Some(l)) => {
+                out.push(h << 4 | l);
+                i += 3;
+            }
+            (c, ..) => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+    Cow::Owned(String::from_utf8_lossy(&out).into_owned())
+}
+
+// Like decode(), with "+" standing for a space.
+pub fn decode_form(s: &str) -> Cow<'_, str> {
+    match s.contains('+') {
+        true => Cow::Owned(decode(&s.replace('+', " ")).into_owned()),
+        false => decode(s),
+    }
+}
+
+fn encode_with(s: &str, keep: fn(u8) -> bool, plus: bool) -> Cow<'_, str> {
+    if s.bytes().all(keep) {
+        return Cow::Borrowed(s);
+    }
+    let mut out = String::with_capacity(s.len() * 3);
+    for b in s.bytes() {
+        if keep(b) {
+            out.push(b as char);
+        } else if plus && b == b' ' {
+            out.push('+');
+        } else {
+            out.push('%');
+            out.push(HEX[(b >> 4) as usize] as char);
+            out.push(HEX[(b & 0xf) as usize] as char);
+        }
+    }
+    Cow::Owned(out)
+}
+
+fn hex_value(b: Option<&u8>) -> Option<u8> {
+    match *b? {
+        c @ b'0'..=b'9' => Some(c - b'0'),
+        c @ b'a'..=b'f' => Some(c - b'a' + 10),
+        c @ b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoding() {
+        assert!(matches!(encode("a-b.c_d~e"), Cow::Borrowed(_)));
+        assert_eq!(encode("a b/c?é"), "a%20b%2Fc%3F%C3%A9");
+        assert_eq!(encode_form("a b*~"), "a+b*%7E");
+        assert_eq!(encode(""), "");
+    }
+
+    #[test]
+    fn decoding() {
+        assert_eq!(decode("a%20b%2fc%C3%A9"), "a b/cé");
+        assert_eq!(decode_form("a+b%2B"), "a b+");
+        assert_eq!(decode("a+b"), "a+b");
+        // invalid escapes stay, bytes that aren't UTF-8 are replaced
+        assert_eq!(decode("%zz%4%"), "%zz%4%");
+        assert_eq!(decode("%ff"), "\u{fffd}");
+    }
+
+    #[test]
+    fn round_trip() {
+        for s in ["", "plain", "a b&c=d", "100% /?#[]@", "é 中文 🦀", "+*~'\""] {
+            assert_eq!(decode(&encode(s)), s);
+            assert_eq!(decode_form(&encode_form(s)), s);
+        }
+    }
+}
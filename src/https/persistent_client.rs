@@ -1,68 +1,471 @@
-use super::client::Methods;
+use super::client::{HttpsClient, Methods};
+use super::connect_to::ConnectTo;
+use super::cookie::CookieJar;
+use super::decompress;
+use super::h2::connection::H2Connection;
+use super::parser::ResponseParser;
+use super::pool::PoolKey;
+use super::redirect;
+use super::redirect::RedirectPolicy;
 use super::request::RequestBuilder;
+use super::resolve::{Resolver, SystemResolver};
 use super::response::Response;
+use super::timeout::Timeouts;
+use super::transport::Transport;
 use super::url::Url;
-use crate::tls::tls_stream::TlsStream;
+use crate::tls::config::TlsConfig;
+use crate::tls::tls_stream::{shared_h2_config, ALPN_H2, ALPN_HTTP11};
+use log::info;
+use rustls::ClientConfig;
 use std::collections::HashMap;
-use std::io::{Error, ErrorKind, Read, Write};
+use std::io::{Error, ErrorKind, Write};
+use std::sync::Arc;
+use std::time::Instant;
 
 type HeaderMap<'p> = HashMap<&'p str, &'p str>;
 type OptHeaders<'p> = Option<HeaderMap<'p>>;
 type TLSResult<T> = Result<T, Error>;
 
+// Protocol spoken on the connection, picked through ALPN.
+// Without TLS there is no ALPN, so plain connections are always HTTP/1.1.
+enum Connection {
+    Http1 {
+        io: Transport,
+        parser: ResponseParser,
+    },
+    Http2(H2Connection<Transport>),
+}
+
+impl Connection {
+    // The kept connection, opened first if there is none yet.
+    // Offers h2, falls back to HTTP/1.1 if the server doesn't pick it.
+    fn get<'c>(
+        conn: &'c mut Option<Self>,
+        url: &Url,
+        cfg: &Arc<ClientConfig>,
+        connect_to: &ConnectTo,
+        resolver: &dyn Resolver,
+        timeouts: &Timeouts,
+        deadline: Option<Instant>,
+    ) -> TLSResult<&'c mut Self> {
+        if let Some(c) = conn {
+            return Ok(c);
+        }
+        let mut io = Transport::connect(url, Some(cfg), connect_to, resolver, timeouts, deadline)?;
+        io.complete_handshake()?;
+        let c = match io.alpn_protocol() {
+            Some(ALPN_H2) => {
+                info!("Using HTTP/2 for {}", url.domain());
+                Connection::Http2(H2Connection::handshake(io)?)
+            }
+            _ => Connection::Http1 {
+                io,
+                parser: ResponseParser::new(),
+            },
+        };
+        Ok(conn.insert(c))
+    }
+
+    fn stream(&mut self) -> &mut Transport {
+        match self {
+            Connection::Http1 { io, .. } => io,
+            Connection::Http2(h2) => h2.get_mut(),
+        }
+    }
+
+    // False once the server closed or is closing the connection.
+    fn is_reusable(&self) -> bool {
+        match self {
+            Connection::Http1 { parser, .. } => parser.is_keep_alive(),
+            Connection::Http2(h2) => !h2.is_closed(),
+        }
+    }
+
+    fn send(
+        &mut self,
+        m: Methods,
+        url: &Url,
+        headers: &HeaderMap,
+        jar: Option<&Arc<CookieJar>>,
+        content: Option<Vec<u8>>,
+        cross_site: bool,
+    ) -> TLSResult<Response> {
+        match self {
+            Connection::Http1 { io, parser } => {
+                let host = url.host_header();
+                let mut req = RequestBuilder::new();
+                parser.set_head_request(matches!(m, Methods::HEAD));
+                req.http_method(m)
+                    .headers(headers)
+                    .route(url.route())
+                    .host(&host);
+
+                if let Some(c) = content {
+                    req.content(c);
+                };
+                if let Some(jar) = jar {
+                    req.cookies(jar, url, cross_site);
+                }
+
+                let b_req = req.build();
+                io.write_all(&b_req)?;
+
+                // reads only this response, so the connection can be reused afterwards
+                let mut res = Response::read_from(io, parser)?;
+                res.tls = io.tls_info();
+                Ok(res)
+            }
+            Connection::Http2(h2) => {
+                let cookie = jar.and_then(|j| j.header_for_request(url, m, cross_site));
+                let id = send_h2(h2, headers, cookie, m, url, content)?;
+                let mut res = h2.read_response(id)?;
+                res.tls = h2.get_mut().tls_info();
+                Ok(res)
+            }
+        }
+    }
+}
+
 pub struct PersistentClient<'p> {
-    io: TlsStream,
+    conn: Option<Connection>,
+    url: Url<'p>,
+    origin: PoolKey,
     head: HeaderMap<'p>,
+    raw_content: bool,
+    max_decompressed: usize,
+    redirect_policy: RedirectPolicy,
+    cookie_jar: Option<Arc<CookieJar>>,
+    timeouts: Timeouts,
+    tls_config: Arc<ClientConfig>,
+    connect_to: ConnectTo,
+    resolver: Arc<dyn Resolver>,
+    // requests redirected away from the kept connection's origin
+    others: HttpsClient<'p>,
 }
 
 impl<'p> PersistentClient<'p> {
     pub fn new(a: &'p str, eh: OptHeaders<'p>, url: &'p str) -> TLSResult<Self> {
+        Self::with_timeouts(a, eh, url, Timeouts::default())
+    }
+
+    // The connection is opened on the first request, or by connect().
+    pub fn with_timeouts(
+        a: &'p str,
+        eh: OptHeaders<'p>,
+        url: &'p str,
+        timeouts: Timeouts,
+    ) -> TLSResult<Self> {
         let mut head: HeaderMap<'p> = HashMap::new();
-        let p_url = Url::new(url).unwrap();
+        let p_url = Url::new(url)?;
         head.insert("User-Agent", a);
+        head.insert("Accept-Encoding", decompress::accept_encoding());
         if let Some(h) = eh {
             head.extend(h.iter())
         };
 
+        let mut others = HttpsClient::new(a, None);
+        others.timeouts(timeouts);
         Ok(Self {
-            io: TlsStream::new(None, p_url.domain(), &p_url.socket_addr())?,
+            conn: None,
+            origin: PoolKey::from_url(&p_url),
+            url: p_url,
             head,
+            raw_content: false,
+            max_decompressed: decompress::DEFAULT_MAX_SIZE,
+            redirect_policy: RedirectPolicy::default(),
+            cookie_jar: None,
+            timeouts,
+            tls_config: shared_h2_config(),
+            connect_to: ConnectTo::default(),
+            resolver: Arc::new(SystemResolver),
+            others,
         })
     }
 
+    // Skips decoding Content-Encoding, responses keep the bytes as sent by the server.
+    pub fn raw_content(&mut self, raw: bool) -> &mut Self {
+        self.raw_content = raw;
+        self
+    }
+
+    // Responses that decode to more than max bytes fail with DecompressedTooLarge,
+    // 64 MiB by default.
+    pub fn max_decompressed_size(&mut self, max: usize) -> &mut Self {
+        self.max_decompressed = max;
+        self
+    }
+
+    // Opens the connection now instead of on the first request.
+    pub fn connect(&mut self) -> TLSResult<&mut Self> {
+        Connection::get(
+            &mut self.conn,
+            &self.url,
+            &self.tls_config,
+            &self.connect_to,
+            self.resolver.as_ref(),
+            &self.timeouts,
+            None,
+        )?;
+        Ok(self)
+    }
+
+    // Only known once connected.
+    pub fn is_http2(&self) -> bool {
+        matches!(self.conn, Some(Connection::Http2(..)))
+    }
+
+    pub fn redirect_policy(&mut self, policy: RedirectPolicy) -> &mut Self {
+        self.redirect_policy = policy;
+        self
+    }
+
+    // Cookies are only kept when a jar is set, it can be shared with other clients.
+    pub fn cookie_jar(&mut self, jar: Arc<CookieJar>) -> &mut Self {
+        self.others.cookie_jar(Arc::clone(&jar));
+        self.cookie_jar = Some(jar);
+        self
+    }
+
+    // The connect and handshake limits only apply to connections opened from now on.
+    pub fn timeouts(&mut self, timeouts: Timeouts) -> &mut Self {
+        if let Some(ref mut conn) = self.conn {
+            conn.stream().set_read_idle_timeout(timeouts.read_idle);
+        }
+        self.others.timeouts(timeouts);
+        self.timeouts = timeouts;
+        self
+    }
+
+    // Closes the kept connection if there is one, the next request reconnects with the new settings.
+    pub fn tls_config(&mut self, config: &TlsConfig) -> TLSResult<&mut Self> {
+        let mut cfg = config.build()?;
+        cfg.alpn_protocols = vec![ALPN_H2.to_vec(), ALPN_HTTP11.to_vec()];
+        self.others.tls_config(config)?;
+        self.tls_config = Arc::new(cfg);
+        self.conn = None;
+        Ok(self)
+    }
+
+    // Closes the kept connection if there is one, the next request reconnects with the new settings.
+    pub fn connect_to(&mut self, connect_to: ConnectTo) -> &mut Self {
+        self.others.connect_to(connect_to.clone());
+        self.connect_to = connect_to;
+        self.conn = None;
+        self
+    }
+
+    // Only used for connections opened from now on, the kept one stays open.
+    pub fn resolver(&mut self, resolver: Arc<dyn Resolver>) -> &mut Self {
+        self.others.resolver(Arc::clone(&resolver));
+        self.resolver = resolver;
+        self
+    }
+
     pub fn request(
         &mut self,
         m: Methods,
         url: &'p str,
         content: Option<Vec<u8>>,
         extra_headers: Option<HeaderMap<'p>>,
-    ) -> TLSResult<Vec<u8>> {
-        let mut req = RequestBuilder::new();
-        let split_url = match Url::new(url) {
-            Ok(u) => u,
-            Err(e) => return Err(Error::new(ErrorKind::InvalidData, e.to_string())),
+    ) -> TLSResult<Response> {
+        let mut headers = self.head.clone();
+        if let Some(h) = extra_headers {
+            headers.extend(h);
         };
 
-        req.http_method(m)
-            .headers(&self.head)
-            .route(split_url.route())
-            .host(split_url.domain());
+        let policy = self.redirect_policy;
+        let deadline = self.timeouts.deadline();
+        let first = self.send(m, url, content.clone(), &headers, deadline, false)?;
+        let mut res =
+            redirect::follow(policy, m, url, content, headers, first, |m, u, c, h, x| {
+                self.send(m, u, c, h, deadline, x)
+            })?;
+
+        if !self.raw_content {
+            res.decompress_limited(self.max_decompressed)?;
+        }
+        Ok(res)
+    }
+
+    // Sends all requests on the origin before reading any response when the connection is
+    // HTTP/2, over HTTP/1.1 and to other origins they are done one after another.
+    // Responses are returned in the order of the requests.
+    // Over HTTP/2 the total timeout is for the whole batch.
+    pub fn request_many(
+        &mut self,
+        requests: Vec<(Methods, &'p str, Option<Vec<u8>>)>,
+    ) -> TLSResult<Vec<Response>> {
+        let deadline = self.timeouts.deadline();
+        let conn = Connection::get(
+            &mut self.conn,
+            &self.url,
+            &self.tls_config,
+            &self.connect_to,
+            self.resolver.as_ref(),
+            &self.timeouts,
+            deadline,
+        )?;
+        if let Connection::Http1 { .. } = conn {
+            return requests
+                .into_iter()
+                .map(|(m, url, content)| self.request(m, url, content, None))
+                .collect();
+        }
 
-        if let Some(c) = content {
-            req.content(c);
+        let batch = match self.send_many_h2(&requests, deadline) {
+            Ok(b) => b,
+            Err(e) => {
+                self.conn = None;
+                return Err(e);
+            }
         };
-        if let Some(h) = extra_headers {
-            req.headers(&h);
+        if !self.conn.as_ref().is_some_and(Connection::is_reusable) {
+            self.conn = None;
+        }
+        // the ones on other origins are sent through others, one after another
+        let head = self.head.clone();
+        let mut firsts = Vec::with_capacity(batch.len());
+        for ((m, url, content), res) in requests.iter().zip(batch) {
+            let res = match res {
+                Some(r) => r,
+                None => self.send(*m, url, content.clone(), &head, deadline, false)?,
+            };
+            firsts.push(res);
+        }
+
+        let policy = self.redirect_policy;
+        let mut responses = Vec::with_capacity(firsts.len());
+        for ((m, url, content), first) in requests.into_iter().zip(firsts) {
+            let headers = self.head.clone();
+            let mut res =
+                redirect::follow(policy, m, url, content, headers, first, |m, u, c, h, x| {
+                    self.send(m, u, c, h, deadline, x)
+                })?;
+            if !self.raw_content {
+                res.decompress_limited(self.max_decompressed)?;
+            }
+            responses.push(res);
+        }
+        Ok(responses)
+    }
+
+    // Sends the requests on the origin before reading any of their responses.
+    // Other origins can't go over the kept connection, they are left as None.
+    fn send_many_h2(
+        &mut self,
+        requests: &[(Methods, &str, Option<Vec<u8>>)],
+        deadline: Option<Instant>,
+    ) -> TLSResult<Vec<Option<Response>>> {
+        let h2 = match self.conn {
+            Some(Connection::Http2(ref mut h2)) => h2,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::NotConnected,
+                    "not connected over HTTP/2",
+                ))
+            }
         };
+        h2.get_mut().set_deadline(deadline);
+
+        let mut ids = Vec::with_capacity(requests.len());
+        for (m, url, content) in requests.iter() {
+            let split_url = Url::new(url)?;
+            if PoolKey::from_url(&split_url) != self.origin {
+                ids.push(None);
+                continue;
+            }
+            let cookie = self
+                .cookie_jar
+                .as_ref()
+                .and_then(|j| j.header_for(&split_url));
+            let id = send_h2(h2, &self.head, cookie, *m, &split_url, content.clone())?;
+            ids.push(Some((split_url, id)));
+        }
 
-        let b_req = req.build();
-        let mut buf = vec![];
-        let _ = self.io.write(&b_req);
-        let _ = self.io.read_to_end(&mut buf);
-        Ok(buf)
+        let mut responses = Vec::with_capacity(ids.len());
+        for id in ids {
+            let Some((url, id)) = id else {
+                responses.push(None);
+                continue;
+            };
+            let mut res = h2.read_response(id)?;
+            res.tls = h2.get_mut().tls_info();
+            if let Some(ref jar) = self.cookie_jar {
+                jar.store(&url, &res);
+            }
+            responses.push(Some(res));
+        }
+        Ok(responses)
     }
 
-    pub fn get(&mut self, url: &'p str, headers: Option<HeaderMap<'p>>) -> TLSResult<Vec<u8>> {
+    // Sends a single request, over the kept connection if the url is on its origin.
+    fn send(
+        &mut self,
+        m: Methods,
+        url: &str,
+        content: Option<Vec<u8>>,
+        headers: &HeaderMap,
+        deadline: Option<Instant>,
+        cross_site: bool,
+    ) -> TLSResult<Response> {
+        let split_url = Url::new(url)?;
+        let key = PoolKey::from_url(&split_url);
+        if key != self.origin {
+            return self
+                .others
+                .send(m, url, content, headers, deadline, cross_site);
+        }
+        let conn = Connection::get(
+            &mut self.conn,
+            &self.url,
+            &self.tls_config,
+            &self.connect_to,
+            self.resolver.as_ref(),
+            &self.timeouts,
+            deadline,
+        )?;
+        conn.stream().set_deadline(deadline);
+
+        let jar = self.cookie_jar.as_ref();
+        let res = conn.send(m, &split_url, headers, jar, content, cross_site);
+        // a failed or closing connection isn't used again, the next request opens a new one
+        if !res.as_ref().is_ok_and(|_| conn.is_reusable()) {
+            self.conn = None;
+        }
+        let res = res?;
+
+        if let Some(ref jar) = self.cookie_jar {
+            jar.store(&split_url, &res);
+        }
+        Ok(res)
+    }
+
+    pub fn get(&mut self, url: &'p str, headers: Option<HeaderMap<'p>>) -> TLSResult<Response> {
         self.request(Methods::GET, url, None, headers)
     }
 }
+
+fn send_h2(
+    h2: &mut H2Connection<Transport>,
+    headers: &HeaderMap,
+    cookie: Option<String>,
+    m: Methods,
+    url: &Url,
+    content: Option<Vec<u8>>,
+) -> TLSResult<u32> {
+    let mut headers: Vec<(&str, &str)> = headers.iter().map(|(k, v)| (*k, *v)).collect();
+    // HTTP/2 allows the Cookie header to be split, RFC 9113 section 8.2.3
+    if let Some(ref c) = cookie {
+        headers.push(("cookie", c));
+    }
+
+    let id = h2.send_request(
+        m.as_str(),
+        url.scheme(),
+        &url.host_header(),
+        url.route(),
+        &headers,
+        content.as_deref(),
+    )?;
+    Ok(id)
+}
@@ -0,0 +1,475 @@
+use super::parser::ResponseParser;
+use super::response::{HttpResult, Response};
+use super::timeout::TimeoutError;
+use super::transport::Transport;
+use super::url::Url;
+use log::debug;
+use rustls::ClientConfig;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+const DEFAULT_MAX_PER_HOST: usize = 8;
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+// Connections are only shared between requests to the same origin.
+// Ones opened with other TLS settings or to another address aren't shared either,
+// see with_connection().
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PoolKey {
+    scheme: String,
+    host: String,
+    port: u16,
+    tls_config: ConfigRef,
+    addr: String,
+    server_name: String,
+    sni: bool,
+}
+
+impl PoolKey {
+    pub fn new(scheme: &str, host: &str, port: u16) -> Self {
+        Self {
+            scheme: scheme.to_ascii_lowercase(),
+            host: host.to_ascii_lowercase(),
+            port,
+            tls_config: ConfigRef(None),
+            addr: String::new(),
+            server_name: String::new(),
+            sni: true,
+        }
+    }
+
+    // Urls of schemes without a default port need an explicit one to be connected to anyway.
+    pub fn from_url(url: &Url) -> Self {
+        Self::new(url.scheme(), url.domain(), url.port().unwrap_or(0))
+    }
+
+    // Ties the key to what connections are opened with, so a connection verified one way,
+    // e.g. without pins or client certificate, is never handed to a client that needs another.
+    pub fn with_connection(
+        mut self,
+        config: &Arc<ClientConfig>,
+        addr: &str,
+        server_name: &str,
+        sni: bool,
+    ) -> Self {
+        self.tls_config = ConfigRef(Some(Arc::clone(config)));
+        self.addr = addr.to_string();
+        self.server_name = server_name.to_ascii_lowercase();
+        self.sni = sni;
+        self
+    }
+}
+
+// ClientConfig a connection was opened with, compared by identity. The key holds on to it,
+// so another config can't take its place at the same address.
+#[derive(Clone)]
+struct ConfigRef(Option<Arc<ClientConfig>>);
+
+impl ConfigRef {
+    fn ptr(&self) -> *const ClientConfig {
+        self.0.as_ref().map_or(std::ptr::null(), Arc::as_ptr)
+    }
+}
+
+impl PartialEq for ConfigRef {
+    fn eq(&self, other: &Self) -> bool {
+        self.ptr() == other.ptr()
+    }
+}
+
+impl Eq for ConfigRef {}
+
+impl Hash for ConfigRef {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.ptr().hash(state);
+    }
+}
+
+impl fmt::Debug for ConfigRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:p}", self.ptr())
+    }
+}
+
+struct Idle {
+    io: Transport,
+    since: Instant,
+}
+
+#[derive(Default)]
+struct PoolState {
+    idle: HashMap<PoolKey, Vec<Idle>>,
+    // connections currently checked out
+    active: HashMap<PoolKey, usize>,
+}
+
+// Pool of keep-alive connections, can be shared between clients and threads.
+pub struct ConnectionPool {
+    state: Mutex<PoolState>,
+    released: Condvar,
+    max_per_host: usize,
+    idle_timeout: Duration,
+}
+
+impl Default for ConnectionPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConnectionPool {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(PoolState::default()),
+            released: Condvar::new(),
+            max_per_host: DEFAULT_MAX_PER_HOST,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+        }
+    }
+
+    // Upper bound of open connections per origin, idle ones included.
+    pub fn max_per_host(mut self, max: usize) -> Self {
+        self.max_per_host = max.max(1);
+        self
+    }
+
+    // Idle connections older than this are closed instead of reused.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    // Hands out an idle connection for the origin, or opens one with connect.
+    // Blocks while the origin is at its connection limit, fails with TimeoutError::Total
+    // when that lasts past deadline.
+    pub fn checkout<F>(
+        self: &Arc<Self>,
+        key: &PoolKey,
+        deadline: Option<Instant>,
+        connect: F,
+    ) -> io::Result<PooledConnection>
+    where
+        F: FnOnce() -> io::Result<Transport>,
+    {
+        let mut state = self.lock();
+        loop {
+            self.evict(&mut state);
+
+            while let Some(idle) = state.idle.get_mut(key).and_then(|v| v.pop()) {
+                if idle.io.is_stale() {
+                    debug!("Dropping dead connection to {:?}", key);
+                    continue;
+                }
+                debug!("Reusing connection to {:?}", key);
+                *state.active.entry(key.clone()).or_insert(0) += 1;
+                return Ok(PooledConnection::new(self, key, idle.io, true));
+            }
+
+            let active = state.active.get(key).copied().unwrap_or(0);
+            let idle = state.idle.get(key).map_or(0, |v| v.len());
+            if active + idle < self.max_per_host {
+                break;
+            }
+            state = match deadline {
+                Some(d) => {
+                    let left = d.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        debug!("No connection to {:?} freed up in time", key);
+                        return Err(TimeoutError::Total.into());
+                    }
+                    match self.released.wait_timeout(state, left) {
+                        Ok((s, _)) => s,
+                        Err(p) => p.into_inner().0,
+                    }
+                }
+                None => match self.released.wait(state) {
+                    Ok(s) => s,
+                    Err(p) => p.into_inner(),
+                },
+            };
+        }
+
+        // connecting happens outside of the lock
+        *state.active.entry(key.clone()).or_insert(0) += 1;
+        drop(state);
+
+        match connect() {
+            Ok(io) => Ok(PooledConnection::new(self, key, io, false)),
+            Err(e) => {
+                self.release(key, None);
+                Err(e)
+            }
+        }
+    }
+
+    // Closes every idle connection.
+    pub fn clear(&self) {
+        self.lock().idle.clear();
+        self.released.notify_all();
+    }
+
+    pub fn idle_count(&self, key: &PoolKey) -> usize {
+        self.lock().idle.get(key).map_or(0, |v| v.len())
+    }
+
+    fn release(&self, key: &PoolKey, io: Option<Transport>) {
+        let mut state = self.lock();
+        if let Some(n) = state.active.get_mut(key) {
+            *n = n.saturating_sub(1);
+        }
+        if let Some(io) = io {
+            debug!("Returning connection to {:?}", key);
+            state.idle.entry(key.clone()).or_default().push(Idle {
+                io,
+                since: Instant::now(),
+            });
+        }
+        self.evict(&mut state);
+        drop(state);
+        self.released.notify_all();
+    }
+
+    fn evict(&self, state: &mut PoolState) {
+        let timeout = self.idle_timeout;
+        state.idle.retain(|_, conns| {
+            conns.retain(|c| c.since.elapsed() < timeout);
+            !conns.is_empty()
+        });
+        state.active.retain(|_, n| *n > 0);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PoolState> {
+        match self.state.lock() {
+            Ok(s) => s,
+            Err(p) => p.into_inner(),
+        }
+    }
+}
+
+// Connection checked out of the pool, it's given back when dropped.
+// Only connections marked with keep_alive() are reused, the rest are closed.
+pub struct PooledConnection {
+    pool: Arc<ConnectionPool>,
+    key: PoolKey,
+    io: Option<Transport>,
+    pub parser: ResponseParser,
+    reused: bool,
+    keep: bool,
+}
+
+impl PooledConnection {
+    fn new(pool: &Arc<ConnectionPool>, key: &PoolKey, io: Transport, reused: bool) -> Self {
+        Self {
+            pool: Arc::clone(pool),
+            key: key.clone(),
+            io: Some(io),
+            parser: ResponseParser::new(),
+            reused,
+            keep: false,
+        }
+    }
+
+    pub fn stream(&mut self) -> &mut Transport {
+        match self.io {
+            Some(ref mut io) => io,
+            None => unreachable!("connection used after release"),
+        }
+    }
+
+    // Whether the connection was used for an earlier request.
+    pub fn is_reused(&self) -> bool {
+        self.reused
+    }
+
+    // Reads a response with the connection's own parser.
+    pub fn read_response(&mut self) -> HttpResult<Response> {
+        match self.io {
+            Some(ref mut io) => Response::read_from(io, &mut self.parser),
+            None => unreachable!("connection used after release"),
+        }
+    }
+
+    pub fn keep_alive(&mut self, keep: bool) {
+        self.keep = keep;
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        let io = self.io.take().filter(|_| self.keep);
+        self.pool.release(&self.key, io);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::https::connect_to::ConnectTo;
+    use crate::https::resolve::SystemResolver;
+    use crate::https::timeout::{timeout_of, Timeouts};
+    use crate::tls::config::TlsConfig;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn connect(listener: &TcpListener) -> io::Result<Transport> {
+        let url = format!("http://{}/", listener.local_addr()?);
+        let url = Url::new(&url).unwrap();
+        let timeouts = Timeouts::default();
+        let connect_to = ConnectTo::default();
+        Transport::connect(&url, None, &connect_to, &SystemResolver, &timeouts, None)
+    }
+
+    fn key(listener: &TcpListener) -> PoolKey {
+        PoolKey::new("http", "127.0.0.1", listener.local_addr().unwrap().port())
+    }
+
+    #[test]
+    fn keys() {
+        let url = Url::new("HTTPS://Example.com/a").unwrap();
+        assert_eq!(
+            PoolKey::from_url(&url),
+            PoolKey::new("https", "example.com", 443)
+        );
+        assert_ne!(
+            PoolKey::from_url(&url),
+            PoolKey::new("http", "example.com", 443)
+        );
+
+        let a = Arc::new(TlsConfig::new().build().unwrap());
+        let b = Arc::new(TlsConfig::new().build().unwrap());
+        let key = |c: &Arc<ClientConfig>, addr: &str, name: &str| {
+            PoolKey::from_url(&url).with_connection(c, addr, name, true)
+        };
+        assert_eq!(
+            key(&a, "example.com:443", "example.com"),
+            key(&a.clone(), "example.com:443", "Example.com")
+        );
+        assert_ne!(
+            key(&a, "example.com:443", "example.com"),
+            key(&b, "example.com:443", "example.com")
+        );
+        assert_ne!(
+            key(&a, "example.com:443", "example.com"),
+            key(&a, "10.0.0.1:443", "example.com")
+        );
+        assert_ne!(
+            key(&a, "example.com:443", "example.com"),
+            key(&a, "example.com:443", "other.example")
+        );
+        assert_ne!(
+            key(&a, "example.com:443", "example.com"),
+            PoolKey::from_url(&url).with_connection(&a, "example.com:443", "example.com", false)
+        );
+    }
+
+    #[test]
+    fn equal_configs_share_connections() {
+        let config = TlsConfig::new().pin_spki_sha256("example.com", [1; 32]);
+        let a = config.build_shared().unwrap();
+        assert!(Arc::ptr_eq(&a, &config.clone().build_shared().unwrap()));
+        let other = config.early_data(true).build_shared().unwrap();
+        assert!(!Arc::ptr_eq(&a, &other));
+    }
+
+    #[test]
+    fn kept_connections_are_reused() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let pool = Arc::new(ConnectionPool::new());
+        let key = key(&listener);
+
+        let mut conn = pool.checkout(&key, None, || connect(&listener)).unwrap();
+        assert!(!conn.is_reused());
+        conn.keep_alive(true);
+        drop(conn);
+        assert_eq!(pool.idle_count(&key), 1);
+
+        let conn = pool
+            .checkout(&key, None, || panic!("should be reused"))
+            .unwrap();
+        assert!(conn.is_reused());
+        assert_eq!(pool.idle_count(&key), 0);
+        // not kept alive, so it's closed
+        drop(conn);
+        assert_eq!(pool.idle_count(&key), 0);
+    }
+
+    #[test]
+    fn closed_connections_are_not_reused() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let pool = Arc::new(ConnectionPool::new());
+        let key = key(&listener);
+
+        let mut conn = pool.checkout(&key, None, || connect(&listener)).unwrap();
+        conn.keep_alive(true);
+        drop(conn);
+        drop(listener.accept().unwrap());
+
+        let start = Instant::now();
+        while !pool.lock().idle[&key][0].io.is_stale() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(5));
+        }
+        let conn = pool.checkout(&key, None, || connect(&listener)).unwrap();
+        assert!(!conn.is_reused());
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let pool = Arc::new(ConnectionPool::new().idle_timeout(Duration::from_millis(20)));
+        let key = key(&listener);
+
+        let mut conn = pool.checkout(&key, None, || connect(&listener)).unwrap();
+        conn.keep_alive(true);
+        drop(conn);
+        thread::sleep(Duration::from_millis(30));
+        let conn = pool.checkout(&key, None, || connect(&listener)).unwrap();
+        assert!(!conn.is_reused());
+    }
+
+    #[test]
+    fn limit_waits_for_a_released_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let pool = Arc::new(ConnectionPool::new().max_per_host(1));
+        let key = key(&listener);
+
+        let mut conn = pool.checkout(&key, None, || connect(&listener)).unwrap();
+        conn.keep_alive(true);
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(30));
+            drop(conn);
+        });
+        let conn = pool
+            .checkout(&key, None, || panic!("over the limit"))
+            .unwrap();
+        assert!(conn.is_reused());
+        releaser.join().unwrap();
+    }
+
+    #[test]
+    fn limit_waits_until_the_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let pool = Arc::new(ConnectionPool::new().max_per_host(1));
+        let key = key(&listener);
+
+        let _held = pool.checkout(&key, None, || connect(&listener)).unwrap();
+        let start = Instant::now();
+        let deadline = Some(start + Duration::from_millis(50));
+        let e = pool
+            .checkout(&key, deadline, || panic!("over the limit"))
+            .err()
+            .unwrap();
+        assert_eq!(timeout_of(&e), Some(TimeoutError::Total));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // other origins aren't held up
+        let other = PoolKey::new("http", "localhost", key.port);
+        assert!(pool
+            .checkout(&other, deadline, || connect(&listener))
+            .is_ok());
+    }
+}
@@ -0,0 +1,167 @@
+use super::percent;
+use super::url::Url;
+use std::borrow::Cow;
+use std::str::Split;
+
+// Decoded key/value pairs of a query string, in order. A key without "=" gets an empty value.
+pub struct QueryPairs<'a> {
+    inner: Split<'a, char>,
+}
+
+impl<'a> QueryPairs<'a> {
+    // query is taken without the leading "?".
+    pub fn new(query: &'a str) -> Self {
+        Self {
+            inner: query.split('&'),
+        }
+    }
+}
+
+impl<'a> Iterator for QueryPairs<'a> {
+    type Item = (Cow<'a, str>, Cow<'a, str>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let pair = self.inner.by_ref().find(|p| !p.is_empty())?;
+        let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+        Some((percent::decode_form(k), percent::decode_form(v)))
+    }
+}
+
+// Builds a form encoded query string.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QueryBuilder {
+    query: String,
+}
+
+impl QueryBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn append_pair(&mut self, key: &str, value: &str) -> &mut Self {
+        if !self.query.is_empty() {
+            self.query.push('&');
+        }
+        self.query.push_str(&percent::encode_form(key));
+        self.query.push('=');
+        self.query.push_str(&percent::encode_form(value));
+        self
+    }
+
+    pub fn extend_pairs<'k, I>(&mut self, pairs: I) -> &mut Self
+    where
+        I: IntoIterator<Item = (&'k str, &'k str)>,
+    {
+        for (k, v) in pairs {
+            self.append_pair(k, v);
+        }
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.query.is_empty()
+    }
+
+    // The encoded query, without a leading "?".
+    pub fn as_str(&self) -> &str {
+        &self.query
+    }
+
+    // Adds the pairs to a route or url after its existing query, in front of any fragment.
+    pub fn append_to(&self, target: &str) -> String {
+        if self.query.is_empty() {
+            return target.to_string();
+        }
+        let (head, fragment) = match target.split_once('#') {
+            Some((h, f)) => (h, Some(f)),
+            None => (target, None),
+        };
+        let mut out = head.to_string();
+        match head.contains('?') {
+            true if !head.ends_with(['?', '&']) => out.push('&'),
+            true => {}
+            false => out.push('?'),
+        }
+        out.push_str(&self.query);
+        if let Some(f) = fragment {
+            out.push('#');
+            out.push_str(f);
+        }
+        out
+    }
+
+    pub fn append_to_url(&self, url: &Url) -> String {
+        self.append_to(url.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(query: &str) -> Vec<(String, String)> {
+        QueryPairs::new(query)
+            .map(|(k, v)| (k.into_owned(), v.into_owned()))
+            .collect()
+    }
+
+    fn owned(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            pairs("a=1&b=x+y&&c&d=%3D&=e"),
+            owned(&[("a", "1"), ("b", "x y"), ("c", ""), ("d", "="), ("", "e")])
+        );
+        assert_eq!(pairs(""), owned(&[]));
+        // only the first "=" splits
+        assert_eq!(pairs("a=b=c"), owned(&[("a", "b=c")]));
+    }
+
+    #[test]
+    fn build() {
+        let mut q = QueryBuilder::new();
+        assert!(q.is_empty());
+        q.append_pair("a", "1")
+            .extend_pairs([("b c", "x&y"), ("é", "")]);
+        assert_eq!(q.as_str(), "a=1&b+c=x%26y&%C3%A9=");
+    }
+
+    #[test]
+    fn round_trip() {
+        let original = [("q", "rust & tls"), ("k=1", "a+b"), ("", "%"), ("é", "ü")];
+        let mut q = QueryBuilder::new();
+        q.extend_pairs(original);
+        assert_eq!(pairs(q.as_str()), owned(&original));
+
+        let url = Url::new("https://example.com/p?x=1#frag").unwrap();
+        let joined = q.append_to_url(&url);
+        let parsed = Url::new(&joined).unwrap();
+        assert_eq!(parsed.fragment(), Some("frag"));
+        let mut expected = owned(&[("x", "1")]);
+        expected.extend(owned(&original));
+        assert_eq!(
+            parsed
+                .query_pairs()
+                .map(|(k, v)| (k.into_owned(), v.into_owned()))
+                .collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    #[test]
+    fn append() {
+        let mut q = QueryBuilder::new();
+        q.append_pair("a", "1");
+        assert_eq!(q.append_to("/p"), "/p?a=1");
+        assert_eq!(q.append_to("/p?"), "/p?a=1");
+        assert_eq!(q.append_to("/p?x&"), "/p?x&a=1");
+        assert_eq!(q.append_to("/p?x#f?g"), "/p?x&a=1#f?g");
+        assert_eq!(QueryBuilder::new().append_to("/p#f"), "/p#f");
+    }
+}
@@ -0,0 +1,336 @@
+use super::client::Methods;
+use super::cookie;
+use super::response::{HttpResponseError, Response};
+use super::url::Url;
+use log::debug;
+use std::collections::HashMap;
+use std::io;
+
+const DEFAULT_MAX_HOPS: usize = 10;
+
+// Headers that must not leak to another origin.
+const SENSITIVE_HEADERS: [&str; 3] = ["Authorization", "Cookie", "Proxy-Authorization"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RedirectPolicy {
+    // 3xx responses are handed back as they are
+    None,
+    Follow { max_hops: usize },
+}
+
+impl Default for RedirectPolicy {
+    fn default() -> Self {
+        RedirectPolicy::Follow {
+            max_hops: DEFAULT_MAX_HOPS,
+        }
+    }
+}
+
+// One hop of a followed redirect.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Redirect {
+    pub status_code: u16,
+    pub url: String,
+    pub location: String,
+}
+
+pub fn is_redirect(status: u16) -> bool {
+    matches!(status, 301 | 302 | 303 | 307 | 308)
+}
+
+// Follows redirects starting from the response to the first request.
+// send is called for every further hop with the rewritten method, url, body and headers,
+// and whether the chain went through another site than the first url, for SameSite cookies.
+pub fn follow<'h, F>(
+    policy: RedirectPolicy,
+    method: Methods,
+    url: &str,
+    content: Option<Vec<u8>>,
+    headers: HashMap<&'h str, &'h str>,
+    first: Response,
+    mut send: F,
+) -> io::Result<Response>
+where
+    F: FnMut(
+        Methods,
+        &str,
+        Option<Vec<u8>>,
+        &HashMap<&'h str, &'h str>,
+        bool,
+    ) -> io::Result<Response>,
+{
+    let max_hops = match policy {
+        RedirectPolicy::None => return Ok(first),
+        RedirectPolicy::Follow { max_hops } => max_hops,
+    };
+
+    let mut method = method;
+    let mut content = content;
+    let mut headers = headers;
+    let initiator = parse(url)?;
+    let mut cross_site = false;
+    let mut url = url.to_string();
+    let mut res = first;
+    let mut chain = vec![];
+
+    while is_redirect(res.status_code) {
+        let location = match res.header("Location") {
+            Some(l) => l,
+            None => break,
+        };
+        if chain.len() >= max_hops {
+            return Err(HttpResponseError::TooManyRedirects(max_hops).into());
+        }
+        let next = resolve(&url, location)?;
+        debug!("Following {} redirect to {}", res.status_code, next);
+
+        // 303 always turns into GET, 301 and 302 only for POST (like browsers do)
+        let to_get = match res.status_code {
+            303 => !matches!(method, Methods::HEAD),
+            301 | 302 => matches!(method, Methods::POST),
+            _ => false,
+        };
+        if to_get {
+            method = Methods::GET;
+            content = None;
+            headers.retain(|k, _| {
+                !k.eq_ignore_ascii_case("Content-Type") && !k.eq_ignore_ascii_case("Content-Length")
+            });
+        }
+
+        if !same_origin(&url, &next)? {
+            headers.retain(|k, _| !SENSITIVE_HEADERS.iter().any(|s| s.eq_ignore_ascii_case(k)));
+        }
+
+        chain.push(Redirect {
+            status_code: res.status_code,
+            url: url.clone(),
+            location: next.clone(),
+        });
+        cross_site |= !cookie::same_site(&initiator, &parse(&next)?);
+        url = next;
+        res = send(method, &url, content.clone(), &headers, cross_site)?;
+    }
+
+    res.redirects = chain;
+    Ok(res)
+}
+
+// Resolves a Location value against the url of the request it answered.
+// A Location without a fragment keeps the one of the request, RFC 9110 section 10.2.2.
+pub fn resolve(base: &str, location: &str) -> io::Result<String> {
+    let base = parse(base)?;
+    let mut next = base.join(location.trim())?;
+    if let Some(f) = base.fragment().filter(|_| !next.contains('#')) {
+        next.push('#');
+        next.push_str(f);
+    }
+    Ok(next)
+}
+
+fn same_origin(a: &str, b: &str) -> io::Result<bool> {
+    let (a, b) = (parse(a)?, parse(b)?);
+    Ok(a.scheme().eq_ignore_ascii_case(b.scheme())
+        && a.domain().eq_ignore_ascii_case(b.domain())
+        && a.port() == b.port())
+}
+
+fn parse(url: &str) -> io::Result<Url<'_>> {
+    Ok(Url::new(url)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(status: u16, location: Option<&str>) -> Response {
+        let location = location.map_or(String::new(), |l| format!("Location: {}\r\n", l));
+        let raw = format!(
+            "HTTP/1.1 {} X\r\n{}Content-Length: 0\r\n\r\n",
+            status, location
+        );
+        Response::from_slice(raw.as_bytes()).unwrap()
+    }
+
+    // What send() got for one hop.
+    #[derive(Debug, PartialEq)]
+    struct Hop {
+        method: Methods,
+        url: String,
+        content: Option<Vec<u8>>,
+        // names, sorted
+        headers: Vec<String>,
+        cross_site: bool,
+    }
+
+    // Follows from the first url through a server answering with the given redirects.
+    fn follow_through(
+        policy: RedirectPolicy,
+        method: Methods,
+        headers: &[(&'static str, &'static str)],
+        hops: &[(&str, u16, Option<&str>)],
+    ) -> (io::Result<Response>, Vec<Hop>) {
+        let mut sent = vec![];
+        let (first, status, location) = hops[0];
+        let res = follow(
+            policy,
+            method,
+            first,
+            Some(b"body".to_vec()),
+            headers.iter().copied().collect(),
+            response(status, location),
+            |m, u, c, h, cross_site| {
+                let mut headers: Vec<String> = h.keys().map(|k| k.to_string()).collect();
+                headers.sort();
+                sent.push(Hop {
+                    method: m,
+                    url: u.to_string(),
+                    content: c,
+                    headers,
+                    cross_site,
+                });
+                let hop = hops.iter().find(|h| h.0 == u).unwrap();
+                Ok(response(hop.1, hop.2))
+            },
+        );
+        (res, sent)
+    }
+
+    #[test]
+    fn methods_and_bodies() {
+        let headers = [("Content-Type", "text/plain"), ("Accept", "*/*")];
+        for (status, method, kept) in [
+            (303, Methods::POST, false),
+            (303, Methods::PUT, false),
+            (302, Methods::POST, false),
+            (301, Methods::PUT, true),
+            (307, Methods::POST, true),
+            (308, Methods::POST, true),
+        ] {
+            let hops = [
+                ("https://example.com/a", status, Some("/b")),
+                ("https://example.com/b", 200, None),
+            ];
+            let policy = RedirectPolicy::default();
+            let (res, sent) = follow_through(policy, method, &headers, &hops);
+            let res = res.unwrap();
+            assert_eq!(res.redirects.len(), 1);
+            assert_eq!(res.redirects[0].location, "https://example.com/b");
+            let hop = &sent[0];
+            match kept {
+                true => {
+                    assert_eq!(hop.method, method, "{}", status);
+                    assert_eq!(hop.content.as_deref(), Some(&b"body"[..]));
+                    assert_eq!(hop.headers, ["Accept", "Content-Type"]);
+                }
+                false => {
+                    assert_eq!(hop.method, Methods::GET, "{}", status);
+                    assert_eq!(hop.content, None);
+                    assert_eq!(hop.headers, ["Accept"]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn credentials_stay_on_the_origin() {
+        let headers = [
+            ("Authorization", "Bearer x"),
+            ("Cookie", "a=1"),
+            ("Accept", "*/*"),
+        ];
+        let hops = [
+            ("https://example.com/", 302, Some("/same")),
+            (
+                "https://example.com/same",
+                302,
+                Some("https://example.com:8443/"),
+            ),
+            (
+                "https://example.com:8443/",
+                302,
+                Some("https://example.com/back"),
+            ),
+            ("https://example.com/back", 200, None),
+        ];
+        let (res, sent) = follow_through(RedirectPolicy::default(), Methods::GET, &headers, &hops);
+        assert!(res.is_ok());
+        assert_eq!(sent[0].headers, ["Accept", "Authorization", "Cookie"]);
+        // another port is another origin, and they aren't given back afterwards
+        assert_eq!(sent[1].headers, ["Accept"]);
+        assert_eq!(sent[2].headers, ["Accept"]);
+    }
+
+    #[test]
+    fn policies_and_limits() {
+        let hops = [
+            ("https://example.com/", 301, Some("/a")),
+            ("https://example.com/a", 301, Some("/b")),
+            ("https://example.com/b", 301, Some("/c")),
+            ("https://example.com/c", 200, None),
+        ];
+        let (res, sent) = follow_through(RedirectPolicy::None, Methods::GET, &[], &hops);
+        assert_eq!(res.unwrap().status_code, 301);
+        assert!(sent.is_empty());
+
+        let policy = RedirectPolicy::Follow { max_hops: 2 };
+        let (res, sent) = follow_through(policy, Methods::GET, &[], &hops);
+        let e = res.unwrap_err();
+        assert!(e.to_string().contains('2'), "{}", e);
+        assert_eq!(sent.len(), 2);
+
+        let policy = RedirectPolicy::Follow { max_hops: 3 };
+        let (res, _) = follow_through(policy, Methods::GET, &[], &hops);
+        assert_eq!(res.unwrap().redirects.len(), 3);
+
+        // without a Location there's nothing to follow
+        let hops = [("https://example.com/", 302, None)];
+        let (res, _) = follow_through(RedirectPolicy::default(), Methods::GET, &[], &hops);
+        assert_eq!(res.unwrap().status_code, 302);
+    }
+
+    #[test]
+    fn locations() {
+        let base = "https://example.com/a/b?q=1#frag";
+        assert_eq!(resolve(base, "c").unwrap(), "https://example.com/a/c#frag");
+        assert_eq!(resolve(base, " /x ").unwrap(), "https://example.com/x#frag");
+        assert_eq!(
+            resolve(base, "/x#new").unwrap(),
+            "https://example.com/x#new"
+        );
+        assert_eq!(
+            resolve(base, "//other.example/").unwrap(),
+            "https://other.example/#frag"
+        );
+        assert_eq!(
+            resolve("https://example.com/", "http://example.com/").unwrap(),
+            "http://example.com/"
+        );
+    }
+
+    #[test]
+    fn cross_site_sticks_for_the_rest_of_the_chain() {
+        let hops = [
+            (
+                "https://www.example.com/",
+                302,
+                Some("https://api.example.com/"),
+            ),
+            (
+                "https://api.example.com/",
+                307,
+                Some("https://other.example/"),
+            ),
+            (
+                "https://other.example/",
+                308,
+                Some("https://www.example.com/end"),
+            ),
+            ("https://www.example.com/end", 200, None),
+        ];
+        let (res, sent) = follow_through(RedirectPolicy::default(), Methods::GET, &[], &hops);
+        assert_eq!(res.unwrap().redirects.len(), 3);
+        let cross: Vec<bool> = sent.iter().map(|s| s.cross_site).collect();
+        assert_eq!(cross, [false, true, true]);
+    }
+}
@@ -1,4 +1,7 @@
 use crate::https::client::Methods;
+use crate::https::cookie::CookieJar;
+use crate::https::query::QueryBuilder;
+use crate::https::url::Url;
 use std::collections::HashMap;
 
 const CRLF: &[u8] = "\r\n".as_bytes();
@@ -9,6 +12,14 @@ pub struct RequestBuilder<'a> {
     headers: HashMap<&'a str, &'a str>,
     content: Option<Vec<u8>>,
     content_len: usize,
+    cookie: Option<String>,
+    query: Option<QueryBuilder>,
+}
+
+impl Default for RequestBuilder<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<'a> RequestBuilder<'a> {
@@ -19,6 +30,8 @@ impl<'a> RequestBuilder<'a> {
             headers: HashMap::new(),
             content: None,
             content_len: 0,
+            cookie: None,
+            query: None,
         }
     }
     pub fn http_method(&mut self, m: Methods) -> &mut Self {
@@ -44,6 +57,18 @@ impl<'a> RequestBuilder<'a> {
         self.route = Some(r);
         self
     }
+    // Appends the pairs to the route's query.
+    pub fn query(&mut self, q: &QueryBuilder) -> &mut Self {
+        self.query = Some(q.clone()).filter(|q| !q.is_empty());
+        self
+    }
+    // Adds the jar's cookies matching url, next to any Cookie header given.
+    // The method has to be set first, it decides which SameSite cookies go cross-site.
+    pub fn cookies(&mut self, jar: &CookieJar, url: &Url, cross_site: bool) -> &mut Self {
+        let method = self.method.unwrap_or(Methods::GET);
+        self.cookie = jar.header_for_request(url, method, cross_site);
+        self
+    }
 
     pub fn build(self) -> Vec<u8> {
         let mut buf = vec![];
@@ -52,46 +77,72 @@ impl<'a> RequestBuilder<'a> {
             Some(m) => m,
         };
 
-        let b_method = match method {
-            Methods::GET => "GET".as_bytes(),
-            Methods::POST => "POST".as_bytes(),
-            Methods::PATCH => "PATCH".as_bytes(),
-            Methods::OPTIONS => "OPTIONS".as_bytes(),
-            Methods::CONNECT => "CONNECT".as_bytes(),
-            Methods::HEAD => "HEAD".as_bytes(),
-            Methods::PUT => "PUT".as_bytes(),
-            Methods::DELETE => "DELETE".as_bytes(),
-        };
-
-        buf.extend_from_slice(b_method);
+        buf.extend_from_slice(method.as_str().as_bytes());
         buf.extend_from_slice(&[32]);
 
         // route
-        match self.route {
-            Some(r) => buf.extend_from_slice(r.as_bytes()),
-            None => buf.extend_from_slice(r"\".as_bytes()),
+        let route = self.route.unwrap_or(r"\");
+        match self.query {
+            Some(q) => buf.extend_from_slice(q.append_to(route).as_bytes()),
+            None => buf.extend_from_slice(route.as_bytes()),
         };
         buf.push(32);
 
         buf.extend_from_slice("HTTP/1.1".as_bytes());
         buf.extend_from_slice(CRLF);
 
+        let mut cookie = self.cookie;
         for (k, v) in self.headers {
+            // the length of the content given is what gets sent
+            if self.content.is_some() && k.eq_ignore_ascii_case("Content-Length") {
+                continue;
+            }
             buf.extend_from_slice(k.as_bytes());
             buf.extend_from_slice(": ".as_bytes());
             buf.extend_from_slice(v.as_bytes());
+            if k.eq_ignore_ascii_case("Cookie") {
+                if let Some(c) = cookie.take() {
+                    buf.extend_from_slice(format!("; {}", c).as_bytes());
+                }
+            }
             buf.extend_from_slice(CRLF);
         }
+        if let Some(c) = cookie {
+            buf.extend_from_slice(format!("Cookie: {}\r\n", c).as_bytes());
+        }
+        // Content-Length is part of the head, the content follows the empty line
+        if self.content.is_some() {
+            buf.extend_from_slice(format!("Content-Length: {}\r\n", self.content_len).as_bytes());
+        }
         buf.extend_from_slice(CRLF);
-
-        // Content-Length and Content
-        if self.content_len > 0 {
-            buf.extend_from_slice("Content-Length: ".as_bytes());
-            buf.extend_from_slice(format!("{}\r\n", self.content_len).as_bytes());
-            if let Some(c) = self.content {
-                buf.extend_from_slice(&c);
-            };
+        if let Some(c) = self.content {
+            buf.extend_from_slice(&c);
         }
         buf
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(route: &str, q: &QueryBuilder) -> String {
+        let mut req = RequestBuilder::new();
+        req.route(route).query(q);
+        let bytes = req.build();
+        let line = String::from_utf8(bytes).unwrap();
+        line.split(' ').nth(1).unwrap().to_string()
+    }
+
+    #[test]
+    fn query_joins_the_route() {
+        let mut q = QueryBuilder::new();
+        q.append_pair("a b", "1&2");
+        assert_eq!(target("/p", &q), "/p?a+b=1%262");
+        assert_eq!(target("/p?x=1", &q), "/p?x=1&a+b=1%262");
+        assert_eq!(target("/p?", &q), "/p?a+b=1%262");
+        assert_eq!(target("/p?x=1&", &q), "/p?x=1&a+b=1%262");
+        assert_eq!(target("/p#f", &q), "/p?a+b=1%262#f");
+        assert_eq!(target("/p", &QueryBuilder::new()), "/p");
+    }
+}
@@ -0,0 +1,190 @@
+use crate::tls::pinning::host_key;
+use log::debug;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+// Turns host names into addresses to connect to, in order of preference.
+// IP literals never get here, they are connected to as they are.
+pub trait Resolver: Send + Sync {
+    fn resolve(&self, host: &str) -> Result<Vec<IpAddr>>;
+}
+
+// What the OS resolves to, through getaddrinfo on unix. Blocks while resolving.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str) -> Result<Vec<IpAddr>> {
+        let addrs = (host, 0).to_socket_addrs()?;
+        Ok(addrs.map(|a| a.ip()).collect())
+    }
+}
+
+// Fixed addresses per host, like /etc/hosts. Hosts not in it go to the fallback,
+// without one they fail to resolve.
+#[derive(Clone, Default)]
+pub struct StaticResolver {
+    hosts: HashMap<String, Vec<IpAddr>>,
+    fallback: Option<Arc<dyn Resolver>>,
+}
+
+impl StaticResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Replaces the addresses host had before.
+    pub fn host(mut self, host: &str, addrs: &[IpAddr]) -> Self {
+        self.hosts.insert(host_key(host), addrs.to_vec());
+        self
+    }
+
+    pub fn fallback(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.fallback = Some(resolver);
+        self
+    }
+}
+
+impl Resolver for StaticResolver {
+    fn resolve(&self, host: &str) -> Result<Vec<IpAddr>> {
+        if let Some(addrs) = self.hosts.get(&host_key(host)) {
+            return Ok(addrs.clone());
+        }
+        match self.fallback {
+            Some(ref f) => f.resolve(host),
+            None => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("no address for {}", host),
+            )),
+        }
+    }
+}
+
+// Keeps what inner resolved for ttl, failures aren't kept.
+// The system resolver doesn't tell how long records are valid, so the ttl is the same for all.
+pub struct CachingResolver {
+    inner: Arc<dyn Resolver>,
+    ttl: Duration,
+    cache: Mutex<HashMap<String, (Instant, Vec<IpAddr>)>>,
+}
+
+impl CachingResolver {
+    pub fn new(inner: Arc<dyn Resolver>, ttl: Duration) -> Self {
+        Self {
+            inner,
+            ttl,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Forgets everything, the next lookups go to inner.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, (Instant, Vec<IpAddr>)>> {
+        match self.cache.lock() {
+            Ok(c) => c,
+            Err(p) => p.into_inner(),
+        }
+    }
+}
+
+impl Resolver for CachingResolver {
+    fn resolve(&self, host: &str) -> Result<Vec<IpAddr>> {
+        let key = host_key(host);
+        let now = Instant::now();
+        {
+            let cache = self.lock();
+            if let Some((expires, addrs)) = cache.get(&key) {
+                if *expires > now {
+                    debug!("Using cached addresses of {}", host);
+                    return Ok(addrs.clone());
+                }
+            }
+        }
+
+        // resolved without the lock, so a slow lookup doesn't hold up other hosts
+        let addrs = self.inner.resolve(host)?;
+        let mut cache = self.lock();
+        cache.retain(|_, (expires, _)| *expires > now);
+        cache.insert(key, (now + self.ttl, addrs.clone()));
+        Ok(addrs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Resolves every host to 10.0.0.n, n counting the lookups.
+    #[derive(Default)]
+    struct Counting(AtomicUsize);
+
+    impl Resolver for Counting {
+        fn resolve(&self, host: &str) -> Result<Vec<IpAddr>> {
+            if host == "fail.example" {
+                return Err(Error::new(ErrorKind::NotFound, "no such host"));
+            }
+            let n = self.0.fetch_add(1, Ordering::SeqCst) + 1;
+            Ok(vec![IpAddr::from([10, 0, 0, n as u8])])
+        }
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn static_hosts() {
+        let resolver = StaticResolver::new()
+            .host("Example.COM", &[ip("10.0.0.1"), ip("::1")])
+            .host("bücher.example", &[ip("10.0.0.2")]);
+        assert_eq!(
+            resolver.resolve("example.com").unwrap(),
+            [ip("10.0.0.1"), ip("::1")]
+        );
+        assert_eq!(
+            resolver.resolve("xn--bcher-kva.example").unwrap(),
+            [ip("10.0.0.2")]
+        );
+        let e = resolver.resolve("other.example").unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+
+        let resolver = resolver
+            .host("example.com", &[ip("10.0.0.3")])
+            .fallback(Arc::new(Counting::default()));
+        assert_eq!(resolver.resolve("example.com").unwrap(), [ip("10.0.0.3")]);
+        assert_eq!(resolver.resolve("other.example").unwrap(), [ip("10.0.0.1")]);
+    }
+
+    #[test]
+    fn system() {
+        let ips = SystemResolver.resolve("localhost").unwrap();
+        assert!(ips.iter().all(|i| i.is_loopback()), "{:?}", ips);
+    }
+
+    #[test]
+    fn caching() {
+        let inner = Arc::new(Counting::default());
+        let cache = CachingResolver::new(inner.clone(), Duration::from_secs(60));
+        assert_eq!(cache.resolve("a.example").unwrap(), [ip("10.0.0.1")]);
+        assert_eq!(cache.resolve("A.example").unwrap(), [ip("10.0.0.1")]);
+        assert_eq!(cache.resolve("b.example").unwrap(), [ip("10.0.0.2")]);
+        // failures aren't kept
+        assert!(cache.resolve("fail.example").is_err());
+        assert!(cache.resolve("fail.example").is_err());
+        assert_eq!(inner.0.load(Ordering::SeqCst), 2);
+
+        cache.clear();
+        assert_eq!(cache.resolve("a.example").unwrap(), [ip("10.0.0.3")]);
+
+        let expiring = CachingResolver::new(inner.clone(), Duration::ZERO);
+        assert_eq!(expiring.resolve("a.example").unwrap(), [ip("10.0.0.4")]);
+        assert_eq!(expiring.resolve("a.example").unwrap(), [ip("10.0.0.5")]);
+    }
+}
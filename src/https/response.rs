@@ -1,11 +1,16 @@
+use super::decompress;
+use super::parser::{ParseEvent, ResponseParser};
+use super::redirect::Redirect;
+use crate::tls::info::TlsInfo;
 use bytes::{Bytes, BytesMut};
-use log::{debug, info, warn};
 use std::collections::HashMap;
 use std::error;
 use std::fmt;
+use std::io;
+use std::io::Read;
 use std::num::ParseIntError;
-use std::str;
 use std::str::Utf8Error;
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub enum HttpResponseError {
@@ -14,6 +19,17 @@ pub enum HttpResponseError {
     ParseError(ParseIntError),
     NoHeaders,
     InvalidHeader,
+    InvalidStatusLine,
+    InvalidChunk,
+    InvalidChunkSize,
+    HeadersTooLarge,
+    Incomplete,
+    TooManyRedirects(usize),
+    UnsupportedEncoding(String),
+    Decompress(io::Error),
+    // decoded content would be larger than the limit
+    DecompressedTooLarge(usize),
+    Io(io::Error),
 }
 
 impl fmt::Display for HttpResponseError {
@@ -32,6 +48,36 @@ impl fmt::Display for HttpResponseError {
             HttpResponseError::InvalidHeader => {
                 write!(f, "invalid header")
             }
+            HttpResponseError::InvalidStatusLine => {
+                write!(f, "invalid status line")
+            }
+            HttpResponseError::InvalidChunk => {
+                write!(f, "invalid chunk")
+            }
+            HttpResponseError::InvalidChunkSize => {
+                write!(f, "invalid chunk size")
+            }
+            HttpResponseError::HeadersTooLarge => {
+                write!(f, "header section too large")
+            }
+            HttpResponseError::Incomplete => {
+                write!(f, "the response ended before it was complete")
+            }
+            HttpResponseError::TooManyRedirects(max) => {
+                write!(f, "more than {} redirects", max)
+            }
+            HttpResponseError::UnsupportedEncoding(ref enc) => {
+                write!(f, "unsupported content encoding: {}", enc)
+            }
+            HttpResponseError::Decompress(ref e) => {
+                write!(f, "failed to decompress the content: {}", e)
+            }
+            HttpResponseError::DecompressedTooLarge(max) => {
+                write!(f, "decompressed content is larger than {} bytes", max)
+            }
+            HttpResponseError::Io(ref e) => {
+                write!(f, "io error while reading the response: {}", e)
+            }
         }
     }
 }
@@ -39,180 +85,191 @@ impl fmt::Display for HttpResponseError {
 impl error::Error for HttpResponseError {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match *self {
-            HttpResponseError::Empty => None,
             HttpResponseError::ParseError(ref e) => Some(e),
             HttpResponseError::ParseStrError(ref e) => Some(e),
-            HttpResponseError::NoHeaders => None,
-            HttpResponseError::InvalidHeader => None,
+            HttpResponseError::Decompress(ref e) => Some(e),
+            HttpResponseError::Io(ref e) => Some(e),
+            _ => None,
         }
     }
 }
 
-pub type HttpResult<T> = Result<T, HttpResponseError>;
-pub type HeaderMap<'r> = HashMap<&'r str, &'r str>;
-
-fn read_chunk_length(buf: &[u8]) -> HttpResult<usize> {
-    let str = match str::from_utf8(buf) {
-        Ok(o) => o,
-        Err(e) => return Err(HttpResponseError::ParseStrError(e)),
-    };
-    match usize::from_str_radix(str, 16) {
-        Ok(num) => Ok(num),
-        Err(e) => Err(HttpResponseError::ParseError(e)),
+impl From<HttpResponseError> for io::Error {
+    fn from(e: HttpResponseError) -> Self {
+        match e {
+            HttpResponseError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
     }
 }
 
+pub type HttpResult<T> = Result<T, HttpResponseError>;
+pub type HeaderMap = HashMap<String, String>;
+
 #[derive(Debug)]
-pub struct Response<'r> {
+pub struct Response {
     pub status_code: u16,
-    pub headers: HeaderMap<'r>,
+    pub reason: String,
+    pub headers: HeaderMap,
+    pub trailers: HeaderMap,
     pub content: Bytes,
+    // redirects followed to get here, oldest first
+    pub redirects: Vec<Redirect>,
+    // TLS session the response came over, None for plain HTTP
+    pub tls: Option<Arc<TlsInfo>>,
+}
+
+// Repeated fields are combined into one list under the first spelling of the name.
+// Set-Cookie values can contain commas themselves, they are kept one per line instead.
+pub(crate) fn insert_header(map: &mut HeaderMap, name: String, value: String) {
+    let sep = match name.eq_ignore_ascii_case("Set-Cookie") {
+        true => "\n",
+        false => ", ",
+    };
+    match map.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(&name)) {
+        Some((_, v)) => {
+            v.push_str(sep);
+            v.push_str(&value);
+        }
+        None => {
+            map.insert(name, value);
+        }
+    }
 }
 
-impl<'r> Response<'r> {
-    pub fn from_slice(d: &'r [u8]) -> HttpResult<Self> {
+impl Response {
+    pub fn from_slice(d: &[u8]) -> HttpResult<Self> {
         if d.is_empty() {
             return Err(HttpResponseError::Empty);
         };
 
-        let mut headers: HashMap<&'r str, &'r str> = HashMap::new();
+        let mut parser = ResponseParser::new();
+        parser.feed(d);
+        parser.finish();
+        Self::read_from(&mut io::empty(), &mut parser)
+    }
 
-        let mut iter = d.iter();
+    // Reads a single response, pulling bytes from the reader only when the parser needs them.
+    // The parser keeps whatever was read past the end of the response for the next call.
+    pub fn read_from<R: Read>(r: &mut R, parser: &mut ResponseParser) -> HttpResult<Self> {
+        parser.reset();
 
-        // loop to split into lines
-        let mut lines: Vec<&'r str> = Vec::with_capacity(10);
-        let mut line_break = false;
-        let mut count = 0;
-        let mut t_count: usize = 0;
+        let mut status_code = 0;
+        let mut reason = String::new();
+        let mut headers: HeaderMap = HashMap::new();
+        let mut trailers: HeaderMap = HashMap::new();
+        let mut content = BytesMut::new();
+        let mut tmp: [u8; 4096] = [0; 4096];
 
-        while let Some(byte) = iter.next() {
-            match byte {
-                b'\r' => {
-                    if iter.next().is_some_and(|v| v == &b'\n') {
-                        line_break = true;
-                        count += 2;
-                        continue;
-                    }
-                }
-                _ => {
-                    if line_break {
-                        let line = match str::from_utf8(&d[t_count..t_count + count - 2]) {
-                            Ok(o) => o,
-                            Err(e) => return Err(HttpResponseError::ParseStrError(e)),
-                        };
-                        lines.push(line);
-                        line_break = false;
-                        t_count += count;
-                        count = 0;
-                    };
-                    count += 1
+        loop {
+            match parser.next_event()? {
+                ParseEvent::Status {
+                    code, reason: r, ..
+                } => {
+                    status_code = code;
+                    reason = r;
                 }
+                ParseEvent::Header { name, value } => insert_header(&mut headers, name, value),
+                ParseEvent::HeadersComplete | ParseEvent::ChunkExtension { .. } => {}
+                ParseEvent::Body(b) => content.extend_from_slice(&b),
+                ParseEvent::Trailer { name, value } => insert_header(&mut trailers, name, value),
+                ParseEvent::Done => break,
+                ParseEvent::NeedMore => match r.read(&mut tmp) {
+                    Ok(0) => parser.finish(),
+                    Ok(n) => parser.feed(&tmp[..n]),
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(HttpResponseError::Io(e)),
+                },
             }
         }
 
-        let mut lines_iter = lines.into_iter();
+        Ok(Self {
+            status_code,
+            reason,
+            headers,
+            trailers,
+            content: content.freeze(),
+            redirects: vec![],
+            tls: None,
+        })
+    }
 
-        // status code
-        let status_code = match lines_iter.next() {
-            Some(line) => match line[9..12].parse::<u16>() {
-                Ok(str) => str,
-                Err(e) => return Err(HttpResponseError::ParseError(e)),
-            },
-            None => return Err(HttpResponseError::Empty),
-        };
+    // Decodes the content according to Content-Encoding.
+    // The header is dropped afterwards and Content-Length updated to match.
+    // Responses without a body, e.g. to HEAD or a 204/304, are left as they are.
+    pub fn decompress(&mut self) -> HttpResult<()> {
+        self.decompress_limited(decompress::DEFAULT_MAX_SIZE)
+    }
 
-        // headers
-        for line in lines_iter {
-            let mut header = line.split(": ");
-            match header.next() {
-                None => return Err(HttpResponseError::NoHeaders),
-                Some(k) => {
-                    if let Some(v) = header.next() {
-                        headers.insert(k, v);
-                    } else {
-                        return Err(HttpResponseError::InvalidHeader);
-                    }
-                }
+    // Same as decompress(), failing with DecompressedTooLarge once the content
+    // grows past max bytes.
+    pub fn decompress_limited(&mut self, max: usize) -> HttpResult<()> {
+        if self.content.is_empty() || matches!(self.status_code, 204 | 304) {
+            return Ok(());
+        }
+        let key = match self
+            .headers
+            .keys()
+            .find(|k| k.eq_ignore_ascii_case("Content-Encoding"))
+        {
+            Some(k) => k.clone(),
+            None => return Ok(()),
+        };
+        self.content = decompress::decode(self.content.clone(), &self.headers[&key], max)?;
+        self.headers.remove(&key);
+        for (k, v) in self.headers.iter_mut() {
+            if k.eq_ignore_ascii_case("Content-Length") {
+                *v = self.content.len().to_string();
             }
         }
+        Ok(())
+    }
 
-        // content length
-        let content_len = match headers.get("Content-Length") {
-            Some(l) => match l.parse::<usize>() {
-                Ok(n) => Some(n),
-                Err(e) => return Err(HttpResponseError::ParseError(e)),
-            },
-            None => None,
-        };
+    // Case insensitive header lookup.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
 
-        // transfer encoding
-        let mut transfer_encoding = None;
-        if let Some(len) = content_len {
-            return Ok(Self {
-                status_code,
-                headers,
-                content: Bytes::copy_from_slice(&d[t_count..t_count + len]),
-            });
-        } else {
-            match headers.get("Transfer-Encoding") {
-                None => {
-                    warn!("No Content-Length and Transfer-Encoding found! Reading everything");
-                }
-                Some(v) => transfer_encoding = Some(*v),
-            }
-        };
+    // Every Set-Cookie field of the response.
+    pub fn set_cookies(&self) -> impl Iterator<Item = &str> {
+        self.header("Set-Cookie")
+            .into_iter()
+            .flat_map(|v| v.split('\n'))
+    }
 
-        match transfer_encoding {
-            Some("chunked") => {
-                let mut data_read = false;
-                let mut length_read = true;
-                let mut read_length = 0;
-                let mut l_count = 0;
-                let mut c_buf = BytesMut::with_capacity(368);
-                t_count += 2;
-
-                while let Some(byte) = iter.next() {
-                    if data_read {
-                        c_buf.extend_from_slice(&d[0..1 + read_length]);
-                        t_count += read_length + 2;
-                        data_read = false;
-                    }
-                    if length_read {
-                        match byte {
-                            b'\r' => {
-                                iter.nth(0);
-                                length_read = false;
-                                data_read = true;
-
-                                read_length = read_chunk_length(&d[t_count..t_count + l_count])?;
-                                l_count = 0;
-
-                                debug!("Chunk length: {}", read_length);
-
-                                if read_length == 0 {
-                                    break;
-                                }
-                            }
-                            _ => {
-                                l_count += 1;
-                                continue;
-                            }
-                        }
-                    }
-                }
+    // Case insensitive trailer lookup.
+    pub fn trailer(&self, name: &str) -> Option<&str> {
+        self.trailers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
 
-                Ok(Self {
-                    status_code,
-                    headers,
-                    content: c_buf.freeze(),
-                })
-            }
-            Some(_) => unimplemented!(),
-            None => Ok(Self {
-                status_code,
-                headers,
-                content: Bytes::copy_from_slice(&d[t_count..]),
-            }),
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_headers() {
+        let res = Response::from_slice(
+            b"HTTP/1.1 200 OK\r\n\
+              Set-Cookie: a=1; Expires=Wed, 21 Oct 2015 07:28:00 GMT\r\n\
+              Vary: Accept\r\n\
+              set-cookie: b=2\r\n\
+              VARY: Cookie\r\n\
+              Content-Length: 0\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(res.headers.len(), 3);
+        assert_eq!(res.header("vary"), Some("Accept, Cookie"));
+        let cookies: Vec<&str> = res.set_cookies().collect();
+        assert_eq!(
+            cookies,
+            ["a=1; Expires=Wed, 21 Oct 2015 07:28:00 GMT", "b=2"]
+        );
     }
 }
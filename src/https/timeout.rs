@@ -0,0 +1,206 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+
+// Which limit ran out, carried inside io errors of kind TimedOut.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutError {
+    Connect,
+    Handshake,
+    // nothing was received or sent for longer than the read idle timeout
+    ReadIdle,
+    Total,
+}
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TimeoutError::Connect => write!(f, "connecting timed out"),
+            TimeoutError::Handshake => write!(f, "TLS handshake timed out"),
+            TimeoutError::ReadIdle => write!(f, "connection was idle for too long"),
+            TimeoutError::Total => write!(f, "request did not complete in time"),
+        }
+    }
+}
+
+impl error::Error for TimeoutError {}
+
+impl From<TimeoutError> for io::Error {
+    fn from(e: TimeoutError) -> Self {
+        io::Error::new(io::ErrorKind::TimedOut, e)
+    }
+}
+
+// The timeout behind an io error, None if it isn't one of ours.
+pub fn timeout_of(e: &io::Error) -> Option<TimeoutError> {
+    e.get_ref()
+        .and_then(|i| i.downcast_ref::<TimeoutError>())
+        .copied()
+}
+
+// Limits for a request, all of them are off by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Timeouts {
+    pub(crate) connect: Option<Duration>,
+    pub(crate) handshake: Option<Duration>,
+    pub(crate) read_idle: Option<Duration>,
+    pub(crate) total: Option<Duration>,
+}
+
+impl Timeouts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // For connecting to one of the addresses of a host, the attempts race within it.
+    pub fn connect(mut self, t: Duration) -> Self {
+        self.connect = Some(t);
+        self
+    }
+
+    pub fn handshake(mut self, t: Duration) -> Self {
+        self.handshake = Some(t);
+        self
+    }
+
+    // Longest wait for the next bytes of a response.
+    pub fn read_idle(mut self, t: Duration) -> Self {
+        self.read_idle = Some(t);
+        self
+    }
+
+    // Whole request, redirects included.
+    pub fn total(mut self, t: Duration) -> Self {
+        self.total = Some(t);
+        self
+    }
+
+    // Deadline of a request starting now.
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.total.map(|t| Instant::now() + t)
+    }
+}
+
+pub(crate) type Limit = (Instant, TimeoutError);
+
+// The limit that runs out first.
+pub(crate) fn earliest(a: Option<Limit>, b: Option<Limit>) -> Option<Limit> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if a.0 <= b.0 { a } else { b }),
+        (a, b) => a.or(b),
+    }
+}
+
+// Time left before the limit, an error once it passed.
+pub(crate) fn remaining(limit: Limit) -> io::Result<Duration> {
+    let left = limit.0.saturating_duration_since(Instant::now());
+    match left.is_zero() {
+        true => Err(limit.1.into()),
+        false => Ok(left),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::https::resolve::SystemResolver;
+    use crate::https::transport::PlainStream;
+    use crate::tls::tls_stream::TlsStream;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+
+    const SHORT: Duration = Duration::from_millis(100);
+
+    // Accepts one connection and keeps it open without sending anything, for as long as
+    // the test runs.
+    fn silent_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (_sock, _) = listener.accept().unwrap();
+            thread::sleep(Duration::from_secs(10));
+        });
+        addr
+    }
+
+    fn kind(e: &io::Error) -> Option<TimeoutError> {
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut, "{}", e);
+        timeout_of(e)
+    }
+
+    #[test]
+    fn limits() {
+        let now = Instant::now();
+        let soon = Some((now + SHORT, TimeoutError::Connect));
+        let later = Some((now + SHORT * 2, TimeoutError::Total));
+        assert_eq!(earliest(soon, later), soon);
+        assert_eq!(earliest(later, soon), soon);
+        assert_eq!(earliest(None, later), later);
+        assert_eq!(earliest(None, None), None);
+
+        assert!(remaining((now + SHORT, TimeoutError::Total)).unwrap() <= SHORT);
+        let e = remaining((now, TimeoutError::Handshake)).unwrap_err();
+        assert_eq!(kind(&e), Some(TimeoutError::Handshake));
+        assert_eq!(timeout_of(&io::Error::other("other")), None);
+
+        assert_eq!(Timeouts::new().deadline(), None);
+        assert!(Timeouts::new().total(SHORT).deadline().unwrap() > now);
+    }
+
+    #[test]
+    fn read_idle() {
+        let timeouts = Timeouts::new().read_idle(SHORT);
+        let addr = silent_server();
+        let mut io = PlainStream::connect(&addr, &SystemResolver, &timeouts, None).unwrap();
+        let start = Instant::now();
+        let e = io.read(&mut [0; 8]).unwrap_err();
+        assert_eq!(kind(&e), Some(TimeoutError::ReadIdle));
+        assert!(start.elapsed() < SHORT * 10);
+    }
+
+    #[test]
+    fn total_wins_over_a_longer_read_idle() {
+        let timeouts = Timeouts::new().read_idle(Duration::from_secs(5));
+        let addr = silent_server();
+        let deadline = Some(Instant::now() + SHORT);
+        let mut io = PlainStream::connect(&addr, &SystemResolver, &timeouts, deadline).unwrap();
+        let e = io.read(&mut [0; 8]).unwrap_err();
+        assert_eq!(kind(&e), Some(TimeoutError::Total));
+        // and everything after it fails right away
+        let e = io.read(&mut [0; 8]).unwrap_err();
+        assert_eq!(kind(&e), Some(TimeoutError::Total));
+    }
+
+    #[test]
+    fn handshake() {
+        let timeouts = Timeouts::new().handshake(SHORT);
+        let addr = silent_server();
+        let res = TlsStream::connect(
+            None,
+            "localhost",
+            true,
+            &addr,
+            &SystemResolver,
+            &timeouts,
+            None,
+        );
+        assert_eq!(kind(&res.err().unwrap()), Some(TimeoutError::Handshake));
+
+        // the total timeout is reported when it runs out first
+        let addr = silent_server();
+        let timeouts = Timeouts::new().handshake(Duration::from_secs(5));
+        let deadline = Some(Instant::now() + SHORT);
+        let res = TlsStream::connect(
+            None,
+            "localhost",
+            true,
+            &addr,
+            &SystemResolver,
+            &timeouts,
+            deadline,
+        );
+        assert_eq!(kind(&res.err().unwrap()), Some(TimeoutError::Total));
+    }
+}
@@ -0,0 +1,447 @@
+use super::connect_to::ConnectTo;
+use super::resolve::Resolver;
+use super::timeout::{self, Limit, TimeoutError, Timeouts};
+use super::url::Url;
+use crate::tls::info::TlsInfo;
+use crate::tls::tls_stream::TlsStream;
+use log::{debug, info};
+use rustls::ClientConfig;
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Connection a request goes over, TLS or not depending on the url's scheme.
+pub enum Transport {
+    Plain(PlainStream),
+    Tls(Box<TlsStream>),
+}
+
+impl Transport {
+    // Opens a plain connection for http:// and ws://, TLS for https:// and wss://.
+    // config is only used for TLS, the default one is used without it.
+    pub fn connect(
+        url: &Url,
+        config: Option<&Arc<ClientConfig>>,
+        connect_to: &ConnectTo,
+        resolver: &dyn Resolver,
+        timeouts: &Timeouts,
+        deadline: Option<Instant>,
+    ) -> Result<Self> {
+        let addr = connect_to.addr_for(url);
+        if is_plain(url.scheme()) {
+            let io = PlainStream::connect(&addr, resolver, timeouts, deadline)?;
+            return Ok(Transport::Plain(io));
+        }
+        if !matches!(url.scheme(), "https" | "wss") {
+            let msg = format!("unsupported scheme {}", url.scheme());
+            return Err(Error::new(ErrorKind::InvalidInput, msg));
+        }
+        let name = connect_to.server_name_for(url);
+        let sni = connect_to.sni_for(url);
+        let io = TlsStream::connect(config, name, sni, &addr, resolver, timeouts, deadline)?;
+        Ok(Transport::Tls(Box::new(io)))
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self, Transport::Tls(..))
+    }
+
+    // Protocol picked through ALPN, always None without TLS.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        match self {
+            Transport::Plain(..) => None,
+            Transport::Tls(io) => io.alpn_protocol(),
+        }
+    }
+
+    // What was negotiated, always None without TLS.
+    pub fn tls_info(&self) -> Option<Arc<TlsInfo>> {
+        match self {
+            Transport::Plain(..) => None,
+            Transport::Tls(io) => io.tls_info(),
+        }
+    }
+
+    // Only TLS 1.3 connections can send early data, see TlsStream::write_early_data().
+    pub fn write_early_data(&mut self, data: &[u8]) -> Result<bool> {
+        match self {
+            Transport::Plain(..) => Ok(false),
+            Transport::Tls(io) => io.write_early_data(data),
+        }
+    }
+
+    pub fn complete_handshake(&mut self) -> Result<()> {
+        match self {
+            Transport::Plain(..) => Ok(()),
+            Transport::Tls(io) => io.complete_handshake(),
+        }
+    }
+
+    pub fn is_stale(&self) -> bool {
+        match self {
+            Transport::Plain(io) => is_stale(&io.sock),
+            Transport::Tls(io) => io.is_stale(),
+        }
+    }
+
+    pub fn set_read_idle_timeout(&mut self, t: Option<Duration>) {
+        match self {
+            Transport::Plain(io) => io.read_idle = t,
+            Transport::Tls(io) => io.set_read_idle_timeout(t),
+        }
+    }
+
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        match self {
+            Transport::Plain(io) => io.deadline = deadline.map(|d| (d, TimeoutError::Total)),
+            Transport::Tls(io) => io.set_deadline(deadline),
+        }
+    }
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            Transport::Plain(io) => io.read(buf),
+            Transport::Tls(io) => io.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            Transport::Plain(io) => io.write(buf),
+            Transport::Tls(io) => io.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            Transport::Plain(io) => io.flush(),
+            Transport::Tls(io) => io.flush(),
+        }
+    }
+}
+
+// TcpStream with the same timeout handling as TlsStream.
+pub struct PlainStream {
+    sock: TcpStream,
+    read_idle: Option<Duration>,
+    deadline: Option<Limit>,
+}
+
+impl PlainStream {
+    pub fn connect(
+        addr: &str,
+        resolver: &dyn Resolver,
+        timeouts: &Timeouts,
+        deadline: Option<Instant>,
+    ) -> Result<Self> {
+        let total = deadline.map(|d| (d, TimeoutError::Total));
+        let connect = timeouts
+            .connect
+            .map(|t| (Instant::now() + t, TimeoutError::Connect));
+        let sock = connect_tcp(addr, resolver, timeout::earliest(connect, total))?;
+        info!("Connected to {} without TLS", addr);
+
+        Ok(Self {
+            sock,
+            read_idle: timeouts.read_idle,
+            deadline: total,
+        })
+    }
+}
+
+impl Read for PlainStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        arm(&self.sock, self.read_idle, self.deadline)?;
+        self.sock
+            .read(buf)
+            .map_err(|e| check_timeout(e, self.deadline))
+    }
+}
+
+impl Write for PlainStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        arm(&self.sock, self.read_idle, self.deadline)?;
+        self.sock
+            .write(buf)
+            .map_err(|e| check_timeout(e, self.deadline))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.sock.flush()
+    }
+}
+
+pub fn is_plain(scheme: &str) -> bool {
+    matches!(scheme, "http" | "ws")
+}
+
+// Time to wait for a connection attempt before starting the next one, RFC 8305 5.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+// Resolves addr, "host:port", and races connections to its addresses the Happy Eyeballs
+// way (RFC 8305): IPv6 and IPv4 addresses take turns, a new attempt starts whenever the
+// last one failed or took longer than the attempt delay, and the first to connect wins.
+// All of it has to be done within the limit if there is one.
+pub(crate) fn connect_tcp(
+    addr: &str,
+    resolver: &dyn Resolver,
+    limit: Option<Limit>,
+) -> Result<TcpStream> {
+    let mut queue = interleave(resolve_addr(addr, resolver)?);
+    if queue.len() == 1 {
+        return connect_one(queue[0], limit);
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let mut pending = 0;
+    let mut last = None;
+    loop {
+        if let Some(a) = queue.pop_front() {
+            let t = limit.map(timeout::remaining).transpose()?;
+            let tx = tx.clone();
+            debug!("Trying {}", a);
+            // attempts that lose the race finish on their own, their sockets are dropped
+            thread::spawn(move || {
+                let _ = tx.send(attempt(a, t));
+            });
+            pending += 1;
+        }
+        if pending == 0 {
+            break;
+        }
+
+        let res = match queue.is_empty() {
+            true => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            false => rx.recv_timeout(CONNECTION_ATTEMPT_DELAY),
+        };
+        match res {
+            Ok(Ok(sock)) => return Ok(sock),
+            Ok(Err(e)) => {
+                pending -= 1;
+                last = Some(e);
+            }
+            Err(_) => {}
+        }
+    }
+
+    match (last, limit) {
+        (Some(e), Some(l)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+            Err(l.1.into())
+        }
+        (Some(e), _) => Err(e),
+        (None, _) => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "no address to connect to",
+        )),
+    }
+}
+
+fn connect_one(addr: SocketAddr, limit: Option<Limit>) -> Result<TcpStream> {
+    let t = limit.map(timeout::remaining).transpose()?;
+    match (attempt(addr, t), limit) {
+        (Err(e), Some(l)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+            Err(l.1.into())
+        }
+        (res, _) => res,
+    }
+}
+
+fn attempt(addr: SocketAddr, t: Option<Duration>) -> Result<TcpStream> {
+    match t {
+        Some(t) => TcpStream::connect_timeout(&addr, t),
+        None => TcpStream::connect(addr),
+    }
+}
+
+// IP literals are used as they are, names go through the resolver.
+fn resolve_addr(addr: &str, resolver: &dyn Resolver) -> Result<Vec<SocketAddr>> {
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("invalid address {}", addr));
+    let (host, port) = addr.rsplit_once(':').ok_or_else(invalid)?;
+    let port: u16 = port.parse().map_err(|_| invalid())?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    let ips = resolver.resolve(host)?;
+    Ok(ips
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect())
+}
+
+// Alternates between the families, starting with the one the resolver preferred, RFC 8305 4.
+fn interleave(addrs: Vec<SocketAddr>) -> VecDeque<SocketAddr> {
+    let first_v6 = addrs.first().is_some_and(|a| a.is_ipv6());
+    let (mut preferred, mut other): (VecDeque<_>, VecDeque<_>) =
+        addrs.into_iter().partition(|a| a.is_ipv6() == first_v6);
+    let mut out = VecDeque::with_capacity(preferred.len() + other.len());
+    while !preferred.is_empty() || !other.is_empty() {
+        out.extend(preferred.pop_front());
+        out.extend(other.pop_front());
+    }
+    out
+}
+
+// Socket timeouts for the next IO, the smaller of idle and what's left until the deadline.
+pub(crate) fn arm(
+    sock: &TcpStream,
+    read_idle: Option<Duration>,
+    deadline: Option<Limit>,
+) -> Result<()> {
+    let mut t = read_idle;
+    if let Some(d) = deadline {
+        let left = timeout::remaining(d)?;
+        t = Some(t.map_or(left, |t| t.min(left)));
+    }
+    sock.set_read_timeout(t)?;
+    sock.set_write_timeout(t)
+}
+
+// Blocking sockets report an expired timeout as WouldBlock or TimedOut, depending on the OS.
+pub(crate) fn check_timeout(e: Error, deadline: Option<Limit>) -> Error {
+    let expired = matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut);
+    if !expired || timeout::timeout_of(&e).is_some() {
+        return e;
+    }
+    match deadline {
+        Some((at, kind)) if Instant::now() >= at => kind.into(),
+        _ => TimeoutError::ReadIdle.into(),
+    }
+}
+
+// Checks without blocking whether an idle connection was closed by the peer,
+// or got data nobody asked for. Either way it can't be reused.
+pub(crate) fn is_stale(sock: &TcpStream) -> bool {
+    if sock.set_nonblocking(true).is_err() {
+        return true;
+    }
+    let mut b = [0u8; 1];
+    let res = sock.peek(&mut b);
+    let _ = sock.set_nonblocking(false);
+    !matches!(res, Err(ref e) if e.kind() == ErrorKind::WouldBlock)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::https::resolve::{StaticResolver, SystemResolver};
+    use std::net::TcpListener;
+
+    // Plain connection and the server's end of it.
+    fn pair() -> (Transport, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let timeouts = Timeouts::default();
+        let io = PlainStream::connect(&addr, &SystemResolver, &timeouts, None).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (Transport::Plain(io), server)
+    }
+
+    // The peer's FIN or data takes a moment to arrive.
+    fn becomes_stale(io: &Transport) -> bool {
+        let until = Instant::now() + Duration::from_secs(5);
+        while !io.is_stale() {
+            if Instant::now() > until {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+
+    #[test]
+    fn stale_connections() {
+        let (mut io, mut server) = pair();
+        assert!(!io.is_stale());
+        // checking doesn't take the data
+        server.write_all(b"x").unwrap();
+        assert!(becomes_stale(&io));
+        let mut b = [0; 1];
+        io.set_read_idle_timeout(Some(Duration::from_secs(5)));
+        assert_eq!(io.read(&mut b).unwrap(), 1);
+        assert!(!io.is_stale());
+        assert!(!io.is_tls());
+
+        let (io, server) = pair();
+        drop(server);
+        assert!(becomes_stale(&io));
+    }
+
+    fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn families_take_turns() {
+        let v6_first = addrs(&["[::1]:1", "[::2]:1", "[::3]:1", "10.0.0.1:1", "10.0.0.2:1"]);
+        assert_eq!(
+            Vec::from(interleave(v6_first)),
+            addrs(&["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "[::3]:1"])
+        );
+        let v4_first = addrs(&["10.0.0.1:1", "[::1]:1", "10.0.0.2:1"]);
+        assert_eq!(
+            Vec::from(interleave(v4_first)),
+            addrs(&["10.0.0.1:1", "[::1]:1", "10.0.0.2:1"])
+        );
+        assert!(interleave(vec![]).is_empty());
+    }
+
+    #[test]
+    fn addresses() {
+        let resolver = StaticResolver::new().host("example.com", &["10.0.0.1".parse().unwrap()]);
+        assert_eq!(
+            resolve_addr("example.com:8080", &resolver).unwrap(),
+            addrs(&["10.0.0.1:8080"])
+        );
+        // literals skip the resolver
+        assert_eq!(
+            resolve_addr("[::1]:443", &resolver).unwrap(),
+            addrs(&["[::1]:443"])
+        );
+        assert_eq!(
+            resolve_addr("127.0.0.1:80", &resolver).unwrap(),
+            addrs(&["127.0.0.1:80"])
+        );
+        for bad in ["example.com", "example.com:http", "example.com:70000"] {
+            let e = resolve_addr(bad, &resolver).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidInput, "{}", bad);
+        }
+        assert!(resolve_addr("other.example:80", &resolver).is_err());
+    }
+
+    #[test]
+    fn racing_falls_back_to_what_works() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // nothing listens on ::1, that attempt fails or isn't possible at all
+        let ips = ["::1".parse().unwrap(), "127.0.0.1".parse().unwrap()];
+        let resolver = StaticResolver::new().host("dual.example", &ips);
+        let addr = format!("dual.example:{}", port);
+        let limit = Some((
+            Instant::now() + Duration::from_secs(5),
+            TimeoutError::Connect,
+        ));
+        let sock = connect_tcp(&addr, &resolver, limit).unwrap();
+        assert_eq!(sock.peer_addr().unwrap().port(), port);
+
+        // all of them failing hands back the last error
+        drop(listener);
+        let e = connect_tcp(&addr, &resolver, limit).unwrap_err();
+        assert_ne!(e.kind(), ErrorKind::TimedOut);
+
+        let resolver = StaticResolver::new().host("empty.example", &[]);
+        let e = connect_tcp("empty.example:80", &resolver, None).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    }
+}
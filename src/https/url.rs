@@ -1,80 +1,795 @@
-use regex::Regex;
-use std::fmt::{Debug, Formatter};
-// parsing urls.
+use super::query::QueryPairs;
+use std::borrow::Cow;
+use std::error;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+// parsing urls, RFC 3986.
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum UrlError {
+    Empty,
+    MissingScheme,
+    InvalidScheme,
+    InvalidUserinfo,
+    EmptyHost,
+    InvalidHost,
+    InvalidIdna,
+    InvalidIpv6,
+    InvalidPort,
+    InvalidPath,
+    InvalidQuery,
+    InvalidFragment,
+}
+
+impl Display for UrlError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            UrlError::Empty => write!(f, "the url is empty"),
+            UrlError::MissingScheme => write!(f, "the url has no scheme"),
+            UrlError::InvalidScheme => write!(f, "invalid scheme"),
+            UrlError::InvalidUserinfo => write!(f, "invalid userinfo"),
+            UrlError::EmptyHost => write!(f, "the url has no host"),
+            UrlError::InvalidHost => write!(f, "invalid host"),
+            UrlError::InvalidIdna => write!(f, "invalid internationalized domain name"),
+            UrlError::InvalidIpv6 => write!(f, "invalid IPv6 address"),
+            UrlError::InvalidPort => write!(f, "invalid port"),
+            UrlError::InvalidPath => write!(f, "invalid path"),
+            UrlError::InvalidQuery => write!(f, "invalid query"),
+            UrlError::InvalidFragment => write!(f, "invalid fragment"),
+        }
+    }
+}
+
+impl error::Error for UrlError {}
+
+impl From<UrlError> for io::Error {
+    fn from(e: UrlError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
+}
+
+pub type UrlResult<T> = Result<T, UrlError>;
+
+// Host of a url with IP literals parsed, IPv6 ones without their brackets.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Host<'h> {
+    Domain(&'h str),
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+}
+
+// Absolute url, the components borrow from the parsed string.
+#[derive(Clone, PartialEq)]
 pub struct Url<'a> {
-    route: &'a str,
-    domain: &'a str,
-    scheme: &'a str,
-    port: u16,
-    query: &'a str,
+    serialization: &'a str,
+    // lowercase
+    scheme: Cow<'a, str>,
+    authority: Option<&'a str>,
+    userinfo: Option<&'a str>,
+    // IPv6 literals keep their brackets, Unicode names are in punycode
+    host: Option<Cow<'a, str>>,
+    port: Option<u16>,
+    path: &'a str,
+    query: Option<&'a str>,
+    fragment: Option<&'a str>,
+    // path and query as sent in the request line
+    target: Cow<'a, str>,
 }
 
 impl Debug for Url<'_> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         f.debug_struct("Url")
-            .field("route", &self.route)
-            .field("domain", &self.domain)
             .field("scheme", &self.scheme)
+            .field("userinfo", &self.userinfo)
+            .field("host", &self.host)
             .field("port", &self.port)
+            .field("path", &self.path)
             .field("query", &self.query)
+            .field("fragment", &self.fragment)
             .finish()
     }
 }
 
+impl Display for Url<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.serialization)
+    }
+}
+
 impl<'a> Url<'a> {
-    pub fn new(u: &'a str) -> Result<Url<'a>, Box<dyn std::error::Error>> {
-        let reg =
-            Regex::new(r"(?<scheme>.*?)://(?<domain>.*?[^\\]?)(?<route>/.*?[^\?]*)(?<query>.*)")?;
-        let matches = match reg.captures(u) {
-            None => return Err("Invalid URL")?,
-            Some(o) => o,
-        };
+    pub fn new(u: &'a str) -> UrlResult<Url<'a>> {
+        if u.is_empty() {
+            return Err(UrlError::Empty);
+        }
 
-        let scheme = match &matches.name("scheme") {
-            None => return Err("Schema not found, url invalid.")?,
-            Some(o) => o.as_str(),
+        // scheme ":" hier-part [ "?" query ] [ "#" fragment ]
+        let Parts {
+            scheme,
+            authority,
+            path,
+            query,
+            fragment,
+        } = split(u);
+        let scheme = match scheme {
+            Some(s) => s,
+            None => match u.split_once(':') {
+                Some((s, _)) if !s.is_empty() && !s.contains(['/', '?', '#']) => {
+                    return Err(UrlError::InvalidScheme)
+                }
+                _ => return Err(UrlError::MissingScheme),
+            },
         };
-        let route = match &matches.name("route") {
-            None => "/",
-            Some(o) => o.as_str(),
+        let scheme = match scheme.bytes().any(|b| b.is_ascii_uppercase()) {
+            true => Cow::Owned(scheme.to_ascii_lowercase()),
+            false => Cow::Borrowed(scheme),
         };
-        let domain = match &matches.name("domain") {
-            None => return Err("Domain not found!")?,
-            Some(o) => o.as_str(),
+
+        let (userinfo, host, port) = match authority {
+            Some(a) => {
+                let (u, h, p) = parse_authority(a)?;
+                (u, Some(h), p)
+            }
+            None => (None, None, None),
         };
-        let query = &matches.name("query").map_or("", |o| o.as_str());
-        let port = match scheme {
-            "http" => 80,
-            "https" => 443,
-            "ftp" => 21,
-            _ => 1919, // IDK
+
+        if !path.bytes().all(|b| is_pchar(b) || b == b'/') || !valid_pct(path) {
+            return Err(UrlError::InvalidPath);
+        }
+        if let Some(q) = query {
+            if !q.bytes().all(|b| is_pchar(b) || b == b'/' || b == b'?') || !valid_pct(q) {
+                return Err(UrlError::InvalidQuery);
+            }
+        }
+        if let Some(f) = fragment {
+            if !f.bytes().all(|b| is_pchar(b) || b == b'/' || b == b'?') || !valid_pct(f) {
+                return Err(UrlError::InvalidFragment);
+            }
+        }
+        // http(s) and ws(s) urls always name a host
+        if default_port(&scheme).is_some() && host.as_ref().is_none_or(|h| h.is_empty()) {
+            return Err(UrlError::EmptyHost);
+        }
+
+        let target = match (path.is_empty(), query) {
+            (true, None) => Cow::Borrowed("/"),
+            (true, Some(q)) => Cow::Owned(format!("/?{}", q)),
+            (false, Some(q)) => {
+                // path and query are next to each other in the input
+                let start = path.as_ptr() as usize - u.as_ptr() as usize;
+                Cow::Borrowed(&u[start..start + path.len() + 1 + q.len()])
+            }
+            (false, None) => Cow::Borrowed(path),
         };
 
         Ok(Url {
-            route,
-            domain,
+            serialization: u,
             scheme,
+            authority,
+            userinfo,
+            host,
             port,
+            path,
             query,
+            fragment,
+            target,
         })
     }
+
+    pub fn as_str(&self) -> &'a str {
+        self.serialization
+    }
+
+    pub fn scheme(&self) -> &str {
+        &self.scheme
+    }
+
+    // Userinfo, host and port as written.
+    pub fn authority(&self) -> Option<&'a str> {
+        self.authority
+    }
+
+    pub fn userinfo(&self) -> Option<&'a str> {
+        self.userinfo
+    }
+
+    pub fn username(&self) -> Option<&'a str> {
+        self.userinfo
+            .map(|u| u.split_once(':').map_or(u, |(name, _)| name))
+    }
+
+    pub fn password(&self) -> Option<&'a str> {
+        self.userinfo
+            .and_then(|u| u.split_once(':'))
+            .map(|(_, p)| p)
+    }
+
+    // Host as sent over the wire, IPv6 literals keep their brackets
+    // and Unicode names are converted to punycode.
+    // Empty for urls without an authority.
+    pub fn domain(&self) -> &str {
+        self.host.as_deref().unwrap_or("")
+    }
+
+    pub fn host(&self) -> Option<Host<'_>> {
+        let host = self.host.as_deref()?;
+        if let Some(v6) = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+            // IPvFuture literals are kept as they are
+            return Some(v6.parse().map_or(Host::Domain(host), Host::Ipv6));
+        }
+        Some(host.parse().map_or(Host::Domain(host), Host::Ipv4))
+    }
+
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self.host()? {
+            Host::Ipv4(ip) => Some(IpAddr::V4(ip)),
+            Host::Ipv6(ip) => Some(IpAddr::V6(ip)),
+            Host::Domain(..) => None,
+        }
+    }
+
+    // Host for display, punycode labels are turned back into Unicode.
+    pub fn domain_unicode(&self) -> Cow<'_, str> {
+        let host = self.domain();
+        let punycode = host
+            .split('.')
+            .any(|l| l.get(..4).is_some_and(|p| p.eq_ignore_ascii_case("xn--")));
+        if !punycode {
+            return Cow::Borrowed(host);
+        }
+        match idna::domain_to_unicode(host) {
+            (unicode, Ok(())) => Cow::Owned(unicode),
+            (_, Err(_)) => Cow::Borrowed(host),
+        }
+    }
+
+    // Explicit port, or the default one of the scheme.
+    pub fn port(&self) -> Option<u16> {
+        self.port.or_else(|| default_port(&self.scheme))
+    }
+
+    pub fn explicit_port(&self) -> Option<u16> {
+        self.port
+    }
+
+    // Value for the Host header, the port is only included when it isn't the default.
+    pub fn host_header(&self) -> Cow<'_, str> {
+        match self.port {
+            Some(p) if Some(p) != default_port(&self.scheme) => {
+                Cow::Owned(format!("{}:{}", self.domain(), p))
+            }
+            _ => Cow::Borrowed(self.domain()),
+        }
+    }
+
+    // host:port, IPv6 literals in brackets.
     pub fn socket_addr(&self) -> String {
-        format!("{}:{}", self.domain, self.port)
+        match self.port() {
+            Some(p) => format!("{}:{}", self.domain(), p),
+            None => self.domain().to_string(),
+        }
     }
-    pub fn route(&self) -> &'a str {
-        self.route
+
+    pub fn path(&self) -> &'a str {
+        match self.path.is_empty() && self.host.is_some() {
+            true => "/",
+            false => self.path,
+        }
     }
-    pub fn domain(&self) -> &'a str {
-        self.domain
+
+    // Path and query, the request target of origin-form requests.
+    pub fn route(&self) -> &str {
+        &self.target
     }
-    pub fn query(&self) -> &'a str {
+
+    // Without the leading "?".
+    pub fn query(&self) -> Option<&'a str> {
         self.query
     }
-    pub fn scheme(&self) -> &'a str {
-        self.scheme
+
+    // Decoded key/value pairs of the query.
+    pub fn query_pairs(&self) -> QueryPairs<'a> {
+        QueryPairs::new(self.query.unwrap_or(""))
+    }
+
+    pub fn fragment(&self) -> Option<&'a str> {
+        self.fragment
     }
-    pub fn port(&self) -> u16 {
-        self.port
+
+    // Resolves a reference against this url, RFC 3986 section 5.2.
+    pub fn join(&self, reference: &str) -> UrlResult<String> {
+        let r = split(reference);
+        let (scheme, authority, path, query) = match (r.scheme, r.authority) {
+            (Some(s), a) => (s, a, remove_dot_segments(r.path), r.query),
+            (None, Some(a)) => (self.scheme(), Some(a), remove_dot_segments(r.path), r.query),
+            (None, None) => {
+                let (path, query) = match r.path {
+                    "" => (self.path.to_string(), r.query.or(self.query)),
+                    p if p.starts_with('/') => (remove_dot_segments(p), r.query),
+                    p => (remove_dot_segments(&self.merge(p)), r.query),
+                };
+                (self.scheme(), self.authority, path, query)
+            }
+        };
+
+        let joined = recompose(scheme, authority, &path, query, r.fragment);
+        // makes sure the result is a valid url
+        Url::new(&joined)?;
+        Ok(joined)
+    }
+
+    // Syntax and scheme based normalization, RFC 3986 section 6.2.2 and 6.2.3.
+    // Scheme and host are lowercased, the default port and dot segments removed,
+    // percent-encoded unreserved characters decoded and the others uppercased.
+    pub fn normalize(&self) -> String {
+        let authority = self.host.as_ref().map(|h| {
+            let mut a = String::new();
+            if let Some(u) = self.userinfo {
+                a.push_str(&normalize_pct(u));
+                a.push('@');
+            }
+            a.push_str(&lowercase_outside_pct(&normalize_pct(h)));
+            if let Some(p) = self.port.filter(|p| Some(*p) != default_port(&self.scheme)) {
+                a.push(':');
+                a.push_str(&p.to_string());
+            }
+            a
+        });
+        let path = match remove_dot_segments(&normalize_pct(self.path)) {
+            p if p.is_empty() && authority.is_some() => "/".to_string(),
+            p => p,
+        };
+        let query = self.query.map(normalize_pct);
+        let fragment = self.fragment.map(normalize_pct);
+        recompose(
+            self.scheme(),
+            authority.as_deref(),
+            &path,
+            query.as_deref(),
+            fragment.as_deref(),
+        )
+    }
+
+    // Appends a relative path to the directory of this url's path, RFC 3986 section 5.2.3.
+    fn merge(&self, path: &str) -> String {
+        if self.authority.is_some() && self.path.is_empty() {
+            return format!("/{}", path);
+        }
+        let dir = &self.path[..self.path.rfind('/').map_or(0, |i| i + 1)];
+        format!("{}{}", dir, path)
+    }
+}
+
+// The components of a uri reference, only path is always there.
+struct Parts<'a> {
+    scheme: Option<&'a str>,
+    authority: Option<&'a str>,
+    path: &'a str,
+    query: Option<&'a str>,
+    fragment: Option<&'a str>,
+}
+
+// Splits a reference into its components without validating them, RFC 3986 appendix B.
+fn split(r: &str) -> Parts<'_> {
+    let (rest, fragment) = match r.split_once('#') {
+        Some((r, f)) => (r, Some(f)),
+        None => (r, None),
+    };
+    let (rest, query) = match rest.split_once('?') {
+        Some((r, q)) => (r, Some(q)),
+        None => (rest, None),
+    };
+    // a colon in the first segment of a relative path doesn't start a scheme
+    let (scheme, rest) = match rest.split_once(':') {
+        Some((s, r)) if is_scheme(s) => (Some(s), r),
+        _ => (None, rest),
+    };
+    let (authority, path) = match rest.strip_prefix("//") {
+        Some(hier) => {
+            let (a, p) = hier.split_at(hier.find('/').unwrap_or(hier.len()));
+            (Some(a), p)
+        }
+        None => (None, rest),
+    };
+    Parts {
+        scheme,
+        authority,
+        path,
+        query,
+        fragment,
+    }
+}
+
+// RFC 3986 section 5.3.
+fn recompose(
+    scheme: &str,
+    authority: Option<&str>,
+    path: &str,
+    query: Option<&str>,
+    fragment: Option<&str>,
+) -> String {
+    let mut out = format!("{}:", scheme);
+    if let Some(a) = authority {
+        out.push_str("//");
+        out.push_str(a);
+    }
+    out.push_str(path);
+    if let Some(q) = query {
+        out.push('?');
+        out.push_str(q);
+    }
+    if let Some(f) = fragment {
+        out.push('#');
+        out.push_str(f);
+    }
+    out
+}
+
+// RFC 3986 section 5.2.4.
+pub fn remove_dot_segments(path: &str) -> String {
+    let mut input = path;
+    let mut output = String::with_capacity(path.len());
+    while !input.is_empty() {
+        if let Some(r) = input.strip_prefix("../") {
+            input = r;
+        } else if let Some(r) = input.strip_prefix("./") {
+            input = r;
+        } else if input.starts_with("/./") {
+            input = &input[2..];
+        } else if input == "/." {
+            input = "/";
+        } else if input.starts_with("/../") {
+            input = &input[3..];
+            pop_segment(&mut output);
+        } else if input == "/.." {
+            input = "/";
+            pop_segment(&mut output);
+        } else if input == "." || input == ".." {
+            input = "";
+        } else {
+            // moves the first segment, with its leading slash, to the output
+            let start = usize::from(input.starts_with('/'));
+            let end = input[start..].find('/').map_or(input.len(), |i| i + start);
+            output.push_str(&input[..end]);
+            input = &input[end..];
+        }
+    }
+    output
+}
+
+fn pop_segment(output: &mut String) {
+    let i = output.rfind('/').unwrap_or(0);
+    output.truncate(i);
+}
+
+// Decodes percent-encoded unreserved characters, uppercases the hex digits of the rest.
+fn normalize_pct(s: &str) -> String {
+    let b = s.as_bytes();
+    let mut out = String::with_capacity(s.len());
+    let mut i = 0;
+    while i < b.len() {
+        let decoded = match b[i] {
+            b'%' if i + 2 < b.len() => std::str::from_utf8(&b[i + 1..i + 3])
+                .ok()
+                .and_then(|h| u8::from_str_radix(h, 16).ok()),
+            _ => None,
+        };
+        match decoded {
+            Some(c) if is_unreserved(c) => out.push(c as char),
+            Some(_) => out.push_str(&s[i..i + 3].to_ascii_uppercase()),
+            None => {
+                let len = s[i..].chars().next().map_or(1, |c| c.len_utf8());
+                out.push_str(&s[i..i + len]);
+                i += len;
+                continue;
+            }
+        }
+        i += 3;
+    }
+    out
+}
+
+// Lowercases everything but the hex digits of percent-encodings, which stay uppercase.
+fn lowercase_outside_pct(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut hex = 0;
+    for c in s.chars() {
+        match c {
+            _ if hex > 0 => {
+                out.push(c);
+                hex -= 1;
+            }
+            '%' => {
+                out.push(c);
+                hex = 2;
+            }
+            _ => out.push(c.to_ascii_lowercase()),
+        }
+    }
+    out
+}
+
+pub fn default_port(scheme: &str) -> Option<u16> {
+    match scheme {
+        "http" | "ws" => Some(80),
+        "https" | "wss" => Some(443),
+        "ftp" => Some(21),
+        _ => None,
+    }
+}
+
+// authority = [ userinfo "@" ] host [ ":" port ]
+fn parse_authority(authority: &str) -> UrlResult<(Option<&str>, Cow<'_, str>, Option<u16>)> {
+    let (userinfo, hostport) = match authority.rsplit_once('@') {
+        Some((u, h)) => (Some(u), h),
+        None => (None, authority),
+    };
+    if let Some(u) = userinfo {
+        if !u
+            .bytes()
+            .all(|b| is_unreserved(b) || is_sub_delim(b) || b == b':' || b == b'%')
+            || !valid_pct(u)
+        {
+            return Err(UrlError::InvalidUserinfo);
+        }
+    }
+
+    let (host, port) = match hostport.starts_with('[') {
+        true => {
+            let end = match hostport.find(']') {
+                Some(i) => i + 1,
+                None => return Err(UrlError::InvalidIpv6),
+            };
+            let (host, rest) = hostport.split_at(end);
+            if !is_ip_literal(&host[1..end - 1]) {
+                return Err(UrlError::InvalidIpv6);
+            }
+            match rest {
+                "" => (host, None),
+                r => match r.strip_prefix(':') {
+                    Some(p) => (host, Some(p)),
+                    None => return Err(UrlError::InvalidHost),
+                },
+            }
+        }
+        false => match hostport.rsplit_once(':') {
+            Some((h, p)) => (h, Some(p)),
+            None => (hostport, None),
+        },
+    };
+
+    // Unicode names go through UTS #46 processing
+    let host = match host.is_ascii() {
+        true => Cow::Borrowed(host),
+        false => match idna::domain_to_ascii(host) {
+            Ok(h) => Cow::Owned(h),
+            Err(_) => return Err(UrlError::InvalidIdna),
+        },
+    };
+    if !host.starts_with('[')
+        && (!host
+            .bytes()
+            .all(|b| is_unreserved(b) || is_sub_delim(b) || b == b'%')
+            || !valid_pct(&host))
+    {
+        return Err(UrlError::InvalidHost);
+    }
+
+    // an empty port is the same as no port
+    let port = match port {
+        None | Some("") => None,
+        Some(p) if p.bytes().all(|b| b.is_ascii_digit()) => match p.parse::<u16>() {
+            Ok(p) => Some(p),
+            Err(_) => return Err(UrlError::InvalidPort),
+        },
+        Some(_) => return Err(UrlError::InvalidPort),
+    };
+    Ok((userinfo, host, port))
+}
+
+// IPv6address or IPvFuture, without the brackets.
+fn is_ip_literal(s: &str) -> bool {
+    if let Some(future) = s.strip_prefix(['v', 'V']) {
+        return match future.split_once('.') {
+            Some((ver, rest)) => {
+                !ver.is_empty()
+                    && ver.bytes().all(|b| b.is_ascii_hexdigit())
+                    && !rest.is_empty()
+                    && rest
+                        .bytes()
+                        .all(|b| is_unreserved(b) || is_sub_delim(b) || b == b':')
+            }
+            None => false,
+        };
+    }
+    s.parse::<Ipv6Addr>().is_ok()
+}
+
+// ALPHA *( ALPHA / DIGIT / "+" / "-" / "." )
+fn is_scheme(s: &str) -> bool {
+    let mut bytes = s.bytes();
+    bytes.next().is_some_and(|b| b.is_ascii_alphabetic())
+        && bytes.all(|b| b.is_ascii_alphanumeric() || matches!(b, b'+' | b'-' | b'.'))
+}
+
+fn is_unreserved(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~')
+}
+
+fn is_sub_delim(b: u8) -> bool {
+    matches!(
+        b,
+        b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+' | b',' | b';' | b'='
+    )
+}
+
+// pchar, with "%" standing in for pct-encoded
+fn is_pchar(b: u8) -> bool {
+    is_unreserved(b) || is_sub_delim(b) || matches!(b, b':' | b'@' | b'%')
+}
+
+// Every "%" is followed by two hex digits.
+fn valid_pct(s: &str) -> bool {
+    let b = s.as_bytes();
+    let mut i = 0;
+    while i < b.len() {
+        if b[i] == b'%' {
+            if i + 2 >= b.len() || !b[i + 1].is_ascii_hexdigit() || !b[i + 2].is_ascii_hexdigit() {
+                return false;
+            }
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parts() {
+        let url = Url::new("HTTPS://example.com/a/b;c?x=1&y#frag").unwrap();
+        assert_eq!(url.scheme(), "https");
+        assert_eq!(url.authority(), Some("example.com"));
+        assert_eq!(url.userinfo(), None);
+        assert_eq!(url.domain(), "example.com");
+        assert_eq!(url.path(), "/a/b;c");
+        assert_eq!(url.query(), Some("x=1&y"));
+        assert_eq!(url.fragment(), Some("frag"));
+        assert_eq!(url.route(), "/a/b;c?x=1&y");
+    }
+
+    #[test]
+    fn hosts_and_ports() {
+        let url = Url::new("https://user:pw@[::1]:8443/p?q#f").unwrap();
+        assert_eq!(url.username(), Some("user"));
+        assert_eq!(url.password(), Some("pw"));
+        assert_eq!(url.ip(), Some("::1".parse().unwrap()));
+        assert_eq!(url.port(), Some(8443));
+        assert_eq!(url.host_header(), "[::1]:8443");
+        assert_eq!(url.route(), "/p?q");
+
+        let url = Url::new("http://example.com").unwrap();
+        assert_eq!(url.port(), Some(80));
+        assert_eq!(url.explicit_port(), None);
+        assert_eq!(url.host_header(), "example.com");
+        assert_eq!(url.path(), "/");
+        assert!(Url::new("http://example.com:99999/").is_err());
+    }
+
+    #[test]
+    fn invalid_urls() {
+        let cases = [
+            ("", UrlError::Empty),
+            ("example.com/a", UrlError::MissingScheme),
+            ("1http://example.com/", UrlError::InvalidScheme),
+            ("http:///a", UrlError::EmptyHost),
+            ("http://exa mple.com/", UrlError::InvalidHost),
+            ("http://[::1/", UrlError::InvalidIpv6),
+            ("http://example.com:8a/", UrlError::InvalidPort),
+            ("http://example.com/a b", UrlError::InvalidPath),
+            ("http://example.com/?a b", UrlError::InvalidQuery),
+            ("http://example.com/#a b", UrlError::InvalidFragment),
+        ];
+        for (url, expected) in cases {
+            assert_eq!(Url::new(url).err(), Some(expected), "{}", url);
+        }
+    }
+
+    // RFC 3986 section 5.4
+    const BASE: &str = "http://a/b/c/d;p?q";
+
+    #[test]
+    fn join_normal_examples() {
+        let base = Url::new(BASE).unwrap();
+        let cases = [
+            ("g:h", "g:h"),
+            ("g", "http://a/b/c/g"),
+            ("./g", "http://a/b/c/g"),
+            ("g/", "http://a/b/c/g/"),
+            ("/g", "http://a/g"),
+            ("//g", "http://g"),
+            ("?y", "http://a/b/c/d;p?y"),
+            ("g?y", "http://a/b/c/g?y"),
+            ("#s", "http://a/b/c/d;p?q#s"),
+            ("g#s", "http://a/b/c/g#s"),
+            ("g?y#s", "http://a/b/c/g?y#s"),
+            (";x", "http://a/b/c/;x"),
+            ("g;x", "http://a/b/c/g;x"),
+            ("g;x?y#s", "http://a/b/c/g;x?y#s"),
+            ("", "http://a/b/c/d;p?q"),
+            (".", "http://a/b/c/"),
+            ("./", "http://a/b/c/"),
+            ("..", "http://a/b/"),
+            ("../", "http://a/b/"),
+            ("../g", "http://a/b/g"),
+            ("../..", "http://a/"),
+            ("../../", "http://a/"),
+            ("../../g", "http://a/g"),
+        ];
+        for (reference, expected) in cases {
+            assert_eq!(base.join(reference).unwrap(), expected, "{}", reference);
+        }
+    }
+
+    #[test]
+    fn join_abnormal_examples() {
+        let base = Url::new(BASE).unwrap();
+        let cases = [
+            ("../../../g", "http://a/g"),
+            ("../../../../g", "http://a/g"),
+            ("/./g", "http://a/g"),
+            ("/../g", "http://a/g"),
+            ("g.", "http://a/b/c/g."),
+            (".g", "http://a/b/c/.g"),
+            ("g..", "http://a/b/c/g.."),
+            ("..g", "http://a/b/c/..g"),
+            ("./../g", "http://a/b/g"),
+            ("./g/.", "http://a/b/c/g/"),
+            ("g/./h", "http://a/b/c/g/h"),
+            ("g/../h", "http://a/b/c/h"),
+            ("g;x=1/./y", "http://a/b/c/g;x=1/y"),
+            ("g;x=1/../y", "http://a/b/c/y"),
+            ("g?y/./x", "http://a/b/c/g?y/./x"),
+            ("g?y/../x", "http://a/b/c/g?y/../x"),
+            ("g#s/./x", "http://a/b/c/g#s/./x"),
+            ("g#s/../x", "http://a/b/c/g#s/../x"),
+        ];
+        for (reference, expected) in cases {
+            assert_eq!(base.join(reference).unwrap(), expected, "{}", reference);
+        }
+        // a strict parser resolves it to "http:g", which has no host to connect to
+        assert!(matches!(base.join("http:g"), Err(UrlError::EmptyHost)));
+    }
+
+    #[test]
+    fn dot_segments() {
+        assert_eq!(remove_dot_segments("/a/b/c/./../../g"), "/a/g");
+        assert_eq!(remove_dot_segments("mid/content=5/../6"), "mid/6");
+        assert_eq!(remove_dot_segments("/.."), "/");
+        assert_eq!(remove_dot_segments(""), "");
+    }
+
+    #[test]
+    fn normalize() {
+        let cases = [
+            (
+                "HTTP://Example.COM:80/%7euser/./a/../b",
+                "http://example.com/~user/b",
+            ),
+            ("https://example.com:443", "https://example.com/"),
+            (
+                "https://example.com:8443/a%2fb?q=%3a#%7E",
+                "https://example.com:8443/a%2Fb?q=%3A#~",
+            ),
+            // escapes in the host keep uppercase hex, decoded letters are lowercased
+            ("http://EX%c3%a4%41MPLE.com/", "http://ex%C3%A4ample.com/"),
+        ];
+        for (url, expected) in cases {
+            assert_eq!(Url::new(url).unwrap().normalize(), expected, "{}", url);
+        }
     }
 }
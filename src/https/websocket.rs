@@ -0,0 +1,753 @@
+use super::client::Methods;
+use super::connect_to::ConnectTo;
+use super::parser::ResponseParser;
+use super::request::RequestBuilder;
+use super::resolve::{Resolver, SystemResolver};
+use super::response::{HttpResponseError, Response};
+use super::timeout::Timeouts;
+use super::transport::Transport;
+use super::url::Url;
+use crate::tls::config::TlsConfig;
+use crate::tls::tls_stream::crypto_provider;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::{Buf, BufMut, BytesMut};
+use log::{debug, warn};
+use rustls::ClientConfig;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::sync::Arc;
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 << 20;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+// Close codes, RFC 6455 section 7.4.1.
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+#[derive(Debug)]
+pub enum WebSocketError {
+    InvalidUrl(String),
+    Handshake(&'static str),
+    Protocol(&'static str),
+    InvalidUtf8,
+    MessageTooBig,
+    Closed,
+    Http(HttpResponseError),
+    Io(io::Error),
+}
+
+impl fmt::Display for WebSocketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WebSocketError::InvalidUrl(ref e) => write!(f, "invalid url: {}", e),
+            WebSocketError::Handshake(msg) => write!(f, "websocket handshake failed: {}", msg),
+            WebSocketError::Protocol(msg) => write!(f, "websocket protocol error: {}", msg),
+            WebSocketError::InvalidUtf8 => write!(f, "text message isn't valid utf-8"),
+            WebSocketError::MessageTooBig => write!(f, "message exceeds the size limit"),
+            WebSocketError::Closed => write!(f, "the connection is closed"),
+            WebSocketError::Http(ref e) => write!(f, "{}", e),
+            WebSocketError::Io(ref e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl error::Error for WebSocketError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            WebSocketError::Http(ref e) => Some(e),
+            WebSocketError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for WebSocketError {
+    fn from(e: io::Error) -> Self {
+        WebSocketError::Io(e)
+    }
+}
+
+impl From<HttpResponseError> for WebSocketError {
+    fn from(e: HttpResponseError) -> Self {
+        WebSocketError::Http(e)
+    }
+}
+
+pub type WsResult<T> = Result<T, WebSocketError>;
+
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<(u16, String)>),
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+// WebSocket client, RFC 6455.
+// Pings are answered automatically, fragmented messages are reassembled.
+pub struct WebSocket<S: Read + Write = Transport> {
+    io: S,
+    read_buf: BytesMut,
+    // opcode and data of a fragmented message in progress
+    fragments: Option<(u8, Vec<u8>)>,
+    fragment_size: Option<usize>,
+    max_message_size: usize,
+    close_sent: bool,
+    close_received: bool,
+}
+
+impl WebSocket<Transport> {
+    // Connects to a ws:// or wss:// (http:// or https://) url and performs the Upgrade handshake.
+    // HttpsClient::websocket() connects with the client's settings instead of the defaults.
+    pub fn connect(url: &str, extra_headers: Option<&HashMap<&str, &str>>) -> WsResult<Self> {
+        let timeouts = Timeouts::default();
+        Self::open(
+            url,
+            extra_headers,
+            None,
+            &ConnectTo::default(),
+            &SystemResolver,
+            &timeouts,
+        )
+    }
+
+    // Same as connect(), verifying wss:// servers with the given settings.
+    pub fn connect_with_tls(
+        url: &str,
+        extra_headers: Option<&HashMap<&str, &str>>,
+        tls: &TlsConfig,
+    ) -> WsResult<Self> {
+        let cfg = Arc::new(tls.build()?);
+        let timeouts = Timeouts::default();
+        Self::open(
+            url,
+            extra_headers,
+            Some(&cfg),
+            &ConnectTo::default(),
+            &SystemResolver,
+            &timeouts,
+        )
+    }
+
+    // The total timeout only covers connecting and the handshake, the socket stays open after.
+    pub(crate) fn open(
+        url: &str,
+        extra_headers: Option<&HashMap<&str, &str>>,
+        cfg: Option<&Arc<ClientConfig>>,
+        connect_to: &ConnectTo,
+        resolver: &dyn Resolver,
+        timeouts: &Timeouts,
+    ) -> WsResult<Self> {
+        let url = match Url::new(url) {
+            Ok(u) => u,
+            Err(e) => return Err(WebSocketError::InvalidUrl(e.to_string())),
+        };
+        if !matches!(url.scheme(), "ws" | "wss" | "http" | "https") {
+            return Err(WebSocketError::InvalidUrl(format!(
+                "unsupported scheme {}",
+                url.scheme()
+            )));
+        }
+
+        let deadline = timeouts.deadline();
+        let io = Transport::connect(&url, cfg, connect_to, resolver, timeouts, deadline)?;
+        let mut ws = Self::handshake(io, &url, extra_headers)?;
+        ws.io.set_deadline(None);
+        Ok(ws)
+    }
+}
+
+impl<S: Read + Write> WebSocket<S> {
+    // Performs the opening handshake over an already connected stream.
+    pub fn handshake(
+        mut io: S,
+        url: &Url,
+        extra_headers: Option<&HashMap<&str, &str>>,
+    ) -> WsResult<Self> {
+        let mut nonce = [0u8; 16];
+        random_bytes(&mut nonce)?;
+        let key = STANDARD.encode(nonce);
+
+        let mut req = RequestBuilder::new();
+        if let Some(h) = extra_headers {
+            req.headers(h);
+        }
+        let host = url.host_header();
+        let mut headers = HashMap::new();
+        headers.insert("Upgrade", "websocket");
+        headers.insert("Connection", "Upgrade");
+        headers.insert("Sec-WebSocket-Key", key.as_str());
+        headers.insert("Sec-WebSocket-Version", "13");
+        req.http_method(Methods::GET)
+            .headers(&headers)
+            .route(url.route())
+            .host(&host);
+        io.write_all(&req.build())?;
+        io.flush()?;
+
+        let mut parser = ResponseParser::new();
+        let res = Response::read_from(&mut io, &mut parser)?;
+        validate_handshake(&res, &key)?;
+        debug!("WebSocket handshake done");
+
+        // frames sent right after the response are already in the parser
+        let read_buf = BytesMut::from(&parser.take_remaining()[..]);
+
+        Ok(Self {
+            io,
+            read_buf,
+            fragments: None,
+            fragment_size: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            close_sent: false,
+            close_received: false,
+        })
+    }
+
+    // Splits outgoing messages into frames of at most this many bytes.
+    pub fn set_fragment_size(&mut self, size: Option<usize>) -> &mut Self {
+        self.fragment_size = size.filter(|s| *s > 0);
+        self
+    }
+
+    pub fn set_max_message_size(&mut self, size: usize) -> &mut Self {
+        self.max_message_size = size;
+        self
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.close_sent && self.close_received
+    }
+
+    pub fn send_text(&mut self, text: &str) -> WsResult<()> {
+        self.send_message(OP_TEXT, text.as_bytes())
+    }
+
+    pub fn send_binary(&mut self, data: &[u8]) -> WsResult<()> {
+        self.send_message(OP_BINARY, data)
+    }
+
+    pub fn send(&mut self, msg: Message) -> WsResult<()> {
+        match msg {
+            Message::Text(t) => self.send_text(&t),
+            Message::Binary(b) => self.send_binary(&b),
+            Message::Ping(p) => self.ping(&p),
+            Message::Pong(p) => self.send_frame(true, OP_PONG, &p),
+            Message::Close(c) => match c {
+                Some((code, reason)) => self.close(code, &reason),
+                None => self.close(CLOSE_NORMAL, ""),
+            },
+        }
+    }
+
+    pub fn ping(&mut self, data: &[u8]) -> WsResult<()> {
+        if data.len() > 125 {
+            return Err(WebSocketError::Protocol("control frame payload too long"));
+        }
+        self.send_frame(true, OP_PING, data)
+    }
+
+    // Starts the closing handshake and waits for the server's close frame.
+    pub fn close(&mut self, code: u16, reason: &str) -> WsResult<()> {
+        self.send_close(code, reason)?;
+        while !self.close_received {
+            match self.read_message() {
+                Ok(_) => {}
+                Err(WebSocketError::Closed) => break,
+                Err(WebSocketError::Io(ref e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    // Reads the next message, control frames in between are handled and returned too.
+    pub fn read_message(&mut self) -> WsResult<Message> {
+        if self.close_received {
+            return Err(WebSocketError::Closed);
+        }
+
+        loop {
+            let frame = match self.read_frame() {
+                Ok(f) => f,
+                Err(e) => return Err(self.fail(e)),
+            };
+
+            match frame.opcode {
+                OP_PING => {
+                    if !self.close_sent {
+                        self.send_frame(true, OP_PONG, &frame.payload)?;
+                    }
+                    return Ok(Message::Ping(frame.payload));
+                }
+                OP_PONG => return Ok(Message::Pong(frame.payload)),
+                OP_CLOSE => return self.handle_close(&frame.payload),
+                OP_TEXT | OP_BINARY if self.fragments.is_some() => {
+                    return Err(self.fail(WebSocketError::Protocol("expected a continuation")));
+                }
+                OP_TEXT | OP_BINARY if !frame.fin => {
+                    self.fragments = Some((frame.opcode, frame.payload));
+                }
+                OP_TEXT | OP_BINARY => return self.finish_message(frame.opcode, frame.payload),
+                OP_CONTINUATION => {
+                    let (opcode, mut data) = match self.fragments.take() {
+                        Some(f) => f,
+                        None => {
+                            let e = WebSocketError::Protocol("continuation without a message");
+                            return Err(self.fail(e));
+                        }
+                    };
+                    if data.len() + frame.payload.len() > self.max_message_size {
+                        return Err(self.fail(WebSocketError::MessageTooBig));
+                    }
+                    data.extend_from_slice(&frame.payload);
+                    if frame.fin {
+                        return self.finish_message(opcode, data);
+                    }
+                    self.fragments = Some((opcode, data));
+                }
+                _ => return Err(self.fail(WebSocketError::Protocol("unknown opcode"))),
+            }
+        }
+    }
+
+    fn finish_message(&mut self, opcode: u8, data: Vec<u8>) -> WsResult<Message> {
+        if opcode == OP_BINARY {
+            return Ok(Message::Binary(data));
+        }
+        match String::from_utf8(data) {
+            Ok(s) => Ok(Message::Text(s)),
+            Err(_) => Err(self.fail(WebSocketError::InvalidUtf8)),
+        }
+    }
+
+    fn handle_close(&mut self, payload: &[u8]) -> WsResult<Message> {
+        self.close_received = true;
+        let close = match payload.len() {
+            0 => None,
+            1 => return Err(self.fail(WebSocketError::Protocol("invalid close payload"))),
+            _ => {
+                let code = u16::from_be_bytes([payload[0], payload[1]]);
+                if !is_valid_close_code(code) {
+                    return Err(self.fail(WebSocketError::Protocol("invalid close code")));
+                }
+                let reason = match std::str::from_utf8(&payload[2..]) {
+                    Ok(r) => r.to_string(),
+                    Err(_) => return Err(self.fail(WebSocketError::InvalidUtf8)),
+                };
+                Some((code, reason))
+            }
+        };
+        debug!("Received close: {:?}", close);
+
+        // echo the code back if we didn't start the closing handshake
+        if !self.close_sent {
+            let code = close.as_ref().map_or(CLOSE_NORMAL, |c| c.0);
+            self.send_close(code, "")?;
+        }
+        Ok(Message::Close(close))
+    }
+
+    // Sends a close frame for the error before handing it back.
+    fn fail(&mut self, e: WebSocketError) -> WebSocketError {
+        let code = match e {
+            WebSocketError::Protocol(..) => CLOSE_PROTOCOL_ERROR,
+            WebSocketError::InvalidUtf8 => CLOSE_INVALID_DATA,
+            WebSocketError::MessageTooBig => CLOSE_TOO_BIG,
+            _ => return e,
+        };
+        warn!("Closing WebSocket: {}", e);
+        if !self.close_sent {
+            let _ = self.send_close(code, "");
+        }
+        self.close_received = true;
+        e
+    }
+
+    fn send_close(&mut self, code: u16, reason: &str) -> WsResult<()> {
+        if self.close_sent {
+            return Ok(());
+        }
+        // control frames carry 125 bytes at most, the reason is cut on a char boundary
+        let mut end = reason.len().min(123);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(&reason.as_bytes()[..end]);
+        self.send_frame(true, OP_CLOSE, &payload)?;
+        self.close_sent = true;
+        Ok(())
+    }
+
+    fn send_message(&mut self, opcode: u8, data: &[u8]) -> WsResult<()> {
+        let size = self.fragment_size.unwrap_or(data.len()).max(1);
+        let mut chunks = data.chunks(size).peekable();
+        let mut op = opcode;
+
+        if chunks.peek().is_none() {
+            return self.send_frame(true, opcode, &[]);
+        }
+        while let Some(chunk) = chunks.next() {
+            self.send_frame(chunks.peek().is_none(), op, chunk)?;
+            op = OP_CONTINUATION;
+        }
+        Ok(())
+    }
+
+    // Client frames are always masked.
+    fn send_frame(&mut self, fin: bool, opcode: u8, payload: &[u8]) -> WsResult<()> {
+        if self.close_sent {
+            return Err(WebSocketError::Closed);
+        }
+
+        let mut buf = BytesMut::with_capacity(payload.len() + 14);
+        buf.put_u8(if fin { 0x80 } else { 0 } | opcode);
+        match payload.len() {
+            n if n < 126 => buf.put_u8(0x80 | n as u8),
+            n if n <= u16::MAX as usize => {
+                buf.put_u8(0x80 | 126);
+                buf.put_u16(n as u16);
+            }
+            n => {
+                buf.put_u8(0x80 | 127);
+                buf.put_u64(n as u64);
+            }
+        }
+
+        let mut mask = [0u8; 4];
+        random_bytes(&mut mask)?;
+        buf.extend_from_slice(&mask);
+        buf.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+
+        self.io.write_all(&buf)?;
+        self.io.flush()?;
+        Ok(())
+    }
+
+    fn read_frame(&mut self) -> WsResult<Frame> {
+        let mut tmp: [u8; 4096] = [0; 4096];
+        loop {
+            if let Some(frame) = self.decode_frame()? {
+                return Ok(frame);
+            }
+            match self.io.read(&mut tmp) {
+                Ok(0) => return Err(WebSocketError::Io(ErrorKind::UnexpectedEof.into())),
+                Ok(n) => self.read_buf.extend_from_slice(&tmp[..n]),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(WebSocketError::Io(e)),
+            }
+        }
+    }
+
+    fn decode_frame(&mut self) -> WsResult<Option<Frame>> {
+        let buf = &self.read_buf;
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        let fin = buf[0] & 0x80 != 0;
+        let opcode = buf[0] & 0x0f;
+        // no extensions are negotiated, so the reserved bits must be unset
+        if buf[0] & 0x70 != 0 {
+            return Err(WebSocketError::Protocol("reserved bits set"));
+        }
+        if buf[1] & 0x80 != 0 {
+            return Err(WebSocketError::Protocol("masked frame from the server"));
+        }
+
+        let (len, header) = match buf[1] & 0x7f {
+            126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+            127 if buf.len() >= 10 => {
+                let mut b = [0u8; 8];
+                b.copy_from_slice(&buf[2..10]);
+                (u64::from_be_bytes(b), 10)
+            }
+            126 | 127 => return Ok(None),
+            n => (n as u64, 2),
+        };
+
+        if opcode & 0x8 != 0 && (len > 125 || !fin) {
+            return Err(WebSocketError::Protocol("invalid control frame"));
+        }
+        if len > self.max_message_size as u64 {
+            return Err(WebSocketError::MessageTooBig);
+        }
+        let len = len as usize;
+        if buf.len() < header + len {
+            return Ok(None);
+        }
+
+        self.read_buf.advance(header);
+        let payload = self.read_buf.split_to(len).to_vec();
+        Ok(Some(Frame {
+            fin,
+            opcode,
+            payload,
+        }))
+    }
+}
+
+// Codes a peer may send, RFC 6455 section 7.4. 1004 is reserved, 1005, 1006 and 1015
+// only stand for a missing code or a failure and are never sent, 1016-2999 aren't assigned.
+fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+fn validate_handshake(res: &Response, key: &str) -> WsResult<()> {
+    if res.status_code != 101 {
+        return Err(WebSocketError::Handshake("server didn't switch protocols"));
+    }
+    if !res
+        .header("Upgrade")
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+    {
+        return Err(WebSocketError::Handshake("missing Upgrade: websocket"));
+    }
+    if !res.header("Connection").is_some_and(|v| {
+        v.split(',')
+            .any(|t| t.trim().eq_ignore_ascii_case("upgrade"))
+    }) {
+        return Err(WebSocketError::Handshake("missing Connection: Upgrade"));
+    }
+
+    let mut sha = Sha1::new();
+    sha.update(key.as_bytes());
+    sha.update(GUID.as_bytes());
+    let expected = STANDARD.encode(sha.finalize());
+    if res.header("Sec-WebSocket-Accept") != Some(expected.as_str()) {
+        return Err(WebSocketError::Handshake("invalid Sec-WebSocket-Accept"));
+    }
+    // nothing was offered, so nothing may be accepted
+    if res.header("Sec-WebSocket-Extensions").is_some() {
+        return Err(WebSocketError::Handshake("unexpected extension"));
+    }
+    Ok(())
+}
+
+fn random_bytes(buf: &mut [u8]) -> WsResult<()> {
+    match crypto_provider().secure_random.fill(buf) {
+        Ok(()) => Ok(()),
+        Err(_) => Err(WebSocketError::Io(io::Error::other(
+            "failed to get random bytes",
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Server side of a connection: answers the handshake once the request is written,
+    // followed by the frames given.
+    struct Peer {
+        input: BytesMut,
+        frames: Vec<u8>,
+        output: Vec<u8>,
+        answered: bool,
+    }
+
+    impl Read for Peer {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if !self.answered {
+                let req = String::from_utf8_lossy(&self.output).into_owned();
+                let key = req
+                    .lines()
+                    .find_map(|l| l.strip_prefix("Sec-WebSocket-Key: "))
+                    .unwrap();
+                let mut sha = Sha1::new();
+                sha.update(key.as_bytes());
+                sha.update(GUID.as_bytes());
+                let res = format!(
+                    "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                     Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                    STANDARD.encode(sha.finalize())
+                );
+                self.input.extend_from_slice(res.as_bytes());
+                self.input.extend_from_slice(&self.frames);
+                self.answered = true;
+                self.output.clear();
+            }
+            let n = buf.len().min(self.input.len());
+            buf[..n].copy_from_slice(&self.input.split_to(n));
+            Ok(n)
+        }
+    }
+
+    impl Write for Peer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        assert!(payload.len() < 126);
+        let mut f = vec![if fin { 0x80 } else { 0 } | opcode, payload.len() as u8];
+        f.extend_from_slice(payload);
+        f
+    }
+
+    fn close(code: u16, reason: &str) -> Vec<u8> {
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        frame(true, OP_CLOSE, &payload)
+    }
+
+    fn connect(frames: &[Vec<u8>]) -> WebSocket<Peer> {
+        let peer = Peer {
+            input: BytesMut::new(),
+            frames: frames.concat(),
+            output: vec![],
+            answered: false,
+        };
+        let url = Url::new("ws://example.com/chat").unwrap();
+        WebSocket::handshake(peer, &url, None).unwrap()
+    }
+
+    // Frames the client wrote, unmasked.
+    fn sent(ws: &WebSocket<Peer>) -> Vec<(u8, Vec<u8>)> {
+        let mut buf = &ws.io.output[..];
+        let mut frames = vec![];
+        while !buf.is_empty() {
+            assert_ne!(buf[1] & 0x80, 0, "client frames are masked");
+            let (len, header) = match buf[1] & 0x7f {
+                126 => (u16::from_be_bytes([buf[2], buf[3]]) as usize, 4),
+                n => (n as usize, 2),
+            };
+            let mask = &buf[header..header + 4];
+            let payload = buf[header + 4..header + 4 + len]
+                .iter()
+                .enumerate()
+                .map(|(i, b)| b ^ mask[i % 4])
+                .collect();
+            frames.push((buf[0] & 0x0f, payload));
+            buf = &buf[header + 4 + len..];
+        }
+        frames
+    }
+
+    fn close_code(payload: &[u8]) -> u16 {
+        u16::from_be_bytes([payload[0], payload[1]])
+    }
+
+    #[test]
+    fn messages() {
+        let mut ws = connect(&[
+            frame(false, OP_TEXT, b"hel"),
+            frame(true, OP_PING, b"p"),
+            frame(true, OP_CONTINUATION, "lo é".as_bytes()),
+            frame(true, OP_BINARY, &[1, 2]),
+        ]);
+        assert_eq!(ws.read_message().unwrap(), Message::Ping(b"p".to_vec()));
+        assert_eq!(ws.read_message().unwrap(), Message::Text("hello é".into()));
+        assert_eq!(ws.read_message().unwrap(), Message::Binary(vec![1, 2]));
+
+        ws.set_fragment_size(Some(2));
+        ws.send_text("abc").unwrap();
+        assert_eq!(
+            sent(&ws),
+            [
+                (OP_PONG, b"p".to_vec()),
+                (OP_TEXT, b"ab".to_vec()),
+                (OP_CONTINUATION, b"c".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn close_is_echoed() {
+        let mut ws = connect(&[close(1001, "going away")]);
+        let msg = ws.read_message().unwrap();
+        assert_eq!(msg, Message::Close(Some((1001, "going away".into()))));
+        let frames = sent(&ws);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(close_code(&frames[0].1), 1001);
+        assert!(ws.is_closed());
+        assert!(matches!(ws.read_message(), Err(WebSocketError::Closed)));
+
+        let mut ws = connect(&[frame(true, OP_CLOSE, &[])]);
+        assert_eq!(ws.read_message().unwrap(), Message::Close(None));
+        assert_eq!(close_code(&sent(&ws)[0].1), CLOSE_NORMAL);
+    }
+
+    #[test]
+    fn invalid_close_codes() {
+        for code in [0, 999, 1004, 1005, 1006, 1015, 1016, 2999, 5000] {
+            let mut ws = connect(&[close(code, "")]);
+            let res = ws.read_message();
+            assert!(matches!(res, Err(WebSocketError::Protocol(..))), "{}", code);
+            assert_eq!(
+                close_code(&sent(&ws)[0].1),
+                CLOSE_PROTOCOL_ERROR,
+                "{}",
+                code
+            );
+        }
+        for code in [1000, 1003, 1007, 1014, 3000, 4999] {
+            let mut ws = connect(&[close(code, "")]);
+            assert!(ws.read_message().is_ok(), "{}", code);
+        }
+    }
+
+    #[test]
+    fn protocol_errors_close_the_connection() {
+        let cases = [
+            (frame(true, OP_CONTINUATION, b"x"), CLOSE_PROTOCOL_ERROR),
+            (frame(true, 0x3, b""), CLOSE_PROTOCOL_ERROR),
+            (frame(false, OP_PING, b""), CLOSE_PROTOCOL_ERROR),
+            (frame(true, OP_TEXT, &[0xff]), CLOSE_INVALID_DATA),
+            (vec![0x81, 0x80, 0, 0, 0, 0], CLOSE_PROTOCOL_ERROR),
+        ];
+        for (f, code) in cases {
+            let mut ws = connect(&[f]);
+            assert!(ws.read_message().is_err());
+            assert_eq!(close_code(&sent(&ws)[0].1), code);
+            assert!(ws.send_text("late").is_err());
+        }
+
+        let mut ws = connect(&[frame(true, OP_BINARY, &[0; 10])]);
+        ws.set_max_message_size(4);
+        assert!(matches!(
+            ws.read_message(),
+            Err(WebSocketError::MessageTooBig)
+        ));
+        assert_eq!(close_code(&sent(&ws)[0].1), CLOSE_TOO_BIG);
+    }
+
+    #[test]
+    fn close_reason_is_cut_on_a_char_boundary() {
+        let mut ws = connect(&[close(1000, "")]);
+        // 2 bytes of code and 61 two byte chars would be 124 bytes, 62 are too many
+        let reason = "é".repeat(70);
+        ws.close(1000, &reason).unwrap();
+        let frames = sent(&ws);
+        let payload = &frames[0].1;
+        assert_eq!(payload.len(), 124);
+        assert_eq!(std::str::from_utf8(&payload[2..]).unwrap(), "é".repeat(61));
+    }
+}
@@ -0,0 +1,2 @@
+pub mod https;
+pub mod tls;
@@ -1,10 +1,5 @@
-mod https;
-mod tls;
-use crate::https::response::Response;
-use crate::https::url::Url;
-use https::client::HttpsClient;
-use https::persistent_client::PersistentClient;
 use log::{Level, Metadata, Record};
+use rusty_tunnels::https::persistent_client::PersistentClient;
 use std::collections::HashMap;
 
 struct Logger;
@@ -32,7 +27,6 @@ fn main() {
     // headers
     let mut headers: HashMap<&str, &str> = HashMap::new();
     headers.insert("Connection", "close");
-    headers.insert("Accept-Encoding", "identity");
     headers.insert("Accept", "*/*");
 
     let mut client =
@@ -41,5 +35,5 @@ fn main() {
         .get("https://prev.rust-lang.org/en-US/", None)
         .unwrap();
 
-    println!("{:#?}", Response::from_slice(&first).unwrap());
+    println!("{:#?}", first);
 }
@@ -0,0 +1,514 @@
+#[cfg(feature = "danger-insecure")]
+use super::danger::{AcceptAnyCertificate, HostnameOverride, Insecure};
+use super::key_log::KeyLogWriter;
+use super::pinning::{self, Pin, PinningVerifier};
+use super::tls_stream::crypto_provider;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+#[cfg(feature = "native-roots")]
+use log::debug;
+#[cfg(feature = "danger-insecure")]
+use log::warn;
+use rustls::client::danger::ServerCertVerifier;
+use rustls::client::{Resumption, WebPkiServerVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::{self, PemObject};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{
+    CipherSuite, ClientConfig, KeyLog, KeyLogFile, RootCertStore, SupportedProtocolVersion,
+};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, Weak};
+use webpki_roots::TLS_SERVER_ROOTS;
+type TLSResult<T> = Result<T, Error>;
+
+// TLS settings of a client, turned into a rustls ClientConfig with build().
+// Servers are verified against the webpki roots unless they're turned off,
+// added roots are trusted on top of them.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    default_roots: bool,
+    #[cfg(feature = "native-roots")]
+    native_roots: bool,
+    roots: Vec<CertificateDer<'static>>,
+    // chain and key presented to servers asking for a client certificate
+    client_auth: Option<(Vec<CertificateDer<'static>>, Arc<PrivateKeyDer<'static>>)>,
+    pins: HashMap<String, Vec<Pin>>,
+    // servers sessions are kept for, 0 means no resumption
+    session_cache_size: usize,
+    early_data: bool,
+    key_log: Option<Arc<dyn KeyLog>>,
+    // None leaves rustls' defaults
+    versions: Option<Vec<&'static SupportedProtocolVersion>>,
+    cipher_suites: Option<Vec<CipherSuite>>,
+    #[cfg(feature = "danger-insecure")]
+    insecure: Option<Insecure>,
+}
+
+// Key logs are compared by identity, everything else by value.
+impl PartialEq for TlsConfig {
+    fn eq(&self, other: &Self) -> bool {
+        let same_key_log = match (&self.key_log, &other.key_log) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (a, b) => a.is_none() && b.is_none(),
+        };
+        #[cfg(feature = "native-roots")]
+        if self.native_roots != other.native_roots {
+            return false;
+        }
+        #[cfg(feature = "danger-insecure")]
+        if self.insecure != other.insecure {
+            return false;
+        }
+        same_key_log
+            && self.default_roots == other.default_roots
+            && self.roots == other.roots
+            && self.client_auth == other.client_auth
+            && self.pins == other.pins
+            && self.session_cache_size == other.session_cache_size
+            && self.early_data == other.early_data
+            && self.versions == other.versions
+            && self.cipher_suites == other.cipher_suites
+    }
+}
+
+// Same as rustls' default.
+const DEFAULT_SESSION_CACHE_SIZE: usize = 256;
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            default_roots: true,
+            #[cfg(feature = "native-roots")]
+            native_roots: false,
+            roots: Vec::new(),
+            client_auth: None,
+            pins: HashMap::new(),
+            session_cache_size: DEFAULT_SESSION_CACHE_SIZE,
+            early_data: false,
+            key_log: None,
+            versions: None,
+            cipher_suites: None,
+            #[cfg(feature = "danger-insecure")]
+            insecure: None,
+        }
+    }
+}
+
+impl TlsConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Turning them off leaves only the added roots, e.g. for an internal PKI.
+    pub fn default_roots(mut self, on: bool) -> Self {
+        self.default_roots = on;
+        self
+    }
+
+    // Certificates trusted by the operating system, loaded on build().
+    #[cfg(feature = "native-roots")]
+    pub fn native_roots(mut self, on: bool) -> Self {
+        self.native_roots = on;
+        self
+    }
+
+    pub fn add_root(mut self, cert: CertificateDer<'static>) -> Self {
+        self.roots.push(cert);
+        self
+    }
+
+    pub fn add_root_der(self, der: &[u8]) -> Self {
+        self.add_root(CertificateDer::from(der.to_vec()))
+    }
+
+    // Every certificate of a PEM bundle, other sections are skipped.
+    pub fn add_root_pem(mut self, pem: &[u8]) -> TLSResult<Self> {
+        let before = self.roots.len();
+        for cert in CertificateDer::pem_slice_iter(pem) {
+            self.roots
+                .push(cert.map_err(|e| Error::new(ErrorKind::InvalidData, e))?);
+        }
+        if self.roots.len() == before {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "no certificate found in PEM",
+            ));
+        }
+        Ok(self)
+    }
+
+    // PEM bundle or a single DER certificate, told apart by the PEM header.
+    pub fn add_root_file<P: AsRef<Path>>(self, path: P) -> TLSResult<Self> {
+        let data = fs::read(path)?;
+        match is_pem(&data) {
+            true => self.add_root_pem(&data),
+            false => Ok(self.add_root_der(&data)),
+        }
+    }
+
+    // Certificate chain, leaf first, and its private key for mutual TLS.
+    pub fn client_cert(
+        mut self,
+        chain: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> Self {
+        self.client_auth = Some((chain, Arc::new(key)));
+        self
+    }
+
+    // The key can be PKCS#8, RSA (PKCS#1) or SEC1, and may be in the same PEM as the chain.
+    pub fn client_cert_pem(self, chain: &[u8], key: &[u8]) -> TLSResult<Self> {
+        let chain = CertificateDer::pem_slice_iter(chain)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        if chain.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "no client certificate found in PEM",
+            ));
+        }
+        let key = match PrivateKeyDer::from_pem_slice(key) {
+            Ok(k) => k,
+            Err(pem::Error::NoItemsFound) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "no private key found in PEM",
+                ))
+            }
+            Err(e) => return Err(Error::new(ErrorKind::InvalidData, e)),
+        };
+        Ok(self.client_cert(chain, key))
+    }
+
+    pub fn client_cert_files<P: AsRef<Path>>(self, chain: P, key: P) -> TLSResult<Self> {
+        self.client_cert_pem(&fs::read(chain)?, &fs::read(key)?)
+    }
+
+    // Connections to host fail with a PinMismatch unless a certificate of the chain has this key.
+    // The chain is still validated against the roots as usual.
+    pub fn pin_spki_sha256(self, host: &str, hash: [u8; 32]) -> Self {
+        self.pin(host, Pin::Spki(hash))
+    }
+
+    // Base64 SHA-256 of the key, with or without the "sha256/" prefix curl and HPKP use.
+    pub fn pin_spki_base64(self, host: &str, pin: &str) -> TLSResult<Self> {
+        let b64 = pin.strip_prefix("sha256/").unwrap_or(pin);
+        let hash = STANDARD
+            .decode(b64)
+            .ok()
+            .and_then(|h| <[u8; 32]>::try_from(h).ok());
+        match hash {
+            Some(h) => Ok(self.pin_spki_sha256(host, h)),
+            None => Err(Error::new(ErrorKind::InvalidInput, "invalid SHA-256 pin")),
+        }
+    }
+
+    // Connections to host fail with a PinMismatch unless the leaf is one of its pinned certificates.
+    pub fn pin_certificate(self, host: &str, cert: CertificateDer<'static>) -> Self {
+        self.pin(host, Pin::Certificate(cert))
+    }
+
+    fn pin(mut self, host: &str, pin: Pin) -> Self {
+        let pins = self.pins.entry(pinning::host_key(host)).or_default();
+        if !pins.contains(&pin) {
+            pins.push(pin);
+        }
+        self
+    }
+
+    // Off makes every connection do a full handshake.
+    pub fn resumption(self, on: bool) -> Self {
+        match on {
+            true => self.session_cache_size(DEFAULT_SESSION_CACHE_SIZE),
+            false => self.session_cache_size(0),
+        }
+    }
+
+    // Every ClientConfig built gets its own cache, rustls only resumes sessions
+    // with the config that made them. A client keeps one for all its connections.
+    pub fn session_cache_size(mut self, size: usize) -> Self {
+        self.session_cache_size = size;
+        self
+    }
+
+    // GET, HEAD and OPTIONS requests on a resumed TLS 1.3 connection are sent before the handshake
+    // finishes when the server allows it. Early data can be replayed by an attacker, so
+    // it's off by default.
+    pub fn early_data(mut self, on: bool) -> Self {
+        self.early_data = on;
+        self
+    }
+
+    // Appends the secrets of every connection to the file named by SSLKEYLOGFILE, so
+    // captured traffic can be decrypted in Wireshark. Does nothing while it isn't set.
+    // Anyone who can read the file can decrypt the traffic, only use it for debugging.
+    pub fn key_log_file(mut self, on: bool) -> Self {
+        self.key_log = match on {
+            true => Some(Arc::new(KeyLogFile::new())),
+            false => None,
+        };
+        self
+    }
+
+    // Same as key_log_file(), with the lines going to out instead.
+    pub fn key_log_writer<W: Write + Send + 'static>(mut self, out: W) -> Self {
+        self.key_log = Some(Arc::new(KeyLogWriter::new(out)));
+        self
+    }
+
+    // Versions offered to servers, e.g. &[&rustls::version::TLS13] for TLS 1.3 only.
+    pub fn protocol_versions(mut self, versions: &[&'static SupportedProtocolVersion]) -> Self {
+        self.versions = Some(versions.to_vec());
+        self
+    }
+
+    // Cipher suites offered, in order of preference. build() fails if the crypto provider
+    // lacks one of them, or if none of them work with the protocol versions.
+    pub fn cipher_suites(mut self, suites: &[CipherSuite]) -> Self {
+        self.cipher_suites = Some(suites.to_vec());
+        self
+    }
+
+    // DANGER: accepts any certificate, so anyone on the network can read and change the traffic.
+    // Only for local development against self-signed servers, every connection logs a warning.
+    #[cfg(feature = "danger-insecure")]
+    pub fn danger_accept_any_certificate(mut self, on: bool) -> Self {
+        self.insecure = on.then_some(Insecure::AnyCertificate);
+        self
+    }
+
+    // DANGER: checks certificates against hostname instead of the host connected to,
+    // e.g. to reach a server by IP address whose certificate only names it.
+    // The chain is still verified, every connection logs a warning.
+    #[cfg(feature = "danger-insecure")]
+    pub fn danger_verify_hostname(mut self, hostname: &str) -> Self {
+        self.insecure = Some(Insecure::Hostname(hostname.to_string()));
+        self
+    }
+
+    // Fails on added roots that aren't valid CA certificates, when nothing is trusted,
+    // or when the client key isn't usable.
+    pub fn build(&self) -> TLSResult<ClientConfig> {
+        let builder = ClientConfig::builder_with_provider(self.provider()?);
+        let builder = match self.versions {
+            Some(ref v) => builder.with_protocol_versions(v),
+            None => builder.with_safe_default_protocol_versions(),
+        };
+        let builder = builder.map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        let verifier = self.verifier(builder.crypto_provider())?;
+        let builder = builder
+            .dangerous()
+            .with_custom_certificate_verifier(verifier);
+        let mut cfg = match self.client_auth {
+            Some((ref chain, ref key)) => builder
+                .with_client_auth_cert(chain.clone(), key.clone_key())
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
+            None => builder.with_no_client_auth(),
+        };
+        cfg.resumption = match self.session_cache_size {
+            0 => Resumption::disabled(),
+            n => Resumption::in_memory_sessions(n),
+        };
+        cfg.enable_early_data = self.early_data;
+        if let Some(ref key_log) = self.key_log {
+            cfg.key_log = Arc::clone(key_log);
+        }
+        // resumed sessions skip verification, and with it the warning
+        #[cfg(feature = "danger-insecure")]
+        if self.insecure.is_some() {
+            warn!("INSECURE: TLS certificate verification is weakened for this client");
+            cfg.resumption = Resumption::disabled();
+        }
+        Ok(cfg)
+    }
+
+    // Same as build(), except that equal configs get the same ClientConfig for as long as
+    // it's in use. Pools key connections by ClientConfig, so they're shared this way.
+    pub fn build_shared(&self) -> TLSResult<Arc<ClientConfig>> {
+        static BUILT: Mutex<Vec<(TlsConfig, Weak<ClientConfig>)>> = Mutex::new(Vec::new());
+        let mut built = match BUILT.lock() {
+            Ok(b) => b,
+            Err(p) => p.into_inner(),
+        };
+        built.retain(|(_, cfg)| cfg.strong_count() > 0);
+        if let Some(cfg) = built
+            .iter()
+            .find(|(settings, _)| settings == self)
+            .and_then(|(_, cfg)| cfg.upgrade())
+        {
+            return Ok(cfg);
+        }
+        let cfg = Arc::new(self.build()?);
+        built.push((self.clone(), Arc::downgrade(&cfg)));
+        Ok(cfg)
+    }
+
+    // The default provider with only the chosen cipher suites.
+    fn provider(&self) -> TLSResult<Arc<CryptoProvider>> {
+        let default = crypto_provider();
+        let suites = match self.cipher_suites {
+            Some(ref s) => s,
+            None => return Ok(default),
+        };
+        let mut provider = CryptoProvider::clone(&default);
+        provider.cipher_suites.clear();
+        for suite in suites {
+            match default.cipher_suites.iter().find(|s| s.suite() == *suite) {
+                Some(s) => provider.cipher_suites.push(*s),
+                None => {
+                    let msg = format!("cipher suite {:?} isn't supported", suite);
+                    return Err(Error::new(ErrorKind::InvalidInput, msg));
+                }
+            }
+        }
+        Ok(Arc::new(provider))
+    }
+
+    // webpki, with pins checked on top when there are some.
+    fn verifier(&self, provider: &Arc<CryptoProvider>) -> TLSResult<Arc<dyn ServerCertVerifier>> {
+        #[cfg(feature = "danger-insecure")]
+        if self.insecure == Some(Insecure::AnyCertificate) {
+            return Ok(Arc::new(AcceptAnyCertificate::new(provider)));
+        }
+
+        let roots = self.root_store()?;
+        if roots.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "no root certificates to verify servers with",
+            ));
+        }
+        let webpki = match WebPkiServerVerifier::builder_with_provider(
+            Arc::new(roots),
+            Arc::clone(provider),
+        )
+        .build()
+        {
+            Ok(v) => v,
+            Err(e) => return Err(Error::new(ErrorKind::InvalidInput, e)),
+        };
+        let verifier: Arc<dyn ServerCertVerifier> = match self.pins.is_empty() {
+            true => webpki,
+            false => Arc::new(PinningVerifier::new(webpki, self.pins.clone())),
+        };
+
+        #[cfg(feature = "danger-insecure")]
+        if let Some(Insecure::Hostname(ref hostname)) = self.insecure {
+            return Ok(Arc::new(HostnameOverride::new(verifier, hostname)?));
+        }
+        Ok(verifier)
+    }
+
+    fn root_store(&self) -> TLSResult<RootCertStore> {
+        let mut store = RootCertStore::empty();
+        if self.default_roots {
+            store.extend(TLS_SERVER_ROOTS.iter().cloned());
+        }
+        #[cfg(feature = "native-roots")]
+        if self.native_roots {
+            add_native_roots(&mut store)?;
+        }
+        for cert in &self.roots {
+            if let Err(e) = store.add(cert.clone()) {
+                return Err(Error::new(ErrorKind::InvalidData, e));
+            }
+        }
+        Ok(store)
+    }
+}
+
+// System stores tend to hold a few certificates webpki can't parse, those are skipped.
+#[cfg(feature = "native-roots")]
+fn add_native_roots(store: &mut RootCertStore) -> TLSResult<()> {
+    let native = rustls_native_certs::load_native_certs();
+    for e in &native.errors {
+        debug!("Couldn't load a system certificate: {}", e);
+    }
+    if native.certs.is_empty() {
+        if let Some(e) = native.errors.into_iter().next() {
+            return Err(Error::other(e));
+        }
+    }
+    let (added, ignored) = store.add_parsable_certificates(native.certs);
+    debug!("Loaded {} system certificates, ignored {}", added, ignored);
+    Ok(())
+}
+
+fn is_pem(data: &[u8]) -> bool {
+    data.windows(11).any(|w| w == b"-----BEGIN ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "W7b3oVildF5AKnnIFUQrHIyhzC8wYDBESbbMgtQaZL4=";
+
+    fn pins(config: &TlsConfig, host: &str) -> Vec<Pin> {
+        config.pins.get(host).cloned().unwrap_or_default()
+    }
+
+    #[test]
+    fn base64_pins() {
+        let hash: [u8; 32] = STANDARD.decode(HASH).unwrap().try_into().unwrap();
+        let config = TlsConfig::new()
+            .pin_spki_base64("Example.com", HASH)
+            .unwrap()
+            .pin_spki_base64("example.com", &format!("sha256/{}", HASH))
+            .unwrap();
+        // same pin twice, under the normalised host
+        assert_eq!(pins(&config, "example.com"), vec![Pin::Spki(hash)]);
+        assert!(pins(&config, "Example.com").is_empty());
+    }
+
+    #[test]
+    fn base64_pins_starting_with_a_slash() {
+        let hash = [0xfc; 32];
+        let b64 = STANDARD.encode(hash);
+        assert!(b64.starts_with('/'));
+
+        let config = TlsConfig::new().pin_spki_base64("a.example", &b64).unwrap();
+        assert_eq!(pins(&config, "a.example"), vec![Pin::Spki(hash)]);
+        let config = TlsConfig::new()
+            .pin_spki_base64("a.example", &format!("sha256/{}", b64))
+            .unwrap();
+        assert_eq!(pins(&config, "a.example"), vec![Pin::Spki(hash)]);
+    }
+
+    #[test]
+    fn invalid_pins() {
+        let short = STANDARD.encode([0u8; 20]);
+        for pin in [
+            "",
+            "sha256/",
+            "not base64!",
+            &short,
+            &format!("sha1/{}", HASH),
+        ] {
+            let e = TlsConfig::new().pin_spki_base64("a.example", pin).err();
+            assert_eq!(
+                e.map(|e| e.kind()),
+                Some(ErrorKind::InvalidInput),
+                "{}",
+                pin
+            );
+        }
+    }
+
+    #[test]
+    fn pins_per_host() {
+        let config = TlsConfig::new()
+            .pin_spki_sha256("a.example", [1; 32])
+            .pin_spki_sha256("A.EXAMPLE", [2; 32])
+            .pin_spki_sha256("[::1]", [3; 32]);
+        assert_eq!(
+            pins(&config, "a.example"),
+            vec![Pin::Spki([1; 32]), Pin::Spki([2; 32])]
+        );
+        assert_eq!(pins(&config, "::1"), vec![Pin::Spki([3; 32])]);
+    }
+}
@@ -0,0 +1,133 @@
+use log::warn;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider, WebPkiSupportedAlgorithms};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+
+// Ways of connecting to servers whose certificates wouldn't verify, only meant for local development.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Insecure {
+    // any certificate is accepted, anyone in between can read and change the traffic
+    AnyCertificate,
+    // the chain is verified as usual, but against this name instead of the one connected to
+    Hostname(String),
+}
+
+// Accepts every certificate, the handshake signatures are still checked
+// so the server at least has the key of the certificate it sent.
+#[derive(Debug)]
+pub(crate) struct AcceptAnyCertificate {
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl AcceptAnyCertificate {
+    pub(crate) fn new(provider: &CryptoProvider) -> Self {
+        Self {
+            algorithms: provider.signature_verification_algorithms,
+        }
+    }
+}
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer,
+        _intermediates: &[CertificateDer],
+        server_name: &ServerName,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        warn!(
+            "INSECURE: accepting the certificate of {} without verifying it",
+            server_name.to_str()
+        );
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+// Verifies certificates with inner, as if the server was called hostname.
+#[derive(Debug)]
+pub(crate) struct HostnameOverride {
+    inner: Arc<dyn ServerCertVerifier>,
+    hostname: ServerName<'static>,
+}
+
+impl HostnameOverride {
+    pub(crate) fn new(inner: Arc<dyn ServerCertVerifier>, hostname: &str) -> Result<Self, Error> {
+        let hostname = match ServerName::try_from(hostname) {
+            Ok(n) => n.to_owned(),
+            Err(e) => return Err(Error::new(ErrorKind::InvalidInput, e)),
+        };
+        Ok(Self { inner, hostname })
+    }
+}
+
+impl ServerCertVerifier for HostnameOverride {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer,
+        intermediates: &[CertificateDer],
+        server_name: &ServerName,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        warn!(
+            "INSECURE: verifying the certificate of {} as {}",
+            server_name.to_str(),
+            self.hostname.to_str()
+        );
+        self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            &self.hostname,
+            ocsp_response,
+            now,
+        )
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
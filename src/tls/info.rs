@@ -0,0 +1,156 @@
+use rustls::pki_types::CertificateDer;
+use rustls::{CipherSuite, ClientConnection, HandshakeKind, ProtocolVersion};
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::FromDer;
+use x509_parser::time::ASN1Time;
+
+// What was negotiated on a TLS connection.
+#[derive(Debug, Clone)]
+pub struct TlsInfo {
+    pub version: ProtocolVersion,
+    pub cipher_suite: CipherSuite,
+    pub alpn_protocol: Option<Vec<u8>>,
+    // an earlier session was resumed instead of doing a full handshake
+    pub resumed: bool,
+    // None when the host is an IP address, those aren't sent in SNI
+    pub sni: Option<String>,
+    // leaf first, as sent by the server
+    pub peer_certificates: Vec<PeerCertificate>,
+}
+
+impl TlsInfo {
+    // None until the handshake is done.
+    pub(crate) fn from_connection(conn: &ClientConnection, sni: Option<&str>) -> Option<Self> {
+        if conn.is_handshaking() {
+            return None;
+        }
+        let certs = conn.peer_certificates().unwrap_or_default();
+        Some(Self {
+            version: conn.protocol_version()?,
+            cipher_suite: conn.negotiated_cipher_suite()?.suite(),
+            alpn_protocol: conn.alpn_protocol().map(|p| p.to_vec()),
+            resumed: conn.handshake_kind() == Some(HandshakeKind::Resumed),
+            sni: sni.map(|s| s.to_string()),
+            peer_certificates: certs.iter().map(PeerCertificate::parse).collect(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubjectAltName {
+    Dns(String),
+    Ip(IpAddr),
+    Email(String),
+    Uri(String),
+}
+
+// Certificate of the peer with the fields worth logging, the rest is in der.
+// Fields that couldn't be parsed are left empty.
+#[derive(Debug, Clone)]
+pub struct PeerCertificate {
+    pub der: CertificateDer<'static>,
+    // distinguished names as "CN=example.com, O=Example", in certificate order
+    pub subject: String,
+    pub issuer: String,
+    pub not_before: Option<SystemTime>,
+    pub not_after: Option<SystemTime>,
+    pub subject_alt_names: Vec<SubjectAltName>,
+}
+
+impl PeerCertificate {
+    pub fn parse(der: &CertificateDer) -> Self {
+        let mut cert = Self {
+            der: der.clone().into_owned(),
+            subject: String::new(),
+            issuer: String::new(),
+            not_before: None,
+            not_after: None,
+            subject_alt_names: Vec::new(),
+        };
+        if let Ok((_, x509)) = X509Certificate::from_der(der) {
+            cert.subject = x509.subject().to_string();
+            cert.issuer = x509.issuer().to_string();
+            cert.not_before = time(x509.validity().not_before);
+            cert.not_after = time(x509.validity().not_after);
+            if let Ok(Some(ext)) = x509.subject_alternative_name() {
+                cert.subject_alt_names = ext
+                    .value
+                    .general_names
+                    .iter()
+                    .filter_map(alt_name)
+                    .collect();
+            }
+        }
+        cert
+    }
+}
+
+fn time(t: ASN1Time) -> Option<SystemTime> {
+    let secs = t.timestamp();
+    match secs >= 0 {
+        true => UNIX_EPOCH.checked_add(Duration::from_secs(secs as u64)),
+        false => UNIX_EPOCH.checked_sub(Duration::from_secs(secs.unsigned_abs())),
+    }
+}
+
+// Only the common choices are kept.
+fn alt_name(name: &GeneralName) -> Option<SubjectAltName> {
+    match *name {
+        GeneralName::DNSName(n) => Some(SubjectAltName::Dns(n.to_string())),
+        GeneralName::RFC822Name(n) => Some(SubjectAltName::Email(n.to_string())),
+        GeneralName::URI(n) => Some(SubjectAltName::Uri(n.to_string())),
+        GeneralName::IPAddress(a) => match (<[u8; 4]>::try_from(a), <[u8; 16]>::try_from(a)) {
+            (Ok(a), _) => Some(SubjectAltName::Ip(a.into())),
+            (_, Ok(a)) => Some(SubjectAltName::Ip(a.into())),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::pem::PemObject;
+
+    const PEER: &[u8] = include_bytes!("testdata/peer.pem");
+
+    fn at(secs: u64) -> Option<SystemTime> {
+        Some(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn peer_certificate() {
+        let der = CertificateDer::from_pem_slice(PEER).unwrap();
+        let cert = PeerCertificate::parse(&der);
+        assert_eq!(cert.subject, "C=NL, O=Example, CN=test.example");
+        assert_eq!(cert.issuer, cert.subject);
+        // UTCTime before 2050, GeneralizedTime after
+        assert_eq!(cert.not_before, at(1_704_067_200));
+        assert_eq!(cert.not_after, at(2_587_680_000));
+        assert_eq!(
+            cert.subject_alt_names,
+            vec![
+                SubjectAltName::Dns("test.example".into()),
+                SubjectAltName::Ip("127.0.0.1".parse().unwrap()),
+                SubjectAltName::Ip("::1".parse().unwrap()),
+                SubjectAltName::Email("a@example.com".into()),
+                SubjectAltName::Uri("https://example.com/".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn garbage_leaves_fields_empty() {
+        let der = PEER.to_vec();
+        for bad in [&[][..], &[0x30, 0x05, 0x02], &der] {
+            let cert = PeerCertificate::parse(&CertificateDer::from(bad.to_vec()));
+            assert_eq!(cert.subject, "");
+            assert_eq!(cert.not_after, None);
+            assert!(cert.subject_alt_names.is_empty());
+        }
+    }
+}
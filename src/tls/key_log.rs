@@ -0,0 +1,37 @@
+use rustls::KeyLog;
+use std::fmt;
+use std::io::Write;
+use std::sync::Mutex;
+
+// Writes TLS secrets in the NSS key log format Wireshark reads,
+// "<label> <client random> <secret>" with both values in hex.
+pub(crate) struct KeyLogWriter {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl KeyLogWriter {
+    pub(crate) fn new<W: Write + Send + 'static>(out: W) -> Self {
+        Self {
+            out: Mutex::new(Box::new(out)),
+        }
+    }
+}
+
+impl KeyLog for KeyLogWriter {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let line = format!("{} {} {}\n", label, hex(client_random), hex(secret));
+        if let Ok(mut out) = self.out.lock() {
+            let _ = out.write_all(line.as_bytes()).and_then(|_| out.flush());
+        }
+    }
+}
+
+impl fmt::Debug for KeyLogWriter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KeyLogWriter").finish_non_exhaustive()
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -1 +1,10 @@
+pub mod config;
+#[cfg(feature = "danger-insecure")]
+mod danger;
+pub mod info;
+mod key_log;
+pub mod pinning;
 pub mod tls_stream;
+
+#[cfg(not(any(feature = "aws-lc-rs", feature = "ring")))]
+compile_error!("a crypto provider is needed, enable the aws-lc-rs or ring feature");
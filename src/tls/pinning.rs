@@ -0,0 +1,213 @@
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{CertificateError, DigitallySignedStruct, OtherError, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+use webpki::EndEntityCert;
+
+// What a host's certificates are checked against, on top of the usual chain validation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Pin {
+    // SHA-256 of a SubjectPublicKeyInfo anywhere in the chain, so intermediates can be pinned too
+    Spki([u8; 32]),
+    // exact leaf certificate
+    Certificate(CertificateDer<'static>),
+}
+
+impl Pin {
+    fn matches(&self, end_entity: &CertificateDer, intermediates: &[CertificateDer]) -> bool {
+        match *self {
+            Pin::Spki(ref hash) => std::iter::once(end_entity)
+                .chain(intermediates)
+                .any(|c| spki_sha256(c).as_ref() == Some(hash)),
+            Pin::Certificate(ref cert) => cert == end_entity,
+        }
+    }
+}
+
+// The certificates of host matched none of its pins.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PinMismatch {
+    host: String,
+}
+
+impl PinMismatch {
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+}
+
+impl fmt::Display for PinMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "certificate of {} matches none of its pinned keys or certificates",
+            self.host
+        )
+    }
+}
+
+impl error::Error for PinMismatch {}
+
+// The pin mismatch behind an io error, None if it failed for another reason.
+pub fn pin_mismatch_of(e: &io::Error) -> Option<&PinMismatch> {
+    e.get_ref().and_then(|i| i.downcast_ref::<PinMismatch>())
+}
+
+// SHA-256 of the certificate's SubjectPublicKeyInfo, what Spki pins are compared to.
+pub fn spki_sha256(cert: &CertificateDer) -> Option<[u8; 32]> {
+    let cert = EndEntityCert::try_from(cert).ok()?;
+    Some(Sha256::digest(cert.subject_public_key_info()).into())
+}
+
+// Hosts are compared as lowercase punycode or the IP address, pins are looked up
+// by the name verified.
+pub(crate) fn host_key(host: &str) -> String {
+    let bare = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    if let Ok(ip) = bare.parse::<IpAddr>() {
+        return ip.to_string();
+    }
+    match idna::domain_to_ascii(bare) {
+        Ok(h) => h,
+        Err(_) => bare.to_ascii_lowercase(),
+    }
+}
+
+// Validates chains with webpki, then checks hosts that have pins against them.
+#[derive(Debug)]
+pub(crate) struct PinningVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: HashMap<String, Vec<Pin>>,
+}
+
+impl PinningVerifier {
+    pub(crate) fn new(inner: Arc<WebPkiServerVerifier>, pins: HashMap<String, Vec<Pin>>) -> Self {
+        Self { inner, pins }
+    }
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer,
+        intermediates: &[CertificateDer],
+        server_name: &ServerName,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+
+        let host = match *server_name {
+            ServerName::DnsName(ref d) => d.as_ref().to_ascii_lowercase(),
+            ServerName::IpAddress(ip) => IpAddr::from(ip).to_string(),
+            _ => return Ok(verified),
+        };
+        let pins = match self.pins.get(&host) {
+            Some(p) => p,
+            None => return Ok(verified),
+        };
+        if pins.iter().any(|p| p.matches(end_entity, intermediates)) {
+            return Ok(verified);
+        }
+        let e = OtherError(Arc::new(PinMismatch { host }));
+        Err(rustls::Error::InvalidCertificate(CertificateError::Other(
+            e,
+        )))
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::pem::PemObject;
+
+    // self-signed P-256, key hash from openssl pkey -pubin -outform der | openssl dgst -sha256
+    const PEER: &[u8] = include_bytes!("testdata/peer.pem");
+    const PEER_SPKI: &str = "W7b3oVildF5AKnnIFUQrHIyhzC8wYDBESbbMgtQaZL4=";
+
+    fn peer() -> CertificateDer<'static> {
+        CertificateDer::from_pem_slice(PEER).unwrap()
+    }
+
+    #[test]
+    fn spki_hash() {
+        use base64::engine::general_purpose::STANDARD;
+        use base64::Engine;
+
+        let hash = spki_sha256(&peer()).unwrap();
+        assert_eq!(STANDARD.encode(hash), PEER_SPKI);
+        assert_eq!(spki_sha256(&CertificateDer::from(vec![0x30, 0x00])), None);
+    }
+
+    #[test]
+    fn pins_match_the_chain() {
+        let hash = spki_sha256(&peer()).unwrap();
+        let other = CertificateDer::from(vec![0x30, 0x00]);
+
+        assert!(Pin::Spki(hash).matches(&peer(), &[]));
+        assert!(Pin::Spki(hash).matches(&other, &[peer()]));
+        assert!(!Pin::Spki([0; 32]).matches(&peer(), &[peer()]));
+        assert!(Pin::Certificate(peer()).matches(&peer(), &[]));
+        // only the leaf counts for certificate pins
+        assert!(!Pin::Certificate(peer()).matches(&other, &[peer()]));
+    }
+
+    #[test]
+    fn host_keys() {
+        assert_eq!(host_key("Example.COM"), "example.com");
+        assert_eq!(host_key("bücher.example"), "xn--bcher-kva.example");
+        assert_eq!(host_key("127.0.0.1"), "127.0.0.1");
+        assert_eq!(host_key("[::1]"), "::1");
+        assert_eq!(host_key("0:0:0:0:0:0:0:1"), "::1");
+    }
+
+    #[test]
+    fn mismatch_from_io_error() {
+        let mismatch = PinMismatch {
+            host: "example.com".into(),
+        };
+        let e = io::Error::new(io::ErrorKind::InvalidData, mismatch.clone());
+        assert_eq!(pin_mismatch_of(&e), Some(&mismatch));
+        assert_eq!(pin_mismatch_of(&e).unwrap().host(), "example.com");
+
+        let e = io::Error::new(io::ErrorKind::InvalidData, "other");
+        assert_eq!(pin_mismatch_of(&e), None);
+    }
+}
@@ -1,41 +1,132 @@
+use super::info::TlsInfo;
+use super::pinning::PinMismatch;
+use crate::https::resolve::{Resolver, SystemResolver};
+use crate::https::timeout::{self, Limit, TimeoutError, Timeouts};
+use crate::https::transport::{self, connect_tcp};
 use log::{debug, error, info};
-use rustls::{ClientConfig, ClientConnection, RootCertStore};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::ServerName;
+use rustls::{CertificateError, ClientConfig, ClientConnection, RootCertStore};
+use std::cell::OnceCell;
 use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
-use std::net::TcpStream;
-use std::sync::Arc;
+use std::net::{IpAddr, TcpStream};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 use webpki_roots::TLS_SERVER_ROOTS;
 type TLSResult<T> = Result<T, Error>;
 
+// ALPN protocol ids
+pub const ALPN_H2: &[u8] = b"h2";
+pub const ALPN_HTTP11: &[u8] = b"http/1.1";
+
+// Crypto behind every config, picked with the aws-lc-rs and ring features.
+pub fn crypto_provider() -> Arc<CryptoProvider> {
+    static PROVIDER: OnceLock<Arc<CryptoProvider>> = OnceLock::new();
+    #[cfg(feature = "aws-lc-rs")]
+    let provider = rustls::crypto::aws_lc_rs::default_provider;
+    #[cfg(all(feature = "ring", not(feature = "aws-lc-rs")))]
+    let provider = rustls::crypto::ring::default_provider;
+    // the compile_error! in tls/mod.rs is all that's reported without a provider
+    #[cfg(not(any(feature = "aws-lc-rs", feature = "ring")))]
+    let provider = || -> CryptoProvider { unreachable!() };
+    Arc::clone(PROVIDER.get_or_init(|| Arc::new(provider())))
+}
+
+// Config with the webpki roots and no ALPN, which means HTTP/1.1.
+pub fn default_config() -> ClientConfig {
+    let root_store: RootCertStore = RootCertStore {
+        roots: TLS_SERVER_ROOTS.into(),
+    };
+    ClientConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()
+        .expect("the providers support the default versions")
+        .with_root_certificates(root_store)
+        .with_no_client_auth()
+}
+
+// Same as default_config(), but offering h2 before http/1.1.
+pub fn h2_config() -> ClientConfig {
+    let mut cfg = default_config();
+    cfg.alpn_protocols = vec![ALPN_H2.to_vec(), ALPN_HTTP11.to_vec()];
+    cfg
+}
+
+// One default_config() for everything that doesn't bring its own,
+// so connections share its session cache and can resume each other's sessions.
+pub fn shared_default_config() -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    Arc::clone(CONFIG.get_or_init(|| Arc::new(default_config())))
+}
+
+// Same for h2_config().
+pub fn shared_h2_config() -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    Arc::clone(CONFIG.get_or_init(|| Arc::new(h2_config())))
+}
+
 pub struct TlsStream {
     pub(crate) conn: ClientConnection,
     pub(crate) buf_r: BufReader<TcpStream>,
     pub(crate) buf_w: BufWriter<TcpStream>,
-    #[allow(dead_code)]
     pub(crate) sock: TcpStream,
+    read_idle: Option<Duration>,
+    deadline: Option<Limit>,
+    // name sent in SNI
+    sni: Option<String>,
+    info: OnceCell<Arc<TlsInfo>>,
 }
 
 impl TlsStream {
     pub fn new(config: Option<&Arc<ClientConfig>>, url: &str, addr: &str) -> TLSResult<Self> {
+        Self::connect(
+            config,
+            url,
+            true,
+            addr,
+            &SystemResolver,
+            &Timeouts::default(),
+            None,
+        )
+    }
+
+    // Connects within the timeouts, the handshake is done right away when it has one.
+    // deadline limits everything done on the stream until it's changed with set_deadline().
+    // Without sni the name is only used to check the certificate.
+    pub fn connect(
+        config: Option<&Arc<ClientConfig>>,
+        url: &str,
+        sni: bool,
+        addr: &str,
+        resolver: &dyn Resolver,
+        timeouts: &Timeouts,
+        deadline: Option<Instant>,
+    ) -> TLSResult<Self> {
+        let total = deadline.map(|d| (d, TimeoutError::Total));
+        let connect = timeouts
+            .connect
+            .map(|t| (Instant::now() + t, TimeoutError::Connect));
+
         info!("Creating DNS name for {}", url);
-        let sock = TcpStream::connect(addr)?;
-        let server_name = match url.to_string().try_into() {
-            Ok(name) => name,
-            Err(_) => panic!("Invalid DNS name!"),
-        };
+        let server_name = server_name(url)?;
+        let sock = connect_tcp(addr, resolver, timeout::earliest(connect, total))?;
 
         // if supplied config
         // use that
-        let cfg = if let Some(c) = config {
+        let mut cfg = if let Some(c) = config {
             Arc::clone(c)
         } else {
-            let root_store: RootCertStore = RootCertStore {
-                roots: TLS_SERVER_ROOTS.into(),
-            };
-            Arc::new(
-                ClientConfig::builder()
-                    .with_root_certificates(root_store)
-                    .with_no_client_auth(),
-            )
+            shared_default_config()
+        };
+        if !sni && cfg.enable_sni {
+            // shares the session cache with the config given
+            let mut without = (*cfg).clone();
+            without.enable_sni = false;
+            cfg = Arc::new(without);
+        }
+
+        let sni = match server_name {
+            ServerName::DnsName(ref d) if cfg.enable_sni => Some(d.as_ref().to_string()),
+            _ => None,
         };
 
         // tls connection
@@ -46,16 +137,100 @@ impl TlsStream {
 
         info!("Connected to {}", addr);
 
-        Ok(Self {
+        let mut stream = Self {
             conn: client_conn,
             buf_r: BufReader::new(TcpStream::try_clone(&sock)?),
             buf_w: BufWriter::new(TcpStream::try_clone(&sock)?),
             sock,
-        })
+            read_idle: timeouts.read_idle,
+            deadline: total,
+            sni,
+            info: OnceCell::new(),
+        };
+        if let Some(t) = timeouts.handshake {
+            let handshake = Some((Instant::now() + t, TimeoutError::Handshake));
+            stream.deadline = timeout::earliest(handshake, total);
+            stream.complete_handshake()?;
+            stream.deadline = total;
+        }
+        Ok(stream)
+    }
+
+    // Longest wait for the socket to make progress, None waits forever.
+    pub fn set_read_idle_timeout(&mut self, t: Option<Duration>) {
+        self.read_idle = t;
+    }
+
+    // Point in time after which every IO fails with TimeoutError::Total.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline.map(|d| (d, TimeoutError::Total));
+    }
+
+    // Runs the handshake until it's done, normally it happens on the first read or write.
+    pub fn complete_handshake(&mut self) -> TLSResult<()> {
+        while self.conn.is_handshaking() {
+            self.handshake()?;
+        }
+        Ok(())
+    }
+
+    // Sends data as TLS 1.3 early data if the session being resumed allows it, then finishes
+    // the handshake. false means the server didn't get it and it has to be written again,
+    // either because early data wasn't possible or the server rejected it.
+    pub fn write_early_data(&mut self, data: &[u8]) -> TLSResult<bool> {
+        let sent = match self.conn.early_data() {
+            Some(mut early) if early.bytes_left() >= data.len() => {
+                early.write_all(data)?;
+                true
+            }
+            _ => false,
+        };
+        self.complete_handshake()?;
+        let accepted = sent && self.conn.is_early_data_accepted();
+        debug!("Early data sent: {}, accepted: {}", sent, accepted);
+        Ok(accepted)
+    }
+
+    // Protocol picked by the server during ALPN, only known after the handshake.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.conn.alpn_protocol()
+    }
+
+    // Version, cipher suite and peer certificates, only known after the handshake.
+    pub fn tls_info(&self) -> Option<Arc<TlsInfo>> {
+        if let Some(info) = self.info.get() {
+            return Some(Arc::clone(info));
+        }
+        let info = TlsInfo::from_connection(&self.conn, self.sni.as_deref())?;
+        Some(Arc::clone(self.info.get_or_init(|| Arc::new(info))))
+    }
+
+    // Checks without blocking whether an idle connection was closed by the peer,
+    // or got data nobody asked for. Either way it can't be reused.
+    pub fn is_stale(&self) -> bool {
+        transport::is_stale(&self.sock)
     }
 
     // Does IO for the connection.
     pub fn handshake(&mut self) -> TLSResult<(usize, usize)> {
+        self.arm()?;
+        self.do_io().map_err(|e| self.check_timeout(e))
+    }
+
+    fn arm(&self) -> TLSResult<()> {
+        transport::arm(&self.sock, self.read_idle, self.deadline)
+    }
+
+    fn check_timeout(&self, e: Error) -> Error {
+        transport::check_timeout(e, self.deadline)
+    }
+
+    fn flush_tls(&mut self) -> TLSResult<()> {
+        self.conn.write_tls(&mut self.buf_w)?;
+        self.buf_w.flush()
+    }
+
+    fn do_io(&mut self) -> TLSResult<(usize, usize)> {
         let mut eof = false;
         let mut read = 0;
         let mut write = 0;
@@ -102,9 +277,10 @@ impl TlsStream {
                 }
             }
 
+            // not Interrupted, write_all() would retry a failed certificate check forever
             match self.conn.process_new_packets() {
                 Ok(io) => debug!("{:#?}", io),
-                Err(e) => return Err(Error::new(ErrorKind::Interrupted, e.to_string())),
+                Err(e) => return Err(tls_error(e)),
             }
 
             if !self.conn.is_handshaking() && handshake && self.conn.wants_write() {
@@ -127,6 +303,41 @@ impl TlsStream {
         }
     }
 }
+// Name checked against the certificate and sent in SNI.
+// IP addresses (IPv6 with or without brackets) are verified as such and never sent in SNI,
+// Unicode names are converted to punycode.
+fn server_name(name: &str) -> TLSResult<ServerName<'static>> {
+    let bare = name
+        .strip_prefix('[')
+        .and_then(|n| n.strip_suffix(']'))
+        .unwrap_or(name);
+    if let Ok(ip) = bare.parse::<IpAddr>() {
+        return Ok(ServerName::IpAddress(ip.into()));
+    }
+
+    let ascii = match name.is_ascii() {
+        true => name.to_string(),
+        false => match idna::domain_to_ascii(name) {
+            Ok(n) => n,
+            Err(_) => return Err(Error::new(ErrorKind::InvalidInput, "invalid domain name")),
+        },
+    };
+    match ServerName::try_from(ascii) {
+        Ok(n) => Ok(n),
+        Err(e) => Err(Error::new(ErrorKind::InvalidInput, e)),
+    }
+}
+
+// A pin mismatch is passed on by itself, so pin_mismatch_of() finds it.
+fn tls_error(e: rustls::Error) -> Error {
+    if let rustls::Error::InvalidCertificate(CertificateError::Other(ref o)) = e {
+        if let Some(m) = o.0.downcast_ref::<PinMismatch>() {
+            return Error::new(ErrorKind::InvalidData, m.clone());
+        }
+    }
+    Error::new(ErrorKind::InvalidData, e)
+}
+
 impl Read for TlsStream {
     // Reads once
     fn read(&mut self, buf: &mut [u8]) -> TLSResult<usize> {
@@ -155,9 +366,9 @@ impl Read for TlsStream {
 
         debug!("Finished reading");
         match self.conn.reader().read(buf) {
-            Ok(u) => return Ok(u),
-            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(0),
-            Err(e) => return Err(e),
+            Ok(u) => Ok(u),
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => Ok(0),
+            Err(e) => Err(e),
         }
     }
 
@@ -204,20 +415,127 @@ impl Write for TlsStream {
 
         let len = self.conn.writer().write(buf)?;
         self.conn.writer().flush()?;
-        self.conn.write_tls(&mut self.buf_w)?;
-
-        self.buf_w.flush()?;
+        self.arm()?;
+        if let Err(e) = self.flush_tls() {
+            return Err(self.check_timeout(e));
+        }
 
         debug!("Finished writing");
         Ok(len)
     }
     // Flushes all buffers
+    // Only writes, do_io() would block on a read whenever no plaintext is pending.
     fn flush(&mut self) -> TLSResult<()> {
-        self.handshake()?;
+        if self.conn.is_handshaking() {
+            self.complete_handshake()?;
+        }
         self.conn.writer().flush()?;
-        if self.conn.wants_write() {
-            self.handshake()?;
+        self.arm()?;
+        while self.conn.wants_write() {
+            if let Err(e) = self.flush_tls() {
+                return Err(self.check_timeout(e));
+            }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::config::TlsConfig;
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use rustls::{ServerConfig, ServerConnection, StreamOwned};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    // localhost signed by ca.pem, both valid until 2124
+    const CA: &[u8] = include_bytes!("testdata/ca.pem");
+    const CERT: &[u8] = include_bytes!("testdata/localhost.pem");
+    const KEY: &[u8] = include_bytes!("testdata/localhost.key");
+
+    // Answers "ping" with "pong", then keeps the connection open without sending anything
+    // until the sender is dropped. The server name the client sent comes out of the receiver.
+    fn server() -> (String, mpsc::Sender<()>, mpsc::Receiver<Option<String>>) {
+        let cert = CertificateDer::from_pem_slice(CERT).unwrap();
+        let key = PrivateKeyDer::from_pem_slice(KEY).unwrap();
+        let config = ServerConfig::builder_with_provider(crypto_provider())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert], key)
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (done, wait) = mpsc::channel();
+        let (sni_tx, sni) = mpsc::channel();
+        thread::spawn(move || {
+            let (sock, _) = listener.accept().unwrap();
+            let conn = ServerConnection::new(Arc::new(config)).unwrap();
+            let mut tls = StreamOwned::new(conn, sock);
+            let mut buf = [0; 4];
+            tls.read_exact(&mut buf).unwrap();
+            let _ = sni_tx.send(tls.conn.server_name().map(|n| n.to_string()));
+            tls.write_all(b"pong").unwrap();
+            tls.flush().unwrap();
+            let _ = wait.recv();
+        });
+        (addr, done, sni)
+    }
+
+    fn client(addr: &str, sni: bool) -> TlsStream {
+        let config = TlsConfig::new()
+            .default_roots(false)
+            .add_root_pem(CA)
+            .unwrap()
+            .build_shared()
+            .unwrap();
+        let timeouts = Timeouts::default();
+        let (name, resolver) = ("localhost", &SystemResolver);
+        TlsStream::connect(Some(&config), name, sni, addr, resolver, &timeouts, None).unwrap()
+    }
+
+    fn ping(tls: &mut TlsStream) {
+        tls.write_all(b"ping").unwrap();
+        tls.flush().unwrap();
+        let mut buf = [0; 4];
+        tls.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+
+    #[test]
+    fn round_trip() {
+        let (addr, _done, sni) = server();
+        let mut tls = client(&addr, true);
+        ping(&mut tls);
+        assert_eq!(sni.recv().unwrap().as_deref(), Some("localhost"));
+        assert_eq!(tls.tls_info().unwrap().sni.as_deref(), Some("localhost"));
+    }
+
+    #[test]
+    fn sni_can_be_left_out() {
+        let (addr, _done, sni) = server();
+        let mut tls = client(&addr, false);
+        // the certificate is still checked against the name
+        ping(&mut tls);
+        assert_eq!(sni.recv().unwrap(), None);
+        assert_eq!(tls.tls_info().unwrap().sni, None);
+    }
+
+    #[test]
+    fn flush_doesnt_wait_for_the_peer() {
+        let (addr, _done, _) = server();
+        let mut tls = client(&addr, true);
+        ping(&mut tls);
+
+        // the server sends nothing more, a flush that reads would run into the deadline
+        tls.set_deadline(Some(Instant::now() + Duration::from_secs(5)));
+        let start = Instant::now();
+        tls.write_all(b"more").unwrap();
+        tls.flush().unwrap();
+        tls.flush().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}